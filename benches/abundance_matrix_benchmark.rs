@@ -0,0 +1,145 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use krakenclip::abundance_matrix::AbundanceMatrix;
+use krakenclip::krk_parser::{KrakenReport, TaxonEntry};
+use krakenclip::rank::Rank;
+
+/// Allocator that keeps track of the bytes currently allocated on the heap
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Number of samples in the benchmark cohort
+const SAMPLES: usize = 300;
+/// Number of species in the synthetic taxonomy
+const SPECIES: usize = 5_000;
+/// Species per genus in the synthetic taxonomy
+const SPECIES_PER_GENUS: usize = 10;
+/// Fraction of the species present in each sample
+const PRESENCE: f64 = 0.3;
+
+/// Builds a report with a random subset of the species of a fixed taxonomy
+fn synthetic_report(rng: &mut StdRng) -> KrakenReport {
+    let mut genera = Vec::new();
+    for genus in 0..SPECIES / SPECIES_PER_GENUS {
+        let mut species = Vec::new();
+        for i in 0..SPECIES_PER_GENUS {
+            if rng.gen_bool(PRESENCE) {
+                let taxid = (100_000 + genus * SPECIES_PER_GENUS + i) as u32;
+                let reads = rng.gen_range(1..10_000);
+                species.push(TaxonEntry::new(0.0, reads, reads, "S".to_string(), taxid, format!("Species {}", taxid), 3));
+            }
+        }
+        if !species.is_empty() {
+            let reads = species.iter().map(|s| s.clade_reads).sum();
+            let mut entry = TaxonEntry::new(0.0, reads, 0, "G".to_string(), (10_000 + genus) as u32, format!("Genus {}", genus), 2);
+            entry.children = species;
+            genera.push(entry);
+        }
+    }
+    let reads = genera.iter().map(|g| g.clade_reads).sum();
+    let mut bacteria = TaxonEntry::new(0.0, reads, 0, "D".to_string(), 2, "Bacteria".to_string(), 1);
+    bacteria.children = genera;
+    let mut root = TaxonEntry::new(0.0, reads, 0, "R".to_string(), 1, "root".to_string(), 0);
+    root.children = vec![bacteria];
+    KrakenReport::new(root, None)
+}
+
+fn cohort() -> Vec<(String, KrakenReport)> {
+    let mut rng = StdRng::seed_from_u64(42);
+    (0..SAMPLES).map(|i| (format!("sample_{:04}", i), synthetic_report(&mut rng))).collect()
+}
+
+/// Builds the matrix with the interned columnar layout of `AbundanceMatrix`
+fn build_matrix(reports: &[(String, KrakenReport)]) -> AbundanceMatrix {
+    let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+    for (sample, report) in reports {
+        matrix.add_sample(report, sample, 0.0, false);
+    }
+    matrix
+}
+
+/// Collects the species of a report into the previous layout (taxid -> sample name -> value)
+fn add_nested(node: &TaxonEntry, sample: &str, matrix: &mut HashMap<u32, HashMap<String, f64>>) {
+    if node.rank == Rank::Species(0) {
+        matrix.entry(node.taxid).or_default().insert(sample.to_string(), node.clade_reads as f64);
+    } else {
+        for child in &node.children {
+            add_nested(child, sample, matrix);
+        }
+    }
+}
+
+/// Builds the matrix with the nested HashMap layout used before interning
+fn build_nested(reports: &[(String, KrakenReport)]) -> HashMap<u32, HashMap<String, f64>> {
+    let mut matrix = HashMap::new();
+    for (sample, report) in reports {
+        add_nested(&report.root, sample, &mut matrix);
+    }
+    matrix
+}
+
+/// Heap memory currently allocated, in MB
+fn memory_mb() -> f64 {
+    ALLOCATED.load(Ordering::Relaxed) as f64 / (1024.0 * 1024.0)
+}
+
+/// Prints the memory taken by each layout for the benchmark cohort
+fn report_memory(reports: &[(String, KrakenReport)]) {
+    let before = memory_mb();
+    let nested = build_nested(reports);
+    let nested_mb = memory_mb() - before;
+    drop(black_box(nested));
+
+    let before = memory_mb();
+    let matrix = build_matrix(reports);
+    let matrix_mb = memory_mb() - before;
+    drop(black_box(matrix));
+
+    println!(
+        "Memory for {} samples x {} species: nested HashMap {:.1} MB, interned columns {:.1} MB",
+        SAMPLES, SPECIES, nested_mb, matrix_mb
+    );
+}
+
+fn benchmark_abundance_matrix(c: &mut Criterion) {
+    let reports = cohort();
+    report_memory(&reports);
+
+    let mut group = c.benchmark_group("abundance matrix");
+    group.sample_size(10);
+    group.bench_function("build (nested HashMap)", |b| b.iter(|| build_nested(black_box(&reports))));
+    group.bench_function("build (interned columns)", |b| b.iter(|| build_matrix(black_box(&reports))));
+
+    let matrix = build_matrix(&reports);
+    let output = std::env::temp_dir().join(format!("krakenclip_bench_{}.tsv", std::process::id()));
+    let output = output.to_str().unwrap().to_string();
+    group.bench_function("write TSV", |b| b.iter(|| matrix.write_matrix(black_box(&output)).unwrap()));
+    group.bench_function("transform to proportions", |b| {
+        b.iter_batched(|| build_matrix(&reports), |mut matrix| matrix.transform_to_proportions(), BatchSize::LargeInput)
+    });
+    group.finish();
+    let _ = std::fs::remove_file(&output);
+}
+
+criterion_group!(benches, benchmark_abundance_matrix);
+criterion_main!(benches);
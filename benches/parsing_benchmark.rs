@@ -1,5 +1,5 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use kraken2_parser::krk_parser::parse_kraken2_report;
+use krakenclip::krk_parser::parse_kraken2_report;
 
 fn benchmark_parsing(c: &mut Criterion) {
     c.bench_function("parse kraken2 report", |b| {
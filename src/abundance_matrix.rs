@@ -1,34 +1,39 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::error::Error;
-use std::cmp::Ordering;
+use std::str::FromStr;
 use crate::krk_parser::{KrakenReport, TaxonEntry};
+use crate::rank::{Rank, RankSelector};
+use crate::gtdb::split_gtdb_name;
+use crate::normalization::{quantile_of_sorted, Normalization};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
 
 /// Optimized buffer size for write operations
 const BUFFER_SIZE: usize = 256 * 1024; // 256KB
 
-/// Supported taxonomic levels
-const TAXON_LEVELS: &[(&str, &str)] = &[
-    ("K", "kingdom"),
-    ("P", "phylum"),
-    ("C", "class"),
-    ("O", "order"),
-    ("F", "family"),
-    ("G", "genus"),
-    ("S", "species")
-];
-
 /// Special name for unclassified entries in the matrix
 const UNCLASSIFIED_NAME: &str = "Unclassified";
 
+/// Taxid used by Kraken2 for unclassified reads, which is also the key of the unclassified row
+pub const UNCLASSIFIED_TAXID: u32 = 0;
+
+/// Name of the row aggregating the taxa removed by cohort filters
+const OTHER_NAME: &str = "Other";
+
+/// Key of the "Other" row (not a valid NCBI or GTDB taxid)
+pub const OTHER_TAXID: u32 = u32::MAX;
+
+/// Key of the single row of reads classified above the level of the matrix
+pub const UNASSIGNED_TAXID: u32 = u32::MAX - 1;
+
 /// Specific errors for the abundance matrix module
 #[derive(Debug)]
 pub enum AbundanceMatrixError {
     IoError(std::io::Error),
     #[allow(dead_code)]
     InvalidLevel(String),
-    #[allow(dead_code)]
     InvalidValue(String),
 }
 
@@ -53,43 +58,272 @@ impl From<std::io::Error> for AbundanceMatrixError {
 /// Specialized result type for abundance matrix functions
 pub type AbundanceResult<T> = Result<T, AbundanceMatrixError>;
 
+/// Annotations of a matrix row
+#[derive(Debug, Clone)]
+pub struct TaxonAnnotation {
+    /// Scientific name as written in the first report containing the taxon
+    pub name: String,
+    /// Rank of the taxon
+    pub rank: Rank,
+    /// Lineage from domain to the taxon itself (e.g. ["d__Bacteria", ..., "g__Escherichia"])
+    pub lineage: Vec<String>,
+    /// Whether the row holds reads classified above the level of the matrix, as
+    /// "Escherichia (unassigned species)" or "Unassigned at S", rather than a taxon of the level
+    pub unassigned: bool,
+}
+
+impl TaxonAnnotation {
+    /// Builds the annotation of a taxon read from a table, filling in the
+    /// rank and name from the deepest lineage element when they are missing
+    /// 
+    /// # Arguments
+    /// * `taxid` - Taxid of the row (0 for unclassified)
+    /// * `name` - Name of the taxon, if known
+    /// * `rank` - Rank of the taxon, if known
+    /// * `lineage` - Lineage labels from domain down (e.g. ["d__Bacteria", "g__Escherichia"])
+    pub fn from_lineage(taxid: u32, name: Option<String>, rank: Option<Rank>, lineage: Vec<String>) -> Self {
+        let deepest = lineage.last().and_then(|label| split_gtdb_name(label));
+        let rank = match (taxid, rank, deepest) {
+            (UNCLASSIFIED_TAXID, _, _) => Rank::Unclassified,
+            (_, Some(rank), _) => rank,
+            (_, None, Some((code, _))) => Rank::from_code(code),
+            _ => Rank::Unknown,
+        };
+        let name = name
+            .or_else(|| deepest.map(|(_, name)| name.to_string()))
+            .unwrap_or_else(|| taxid.to_string());
+        let unassigned = taxid == UNASSIGNED_TAXID;
+        Self { name, rank, lineage, unassigned }
+    }
+}
+
+/// Cohort-level filter applied once every sample is in the matrix
+/// 
+/// A taxon is kept when it reaches `min_abundance` in at least `min_samples`
+/// samples, or when it has at least `min_total_reads` reads over all samples.
+/// Disabled criteria are ignored, and a filter with none keeps every taxon.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PrevalenceFilter {
+    /// Minimum abundance (percentage of the sample reads) for a sample to count towards the prevalence
+    pub min_abundance: f64,
+    /// Minimum number of samples reaching `min_abundance` (0 disables the prevalence criterion)
+    pub min_samples: usize,
+    /// Minimum number of reads summed over all samples
+    pub min_total_reads: Option<f64>,
+}
+
+impl PrevalenceFilter {
+    /// Whether the filter has any criterion
+    pub fn is_active(&self) -> bool {
+        self.min_samples > 0 || self.min_total_reads.is_some()
+    }
+
+    /// Whether a taxon present in `prevalence` samples with `total_reads` reads is kept
+    fn keeps(&self, prevalence: usize, total_reads: f64) -> bool {
+        let by_prevalence = self.min_samples > 0 && prevalence >= self.min_samples;
+        let by_reads = self.min_total_reads.is_some_and(|min| total_reads >= min);
+        !self.is_active() || by_prevalence || by_reads
+    }
+}
+
+/// Handling of the reads classified above the level of the matrix
+/// 
+/// In a species matrix, reads assigned directly to a genus or higher taxon
+/// are not part of any species. They can be dropped, or kept so that the
+/// columns sum to the classified reads of each sample.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnassignedReads {
+    /// The reads are not in the matrix
+    #[default]
+    Drop,
+    /// One row per taxon holding reads, e.g. "Escherichia (unassigned species)"
+    PerParent,
+    /// A single "Unassigned at S" row
+    Single,
+}
+
+impl FromStr for UnassignedReads {
+    type Err = AbundanceMatrixError;
+
+    /// Parses an unassigned reads mode (none, parent or single)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Self::Drop),
+            "parent" => Ok(Self::PerParent),
+            "single" => Ok(Self::Single),
+            _ => Err(AbundanceMatrixError::InvalidValue(format!(
+                "unknown unassigned reads mode '{}', use none, parent or single", s
+            ))),
+        }
+    }
+}
+
+/// Statistic used to rank taxa across samples when keeping the top taxa
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaxonRanking {
+    /// Mean abundance over all samples
+    Mean,
+    /// Median abundance over all samples
+    Median,
+    /// Highest abundance in any sample
+    Max,
+    /// Number of samples where the taxon is present
+    Prevalence,
+}
+
+impl FromStr for TaxonRanking {
+    type Err = AbundanceMatrixError;
+
+    /// Parses a ranking name (mean, median, max or prevalence)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mean" => Ok(Self::Mean),
+            "median" => Ok(Self::Median),
+            "max" => Ok(Self::Max),
+            "prevalence" => Ok(Self::Prevalence),
+            _ => Err(AbundanceMatrixError::InvalidValue(format!(
+                "unknown ranking '{}', use mean, median, max or prevalence", s
+            ))),
+        }
+    }
+}
+
+/// Layout of the taxonomy table written next to a matrix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaxonomyFormat {
+    /// One column per standard rank from Domain to Species, as a phyloseq `tax_table`
+    Table,
+    /// QIIME2 `taxonomy.tsv` with `Feature ID` and a `d__...; p__...; ...` `Taxon` string
+    Qiime2,
+}
+
+impl FromStr for TaxonomyFormat {
+    type Err = AbundanceMatrixError;
+
+    /// Parses a taxonomy table format name (table/phyloseq or qiime2/qiime)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "table" | "phyloseq" => Ok(Self::Table),
+            "qiime2" | "qiime" => Ok(Self::Qiime2),
+            _ => Err(AbundanceMatrixError::InvalidValue(format!(
+                "unknown taxonomy format '{}', use table or qiime2", s
+            ))),
+        }
+    }
+}
+
+/// Non-zero abundances and read totals of one sample
+#[derive(Debug, Clone, Default)]
+struct SampleColumn {
+    /// Non-zero abundances as (row, value) pairs sorted by row
+    entries: Vec<(u32, f64)>,
+    /// Total reads in the sample (for normalization)
+    total: f64,
+    /// Unclassified reads in the sample
+    unclassified: f64,
+}
+
+impl SampleColumn {
+    /// Sets the value of a row, keeping the entries sorted and sparse
+    fn set(&mut self, row: u32, value: f64) {
+        match self.entries.binary_search_by_key(&row, |&(r, _)| r) {
+            Ok(idx) if value == 0.0 => {
+                self.entries.remove(idx);
+            }
+            Ok(idx) => self.entries[idx].1 = value,
+            Err(_) if value == 0.0 => {}
+            Err(idx) => self.entries.insert(idx, (row, value)),
+        }
+    }
+
+    /// Value of a row (0 when absent)
+    fn get(&self, row: u32) -> f64 {
+        self.entries.binary_search_by_key(&row, |&(r, _)| r)
+            .map(|idx| self.entries[idx].1)
+            .unwrap_or(0.0)
+    }
+}
+
 /// Structure to efficiently store the abundance matrix
 /// 
-/// This implementation uses nested HashMaps to efficiently store
-/// sparse abundance data, where many taxa may be present in some
-/// samples but not others.
-#[derive(Default)]
+/// Taxa and samples are interned: each taxid gets a row index and each
+/// sample name a column index the first time they are seen, so names are
+/// stored once. Abundances are kept column by column as sparse (row, value)
+/// pairs, which suits the many taxa present in some samples but not others
+/// and lets a whole sample be added or replaced at once. Rows are keyed by
+/// taxid, so distinct taxa sharing a name (e.g. "uncultured bacterium") are
+/// kept apart.
 pub struct AbundanceMatrix {
-    /// Map of taxa to their abundances by sample
-    taxon_abundances: HashMap<String, HashMap<String, f64>>,
-    /// Set of all samples
-    samples: HashSet<String>,
-    /// Current taxonomic level
-    level: String,
-    /// Total reads per sample (for normalization)
-    sample_totals: HashMap<String, f64>,
-    /// Specifically stores unclassified reads per sample
-    unclassified_reads: HashMap<String, f64>,
+    /// Taxid of each row, in insertion order
+    row_taxids: Vec<u32>,
+    /// Name, rank and lineage of each row
+    taxa: Vec<TaxonAnnotation>,
+    /// Row index of each taxid
+    row_index: HashMap<u32, u32>,
+    /// Name of each sample column, in insertion order
+    samples: Vec<String>,
+    /// Column index of each sample name
+    sample_index: HashMap<String, usize>,
+    /// Abundances and read totals of each sample column
+    columns: Vec<SampleColumn>,
+    /// Column order set from sample metadata (samples are sorted by name otherwise)
+    sample_order: Option<Vec<String>>,
+    /// Ranks selected for the rows of the matrix
+    level: RankSelector,
     /// Indicates whether to force inclusion of unclassified entries
     force_include_unclassified: bool,
+    /// Handling of the reads classified above the level of the matrix
+    unassigned: UnassignedReads,
+    /// Indicates whether the TSV output includes a lineage column
+    include_lineage: bool,
 }
 
 impl AbundanceMatrix {
     /// Creates a new abundance matrix for the specified taxonomic level
     /// 
     /// # Arguments
-    /// * `level` - Taxonomic level (K, P, C, O, F, G, S)
-    pub fn new(level: &str) -> Self {
+    /// * `level` - Ranks to aggregate at (e.g. S, S1, or G+ for genus and anything below it)
+    pub fn new(level: RankSelector) -> Self {
         Self {
-            taxon_abundances: HashMap::new(),
-            samples: HashSet::new(),
-            level: level.to_string(),
-            sample_totals: HashMap::new(),
-            unclassified_reads: HashMap::new(),
+            row_taxids: Vec::new(),
+            taxa: Vec::new(),
+            row_index: HashMap::new(),
+            samples: Vec::new(),
+            sample_index: HashMap::new(),
+            columns: Vec::new(),
+            sample_order: None,
+            level,
             force_include_unclassified: false,
+            unassigned: UnassignedReads::Drop,
+            include_lineage: false,
         }
     }
 
+    /// Sets whether the TSV output includes a lineage column
+    /// 
+    /// # Arguments
+    /// * `include` - If true, writes a `lineage` column with `d__;p__;...` strings
+    pub fn set_include_lineage(&mut self, include: bool) {
+        self.include_lineage = include;
+    }
+
+    /// Infers the level of a matrix read from a table from the ranks of its taxa
+    /// 
+    /// The level is the rank shared by all classified taxa of known rank (rows
+    /// such as "Other" have none), or an unknown rank when the taxa have
+    /// different ranks.
+    pub fn infer_level<'a>(taxa: impl IntoIterator<Item = &'a TaxonAnnotation>) -> RankSelector {
+        let mut ranks = taxa.into_iter()
+            .filter(|taxon| !taxon.unassigned)
+            .map(|taxon| taxon.rank)
+            .filter(|rank| *rank != Rank::Unclassified && *rank != Rank::Unknown);
+        let level = match ranks.next() {
+            Some(first) if ranks.all(|rank| rank == first) => first,
+            _ => Rank::Unknown,
+        };
+        RankSelector::Exact(level)
+    }
+
     /// Sets whether unclassified reads should be forcibly included
     /// 
     /// # Arguments
@@ -98,6 +332,38 @@ impl AbundanceMatrix {
         self.force_include_unclassified = include;
     }
 
+    /// Returns the row of a taxid, adding it with the given annotation if needed
+    fn intern_taxon(&mut self, taxid: u32, taxon: impl FnOnce() -> TaxonAnnotation) -> u32 {
+        if let Some(&row) = self.row_index.get(&taxid) {
+            return row;
+        }
+        let row = self.row_taxids.len() as u32;
+        self.row_taxids.push(taxid);
+        self.taxa.push(taxon());
+        self.row_index.insert(taxid, row);
+        row
+    }
+
+    /// Returns the column of a sample, adding an empty column if needed
+    fn intern_sample(&mut self, sample: &str) -> usize {
+        if let Some(&col) = self.sample_index.get(sample) {
+            return col;
+        }
+        let col = self.samples.len();
+        self.samples.push(sample.to_string());
+        self.sample_index.insert(sample.to_string(), col);
+        self.columns.push(SampleColumn::default());
+        col
+    }
+
+    /// Sets how reads classified above the level of the matrix are handled
+    /// 
+    /// # Arguments
+    /// * `unassigned` - Drop them, or keep them in per-parent rows or in a single row
+    pub fn set_unassigned_reads(&mut self, unassigned: UnassignedReads) {
+        self.unassigned = unassigned;
+    }
+
     /// Adds a sample to the abundance matrix
     /// 
     /// # Arguments
@@ -109,38 +375,92 @@ impl AbundanceMatrix {
     /// # Implementation Details
     /// This method calculates the total reads for normalization and
     /// recursively processes the taxonomic tree to extract abundances
-    /// at the specified taxonomic level.
+    /// at the specified taxonomic level. The values are collected for the
+    /// whole sample and stored as one column, replacing any previous
+    /// column with the same name.
     pub fn add_sample(&mut self, report: &KrakenReport, sample_name: &str, min_abundance: f64, normalize: bool) {
-        self.samples.insert(sample_name.to_string());
-        
-        // Calculate the total reads for this sample
-        let total_reads = self.calculate_total_reads(report);
-        self.sample_totals.insert(sample_name.to_string(), total_reads);
+        let col = self.intern_sample(sample_name);
+        let mut column = SampleColumn {
+            total: self.calculate_total_reads(report),
+            ..Default::default()
+        };
         
         // Store unclassified reads
         if let Some(ref unclassified) = report.unclassified {
-            let unclassified_count = unclassified.clade_reads as f64;
-            self.unclassified_reads.insert(sample_name.to_string(), unclassified_count);
+            column.unclassified = unclassified.clade_reads as f64;
             
             // If we're forcing the inclusion of no classified, add them as a special taxon
             if self.force_include_unclassified {
                 let abundance = if normalize {
-                    (unclassified_count / total_reads) * 100.0
+                    (column.unclassified / column.total) * 100.0
                 } else {
-                    unclassified_count
+                    column.unclassified
                 };
                 
                 if abundance >= min_abundance {
-                    self.taxon_abundances
-                        .entry(UNCLASSIFIED_NAME.to_string())
-                        .or_default()
-                        .insert(sample_name.to_string(), abundance);
+                    let row = self.intern_taxon(UNCLASSIFIED_TAXID, || TaxonAnnotation {
+                        name: UNCLASSIFIED_NAME.to_string(),
+                        rank: Rank::Unclassified,
+                        lineage: Vec::new(),
+                        unassigned: false,
+                    });
+                    column.entries.push((row, abundance));
                 }
             }
         }
         
         // Process the taxonomic tree recursively
-        self.process_node(&report.root, sample_name, min_abundance, normalize);
+        let mut lineage = Vec::with_capacity(16);
+        let total = if normalize { Some(column.total) } else { None };
+        let mut unassigned = Vec::new();
+        self.process_node(&report.root, total, min_abundance, &mut lineage, &mut column.entries, &mut unassigned);
+        
+        // Reads above the level, summed per row so the single row is compared with the threshold once
+        unassigned.sort_unstable_by_key(|&(row, _)| row);
+        unassigned.dedup_by(|next, first| {
+            let same_row = next.0 == first.0;
+            if same_row {
+                first.1 += next.1;
+            }
+            same_row
+        });
+        for (row, reads) in unassigned {
+            let abundance = match total {
+                Some(total) => (reads / total) * 100.0,
+                None => reads,
+            };
+            if abundance >= min_abundance {
+                column.entries.push((row, abundance));
+            }
+        }
+        
+        column.entries.retain(|&(_, value)| value != 0.0);
+        column.entries.sort_unstable_by_key(|&(row, _)| row);
+        self.columns[col] = column;
+    }
+
+    /// Merges the samples of another matrix into this one
+    /// 
+    /// # Arguments
+    /// * `other` - Matrix at the same level, usually holding a single sample
+    /// 
+    /// # Implementation Details
+    /// Used to combine the per-sample partial matrices built by parallel
+    /// workers. The annotation of a taxon already in the matrix is kept, so
+    /// merging partials in input order gives the same matrix as adding the
+    /// samples one by one. Samples present in both matrices are overwritten.
+    pub fn merge(&mut self, other: AbundanceMatrix) {
+        let rows: Vec<u32> = other.row_taxids.into_iter().zip(other.taxa)
+            .map(|(taxid, taxon)| self.intern_taxon(taxid, || taxon))
+            .collect();
+        for (sample, mut column) in other.samples.into_iter().zip(other.columns) {
+            for entry in column.entries.iter_mut() {
+                entry.0 = rows[entry.0 as usize];
+            }
+            column.entries.sort_unstable_by_key(|&(row, _)| row);
+            let col = self.intern_sample(&sample);
+            self.columns[col] = column;
+        }
     }
 
     /// Calculates the total reads in a sample
@@ -162,38 +482,115 @@ impl AbundanceMatrix {
     /// 
     /// # Arguments
     /// * `node` - Current node to process
-    /// * `sample_name` - Name of the sample
+    /// * `total` - Total reads of the sample when normalizing during processing
     /// * `min_abundance` - Minimum abundance threshold
-    /// * `normalize` - If true, normalizes values during processing
+    /// * `lineage` - Lineage labels of the ancestors of the node
+    /// * `entries` - (row, value) pairs of the sample being added
+    /// * `unassigned` - (row, reads) pairs of the reads classified above the level
+    /// 
+    /// # Returns
+    /// * `u64` - Direct reads of the node and its descendants that are below the
+    ///   level without an ancestor at it, left to the nearest ancestor above the level
     /// 
     /// # Implementation Details
     /// This recursive method traverses the taxonomic tree, extracting
     /// abundance data for nodes at the target taxonomic level.
     /// The recursive approach ensures we capture all taxa at the specified
-    /// level, regardless of their position in the tree.
-    fn process_node(&mut self, node: &TaxonEntry, sample_name: &str, min_abundance: f64, normalize: bool) {
+    /// level, regardless of their position in the tree. The descendants of a
+    /// selected node are not visited, as their reads are already part of its clade.
+    /// Every other read is a direct read of a node above the level, or of a node
+    /// below it with no ancestor at the level (e.g. a species directly under a
+    /// family in a genus matrix), whose reads are counted with those of its
+    /// nearest ancestor above the level.
+    fn process_node(&mut self, node: &TaxonEntry, total: Option<f64>, min_abundance: f64, lineage: &mut Vec<String>, entries: &mut Vec<(u32, f64)>, unassigned: &mut Vec<(u32, f64)>) -> u64 {
+        let label = node.lineage_label();
+        let has_label = label.is_some();
+        if let Some(label) = label {
+            lineage.push(label);
+        }
+
+        let mut orphan_reads = 0;
         // Check if the node is at the desired taxonomic level
-        if node.rank == self.level {
-            let abundance = if normalize {
+        if self.level.matches(node.rank) {
+            let abundance = match total {
                 // Normalize by the total reads in the sample
-                let total = self.sample_totals.get(sample_name).unwrap_or(&1.0);
-                (node.clade_reads as f64 / total) * 100.0
-            } else {
-                node.clade_reads as f64
+                Some(total) => (node.clade_reads as f64 / total) * 100.0,
+                None => node.clade_reads as f64,
             };
 
             if abundance >= min_abundance {
-                self.taxon_abundances
-                    .entry(node.name.clone())
-                    .or_default()
-                    .insert(sample_name.to_string(), abundance);
+                let row = self.intern_taxon(node.taxid, || TaxonAnnotation {
+                    name: node.name.clone(),
+                    rank: node.rank,
+                    lineage: lineage.clone(),
+                    unassigned: false,
+                });
+                entries.push((row, abundance));
+            }
+        } else {
+            // Process children recursively
+            let mut reads = node.direct_reads;
+            for child in &node.children {
+                reads += self.process_node(child, total, min_abundance, lineage, entries, unassigned);
+            }
+            if node.rank.is_at_or_below(self.level.rank()) {
+                orphan_reads = reads;
+            } else if reads > 0 {
+                if let Some(row) = self.unassigned_row(node, lineage) {
+                    unassigned.push((row, reads as f64));
+                }
             }
         }
 
-        // Process children recursively
-        for child in &node.children {
-            self.process_node(child, sample_name, min_abundance, normalize);
+        if has_label {
+            lineage.pop();
         }
+        orphan_reads
+    }
+
+    /// Row receiving the direct reads of a node above the level (with those of its
+    /// descendants below the level without an ancestor at it), if they are kept
+    /// 
+    /// Per-parent rows are keyed by the taxid of the node, which cannot be a
+    /// regular row since the node is not at the level of the matrix.
+    fn unassigned_row(&mut self, node: &TaxonEntry, lineage: &[String]) -> Option<u32> {
+        let level = self.level;
+        match self.unassigned {
+            UnassignedReads::Drop => None,
+            UnassignedReads::PerParent => Some(self.intern_taxon(node.taxid, || TaxonAnnotation {
+                name: format!("{} (unassigned {})", node.name, level.rank().name()),
+                rank: node.rank,
+                lineage: lineage.to_vec(),
+                unassigned: true,
+            })),
+            UnassignedReads::Single => Some(self.intern_taxon(UNASSIGNED_TAXID, || TaxonAnnotation {
+                name: format!("Unassigned at {}", level),
+                rank: Rank::Unknown,
+                lineage: Vec::new(),
+                unassigned: true,
+            })),
+        }
+    }
+
+    /// Sets the abundance of a taxon in a sample, adding both to the matrix if needed
+    /// 
+    /// # Arguments
+    /// * `taxid` - Taxid of the row
+    /// * `taxon` - Annotation of the row, used only when the taxid is new
+    /// * `sample` - Name of the sample
+    /// * `value` - Abundance value
+    pub fn set_abundance(&mut self, taxid: u32, taxon: TaxonAnnotation, sample: &str, value: f64) {
+        let row = self.intern_taxon(taxid, || taxon);
+        let col = self.intern_sample(sample);
+        self.columns[col].set(row, value);
+    }
+
+    /// Sets the total reads of a sample, adding the sample to the matrix if needed
+    /// 
+    /// The total is used as the denominator when transforming to proportions.
+    pub fn set_sample_total(&mut self, sample: &str, total: f64) {
+        let col = self.intern_sample(sample);
+        self.columns[col].total = total;
     }
 
     /// Applies a transformation to convert absolute counts to proportions
@@ -204,16 +601,352 @@ impl AbundanceMatrix {
     /// It divides each taxon's count by the total reads in its sample
     /// and multiplies by 100 to get a percentage.
     pub fn transform_to_proportions(&mut self) {
-        // Only apply if we're not already normalized
-        for (_, sample_abundances) in self.taxon_abundances.iter_mut() {
-            for (sample, abundance) in sample_abundances.iter_mut() {
-                if let Some(total) = self.sample_totals.get(sample) {
-                    if *total > 0.0 {
-                        *abundance = (*abundance / total) * 100.0;
+        for column in self.columns.iter_mut().filter(|column| column.total > 0.0) {
+            for (_, abundance) in column.entries.iter_mut() {
+                *abundance = (*abundance / column.total) * 100.0;
+            }
+        }
+    }
+
+    /// Applies a normalization strategy to every sample of the matrix
+    /// 
+    /// # Arguments
+    /// * `normalization` - Strategy to apply; the matrix must hold absolute read counts
+    /// 
+    /// # Returns
+    /// * `AbundanceResult<Vec<String>>` - Samples removed because they have fewer
+    ///   reads than the rarefaction depth
+    /// 
+    /// # Implementation Details
+    /// Samples are processed in output order, so rarefaction with the same seed
+    /// always draws the same reads. Rarefaction subsamples the reads of the taxa
+    /// of the level (including "Other"), while the "Unclassified" and unassigned
+    /// rows keep their reads. Without an explicit depth, it uses the smallest
+    /// number of such reads in a sample of the matrix, leaving out (and
+    /// dropping) the samples with none.
+    pub fn apply_normalization(&mut self, normalization: &Normalization) -> AbundanceResult<Vec<String>> {
+        let mut normalization = *normalization;
+        let mut rng = StdRng::seed_from_u64(0);
+        let rarefy = matches!(normalization, Normalization::Rarefy { .. });
+        if let Normalization::Rarefy { ref mut depth, seed } = normalization {
+            rng = StdRng::seed_from_u64(seed);
+            if depth.is_none() {
+                // Samples without reads at the level are dropped rather than setting the depth to 0
+                let smallest = self.columns.iter()
+                    .map(|column| column.entries.iter()
+                        .filter(|&&(row, _)| !self.is_outside_level(row as usize))
+                        .map(|&(_, value)| value)
+                        .sum::<f64>())
+                    .filter(|&reads| reads >= 1.0)
+                    .fold(f64::INFINITY, f64::min);
+                if !smallest.is_finite() {
+                    return Err(AbundanceMatrixError::InvalidValue(
+                        "no sample has reads at the level of the matrix to rarefy".to_string(),
+                    ));
+                }
+                *depth = Some(smallest as u64);
+            }
+        }
+
+        let mut dropped = Vec::new();
+        for sample in self.sample_names() {
+            let col = self.sample_index[&sample];
+            // Rows left out of the rarefaction, added back unchanged
+            let (kept, entries): (Vec<_>, Vec<_>) = self.columns[col].entries.iter()
+                .partition(|&&(row, _)| rarefy && self.is_outside_level(row as usize));
+            match normalization.apply(&entries, self.row_taxids.len(), &mut rng)? {
+                Some(mut entries) => {
+                    if let Normalization::Rarefy { depth: Some(depth), .. } = normalization {
+                        self.columns[col].total = depth as f64 + kept.iter().map(|&(_, value)| value).sum::<f64>();
+                    }
+                    if !kept.is_empty() {
+                        entries.extend(kept);
+                        entries.sort_unstable_by_key(|&(row, _)| row);
+                    }
+                    self.columns[col].entries = entries;
+                }
+                None => dropped.push(sample),
+            }
+        }
+        for sample in &dropped {
+            self.remove_sample(sample);
+        }
+        Ok(dropped)
+    }
+
+    /// Removes the taxa that do not pass a cohort-level filter
+    /// 
+    /// # Arguments
+    /// * `filter` - Prevalence and total read criteria
+    /// * `aggregate_other` - If true, the values of the removed taxa are added to an
+    ///   "Other" row, so the column sums do not change
+    /// 
+    /// # Returns
+    /// * `usize` - Number of taxa removed
+    /// 
+    /// # Implementation Details
+    /// The matrix must hold read counts, as the abundance of a taxon in a sample
+    /// is its percentage of the sample total. "Unclassified", "Unassigned" and
+    /// "Other" rows are never removed.
+    pub fn filter_taxa(&mut self, filter: &PrevalenceFilter, aggregate_other: bool) -> usize {
+        let mut prevalence = vec![0usize; self.row_taxids.len()];
+        let mut total_reads = vec![0.0; self.row_taxids.len()];
+        for column in &self.columns {
+            for &(row, value) in &column.entries {
+                total_reads[row as usize] += value;
+                let abundance = if column.total > 0.0 { value / column.total * 100.0 } else { 0.0 };
+                if value > 0.0 && abundance >= filter.min_abundance {
+                    prevalence[row as usize] += 1;
+                }
+            }
+        }
+
+        let keep: Vec<bool> = prevalence.into_iter().zip(total_reads)
+            .map(|(prevalence, total_reads)| filter.keeps(prevalence, total_reads))
+            .collect();
+        self.retain_taxa(&keep, aggregate_other)
+    }
+
+    /// Keeps the taxa with the highest abundances across samples
+    /// 
+    /// # Arguments
+    /// * `n` - Number of taxa to keep
+    /// * `ranking` - Statistic used to rank the taxa across samples
+    /// * `aggregate_other` - If true, the values of the removed taxa are added to an "Other" row
+    /// 
+    /// # Returns
+    /// * `usize` - Number of taxa removed
+    /// 
+    /// # Implementation Details
+    /// Meant for plotting-ready matrices, so it works on the values as they are
+    /// (usually after normalization). Samples without a taxon count as zeros,
+    /// and ties are broken by the mean and then by taxid. "Unclassified",
+    /// "Unassigned" and "Other" rows are kept and do not count towards `n`.
+    pub fn keep_top_taxa(&mut self, n: usize, ranking: TaxonRanking, aggregate_other: bool) -> usize {
+        let samples = self.columns.len();
+        let mut values: Vec<Vec<f64>> = vec![Vec::new(); self.row_taxids.len()];
+        for column in &self.columns {
+            for &(row, value) in &column.entries {
+                values[row as usize].push(value);
+            }
+        }
+
+        let mut scores: Vec<(usize, f64, f64)> = values.into_iter().enumerate()
+            .filter(|&(row, _)| !self.is_special_row(row))
+            .map(|(row, mut values)| {
+                let mean = if samples > 0 { values.iter().sum::<f64>() / samples as f64 } else { 0.0 };
+                let score = match ranking {
+                    TaxonRanking::Mean => mean,
+                    TaxonRanking::Median => {
+                        // Samples without the taxon are zeros
+                        values.resize(samples, 0.0);
+                        values.sort_by(|a, b| a.total_cmp(b));
+                        quantile_of_sorted(&values, 0.5)
                     }
+                    TaxonRanking::Max => values.iter().copied().fold(0.0, f64::max),
+                    TaxonRanking::Prevalence => values.iter().filter(|&&v| v != 0.0).count() as f64,
+                };
+                (row, score, mean)
+            })
+            .collect();
+        scores.sort_by(|a, b| {
+            b.1.total_cmp(&a.1)
+                .then(b.2.total_cmp(&a.2))
+                .then(self.row_taxids[a.0].cmp(&self.row_taxids[b.0]))
+        });
+
+        let mut keep = vec![false; self.row_taxids.len()];
+        for &(row, _, _) in scores.iter().take(n) {
+            keep[row] = true;
+        }
+        self.retain_taxa(&keep, aggregate_other)
+    }
+
+    /// Whether a row is the "Unclassified", "Other" or an unassigned row
+    fn is_special_row(&self, row: usize) -> bool {
+        matches!(self.row_taxids[row], UNCLASSIFIED_TAXID | OTHER_TAXID) || self.taxa[row].unassigned
+    }
+
+    /// Whether a row holds reads that are not assigned to a taxon of the level:
+    /// the "Unclassified" and unassigned rows
+    fn is_outside_level(&self, row: usize) -> bool {
+        self.row_taxids[row] == UNCLASSIFIED_TAXID || self.taxa[row].unassigned
+    }
+
+    /// Whether a taxid is that of the "Unclassified", "Other" or an unassigned row
+    /// rather than a taxon of the level of the matrix
+    pub fn is_special_taxon(&self, taxid: u32) -> bool {
+        self.row_index.get(&taxid).is_some_and(|&row| self.is_special_row(row as usize))
+    }
+
+    /// Removes the rows not marked in `keep`, optionally adding their values to the "Other" row
+    /// 
+    /// "Unclassified", "Unassigned" and "Other" rows are always kept, and the
+    /// remaining rows keep their relative order.
+    fn retain_taxa(&mut self, keep: &[bool], aggregate_other: bool) -> usize {
+        // New index of each kept row
+        let mut new_rows = vec![None; self.row_taxids.len()];
+        let mut kept = 0u32;
+        for (row, &keep) in keep.iter().enumerate() {
+            if keep || self.is_special_row(row) {
+                new_rows[row] = Some(kept);
+                kept += 1;
+            }
+        }
+        let removed = self.row_taxids.len() - kept as usize;
+        if removed == 0 {
+            return 0;
+        }
+
+        let row_taxids = std::mem::take(&mut self.row_taxids);
+        let taxa = std::mem::take(&mut self.taxa);
+        self.row_index.clear();
+        for ((taxid, taxon), new_row) in row_taxids.into_iter().zip(taxa).zip(&new_rows) {
+            if new_row.is_some() {
+                self.intern_taxon(taxid, || taxon);
+            }
+        }
+        let other_row = if aggregate_other {
+            let level = self.level.rank();
+            Some(self.intern_taxon(OTHER_TAXID, || TaxonAnnotation {
+                name: OTHER_NAME.to_string(),
+                rank: level,
+                lineage: Vec::new(),
+                unassigned: false,
+            }))
+        } else {
+            None
+        };
+
+        for column in self.columns.iter_mut() {
+            let mut other = 0.0;
+            let mut entries = Vec::with_capacity(column.entries.len());
+            for &(row, value) in &column.entries {
+                match new_rows[row as usize] {
+                    Some(new_row) if Some(new_row) == other_row => other += value,
+                    Some(new_row) => entries.push((new_row, value)),
+                    None => other += value,
                 }
             }
+            if let Some(other_row) = other_row {
+                if other != 0.0 {
+                    entries.push((other_row, other));
+                }
+            }
+            entries.sort_unstable_by_key(|&(row, _)| row);
+            column.entries = entries;
+        }
+        removed
+    }
+
+    /// Removes a sample column from the matrix
+    /// 
+    /// Rows are kept even when the sample held their only values.
+    pub fn remove_sample(&mut self, sample: &str) -> bool {
+        let col = match self.sample_index.remove(sample) {
+            Some(col) => col,
+            None => return false,
+        };
+        self.samples.swap_remove(col);
+        self.columns.swap_remove(col);
+        if let Some(moved) = self.samples.get(col) {
+            self.sample_index.insert(moved.clone(), col);
+        }
+        true
+    }
+
+    /// Ranks selected for the rows of the matrix
+    pub fn level(&self) -> RankSelector {
+        self.level
+    }
+
+    /// Sets the column order of the matrix
+    /// 
+    /// # Arguments
+    /// * `order` - Sample names in output order; samples missing from it go last, sorted by name
+    pub fn set_sample_order(&mut self, order: Vec<String>) {
+        self.sample_order = Some(order);
+    }
+
+    /// Samples in the matrix, in output order
+    /// 
+    /// Samples follow the order set with `set_sample_order`, or are sorted by name.
+    pub fn sample_names(&self) -> Vec<String> {
+        let mut samples = self.samples.clone();
+        samples.sort();
+        if let Some(ref order) = self.sample_order {
+            let positions: HashMap<&str, usize> = order.iter().enumerate().map(|(i, s)| (s.as_str(), i)).collect();
+            samples.sort_by_key(|sample| positions.get(sample.as_str()).copied().unwrap_or(order.len()));
+        }
+        samples
+    }
+
+    /// Taxids of the matrix rows in output order
+    /// 
+    /// "Unclassified" comes first, followed by the taxa sorted by name and then
+    /// by taxid, the single "Unassigned" row and "Other" last.
+    pub fn taxids(&self) -> Vec<u32> {
+        let group = |taxid: u32| match taxid {
+            UNCLASSIFIED_TAXID => 0,
+            UNASSIGNED_TAXID => 2,
+            OTHER_TAXID => 3,
+            _ => 1,
+        };
+        let mut rows: Vec<usize> = (0..self.row_taxids.len()).collect();
+        rows.sort_by(|&a, &b| {
+            let (taxid_a, taxid_b) = (self.row_taxids[a], self.row_taxids[b]);
+            group(taxid_a).cmp(&group(taxid_b))
+                .then_with(|| self.taxa[a].name.cmp(&self.taxa[b].name))
+                .then(taxid_a.cmp(&taxid_b))
+        });
+        rows.into_iter().map(|row| self.row_taxids[row]).collect()
+    }
+
+    /// Name, rank and lineage of a taxon in the matrix
+    pub fn taxon(&self, taxid: u32) -> Option<&TaxonAnnotation> {
+        self.row_index.get(&taxid).map(|&row| &self.taxa[row as usize])
+    }
+
+    /// Abundance of a taxon in a sample (0 when absent)
+    #[allow(dead_code)]
+    pub fn abundance(&self, taxid: u32, sample: &str) -> f64 {
+        match (self.row_index.get(&taxid), self.sample_index.get(sample)) {
+            (Some(&row), Some(&col)) => self.columns[col].get(row),
+            _ => 0.0,
+        }
+    }
+
+    /// Non-zero (taxid, value) pairs of the taxa in a sample, leaving out the
+    /// "Unclassified", "Unassigned" and "Other" rows, which are not taxa of the level
+    pub fn taxon_values(&self, sample: &str) -> Vec<(u32, f64)> {
+        match self.sample_index.get(sample) {
+            Some(&col) => self.columns[col].entries.iter()
+                .filter(|&&(row, _)| !self.is_special_row(row as usize))
+                .map(|&(row, value)| (self.row_taxids[row as usize], value))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Non-zero abundances of every row, in output order
+    /// 
+    /// # Returns
+    /// * `Vec<(u32, Vec<(usize, f64)>)>` - Taxid of each row in `taxids()` order with its
+    ///   (column, value) pairs, where columns are positions in `sample_names()`
+    /// 
+    /// # Implementation Details
+    /// The columns are transposed once, so writing a matrix takes time and
+    /// memory proportional to the non-zero values rather than to rows × samples.
+    pub fn sparse_rows(&self) -> Vec<(u32, Vec<(usize, f64)>)> {
+        let mut rows: Vec<Vec<(usize, f64)>> = vec![Vec::new(); self.row_taxids.len()];
+        for (position, sample) in self.sample_names().iter().enumerate() {
+            for &(row, value) in &self.columns[self.sample_index[sample]].entries {
+                rows[row as usize].push((position, value));
+            }
         }
+        self.taxids().into_iter()
+            .map(|taxid| (taxid, std::mem::take(&mut rows[self.row_index[&taxid] as usize])))
+            .collect()
     }
 
     /// Generates the abundance matrix in TSV format
@@ -226,76 +959,620 @@ impl AbundanceMatrix {
     /// 
     /// # Implementation Details
     /// This method writes the abundance matrix to a TSV file with
-    /// taxa as rows and samples as columns. It uses a BufWriter for
-    /// efficient I/O operations and ensures "Unclassified" appears
-    /// at the top of the matrix if present.
+    /// taxa as rows and samples as columns. Each row starts with the
+    /// taxid and name of the taxon, plus its lineage when enabled and an
+    /// `unassigned` column (true or false) when the matrix has unassigned rows.
+    /// It uses a BufWriter for efficient I/O operations and ensures
+    /// "Unclassified" appears at the top of the matrix if present.
     pub fn write_matrix(&self, output_file: &str) -> AbundanceResult<()> {
         let file = File::create(output_file)?;
         let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
 
         // Get sorted list of samples
-        let mut samples: Vec<String> = self.samples.iter().cloned().collect();
-        samples.sort();
+        let samples = self.sample_names();
 
         // Write header
-        write!(writer, "Taxon")?;
+        let include_unassigned = self.taxa.iter().any(|taxon| taxon.unassigned);
+        write!(writer, "taxid\tname")?;
+        if self.include_lineage {
+            write!(writer, "\tlineage")?;
+        }
+        if include_unassigned {
+            write!(writer, "\tunassigned")?;
+        }
         for sample in &samples {
             write!(writer, "\t{}", sample)?;
         }
         writeln!(writer)?;
 
-        // Prepare the taxa
-        let mut taxons: Vec<String> = self.taxon_abundances.keys().cloned().collect();
-        
-        // Sort taxa but place "Unclassified" at the beginning if it exists
-        taxons.sort_by(|a, b| {
-            if a == UNCLASSIFIED_NAME {
-                Ordering::Less
-            } else if b == UNCLASSIFIED_NAME {
-                Ordering::Greater
-            } else {
-                a.cmp(b)
+        // Write data, with "Unclassified" at the beginning if it exists
+        let mut values = vec![0.0; samples.len()];
+        for (taxid, entries) in self.sparse_rows() {
+            let taxon = &self.taxa[self.row_index[&taxid] as usize];
+            write!(writer, "{}\t{}", taxid, taxon.name)?;
+            if self.include_lineage {
+                write!(writer, "\t{}", taxon.lineage.join(";"))?;
+            }
+            if include_unassigned {
+                write!(writer, "\t{}", taxon.unassigned)?;
             }
-        });
-
-        // Write data
-        for taxon in taxons {
-            write!(writer, "{}", taxon)?;
-            let abundances = self.taxon_abundances.get(&taxon).unwrap();
             
-            for sample in &samples {
-                let abundance = abundances.get(sample).unwrap_or(&0.0);
+            for &(col, value) in &entries {
+                values[col] = value;
+            }
+            for abundance in &values {
                 write!(writer, "\t{:.6}", abundance)?;
             }
+            for &(col, _) in &entries {
+                values[col] = 0.0;
+            }
             writeln!(writer)?;
         }
 
         writer.flush()?;
         Ok(())
     }
-}
 
-/// Validates the specified taxonomic level
-/// 
-/// # Arguments
-/// * `level` - Taxonomic level to validate (K, P, C, O, F, G, S)
-/// 
-/// # Returns
-/// * `bool` - True if the level is valid, false otherwise
-pub fn validate_taxonomic_level(level: &str) -> bool {
-    TAXON_LEVELS.iter().any(|(code, _)| *code == level)
+    /// Generates the abundance matrix in tidy long format
+    /// 
+    /// # Arguments
+    /// * `output_file` - Path to the output file
+    /// 
+    /// # Returns
+    /// * `AbundanceResult<()>` - Result of the operation
+    /// 
+    /// # Implementation Details
+    /// Writes one line per non-zero cell with the columns `sample`, `taxid`,
+    /// `name`, `rank`, `reads`, `relative_abundance` and `lineage`. The matrix
+    /// must hold read counts; the relative abundance is the percentage of the
+    /// sample total, as in the default TSV matrix. Lines follow the sample
+    /// order and then the row order of `write_matrix`.
+    pub fn write_long(&self, output_file: &str) -> AbundanceResult<()> {
+        let file = File::create(output_file)?;
+        let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+        writeln!(writer, "sample\ttaxid\tname\trank\treads\trelative_abundance\tlineage")?;
+
+        // Output position of each row
+        let mut positions = vec![0usize; self.row_taxids.len()];
+        for (position, taxid) in self.taxids().into_iter().enumerate() {
+            positions[self.row_index[&taxid] as usize] = position;
+        }
+
+        for sample in self.sample_names() {
+            let column = &self.columns[self.sample_index[&sample]];
+            let mut entries = column.entries.clone();
+            entries.sort_unstable_by_key(|&(row, _)| positions[row as usize]);
+            for (row, reads) in entries {
+                let taxon = &self.taxa[row as usize];
+                let relative = if column.total > 0.0 { reads / column.total * 100.0 } else { 0.0 };
+                writeln!(
+                    writer, "{}\t{}\t{}\t{}\t{}\t{:.6}\t{}",
+                    sample, self.row_taxids[row as usize], taxon.name, taxon.rank, reads, relative, taxon.lineage.join(";")
+                )?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Generates the taxonomy table of the matrix rows
+    ///
+    /// # Arguments
+    /// * `output_file` - Path to the output file
+    /// * `format` - Per-rank columns (phyloseq) or QIIME2 taxonomy strings
+    ///
+    /// # Returns
+    /// * `AbundanceResult<()>` - Result of the operation
+    ///
+    /// # Implementation Details
+    /// Rows follow the order of `write_matrix` and are identified by taxid.
+    /// Ranks come from the lineage collected while walking down each report,
+    /// so every row carries its ancestors from domain to the taxon itself.
+    /// Ranks missing from a lineage are left empty. In the QIIME2 layout,
+    /// missing ranks above the deepest one keep an empty label ("c__"), the
+    /// Unclassified row is "Unassigned" and rows without lineage use their name.
+    pub fn write_taxonomy_table(&self, output_file: &str, format: TaxonomyFormat) -> AbundanceResult<()> {
+        let file = File::create(output_file)?;
+        let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+        let ranks: Vec<Rank> = match format {
+            TaxonomyFormat::Table => Rank::standard_ranks().collect(),
+            // QIIME2 lineages are positional, so the kingdom level is left out as in GTDB and SILVA
+            TaxonomyFormat::Qiime2 => Rank::standard_ranks().filter(|rank| *rank != Rank::Kingdom(0)).collect(),
+        };
+
+        match format {
+            TaxonomyFormat::Table => {
+                write!(writer, "taxid")?;
+                for rank in &ranks {
+                    let name = rank.name();
+                    write!(writer, "\t{}{}", name[..1].to_ascii_uppercase(), &name[1..])?;
+                }
+                writeln!(writer)?;
+            }
+            TaxonomyFormat::Qiime2 => writeln!(writer, "Feature ID\tTaxon")?,
+        }
+
+        for taxid in self.taxids() {
+            let taxon = &self.taxa[self.row_index[&taxid] as usize];
+            let names: Vec<Option<&str>> = ranks.iter()
+                .map(|rank| {
+                    taxon.lineage.iter()
+                        .filter_map(|label| split_gtdb_name(label))
+                        .find(|(code, _)| Rank::from_code(code) == *rank)
+                        .map(|(_, name)| name)
+                })
+                .collect();
+
+            match format {
+                TaxonomyFormat::Table => {
+                    write!(writer, "{}", taxid)?;
+                    for name in &names {
+                        write!(writer, "\t{}", name.unwrap_or(""))?;
+                    }
+                    writeln!(writer)?;
+                }
+                TaxonomyFormat::Qiime2 => {
+                    let taxon_string = match names.iter().rposition(|name| name.is_some()) {
+                        Some(deepest) => ranks[..=deepest].iter().zip(&names)
+                            .map(|(rank, name)| format!(
+                                "{}__{}", rank.letter().unwrap_or('x').to_ascii_lowercase(), name.unwrap_or("")
+                            ))
+                            .collect::<Vec<_>>()
+                            .join("; "),
+                        None if taxid == UNCLASSIFIED_TAXID => "Unassigned".to_string(),
+                        None => taxon.name.clone(),
+                    };
+                    writeln!(writer, "{}\t{}", taxid, taxon_string)?;
+                }
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Reads an abundance matrix in the TSV layout written by `write_matrix`
+    /// 
+    /// # Arguments
+    /// * `input_file` - Path to the TSV file
+    /// 
+    /// # Returns
+    /// * `AbundanceResult<AbundanceMatrix>` - Matrix with the same rows and samples
+    /// 
+    /// # Implementation Details
+    /// The header must start with `taxid` and `name` columns, optionally followed
+    /// by `lineage` and `unassigned`; every other column is a sample. Ranks are taken from the
+    /// deepest lineage element, and the column sums are used as sample totals
+    /// since the reads of the taxa outside the matrix are unknown.
+    pub fn read_matrix(input_file: &str) -> AbundanceResult<Self> {
+        let file = File::open(input_file)?;
+        let reader = BufReader::with_capacity(BUFFER_SIZE, file);
+        let mut lines = reader.lines();
+
+        let header = match lines.next() {
+            Some(line) => line?,
+            None => return Err(AbundanceMatrixError::InvalidValue("empty abundance matrix file".to_string())),
+        };
+        let columns: Vec<&str> = header.split('\t').collect();
+        if columns.len() < 2 || columns[0] != "taxid" || columns[1] != "name" {
+            return Err(AbundanceMatrixError::InvalidValue(
+                "the abundance matrix header must start with 'taxid' and 'name' columns".to_string(),
+            ));
+        }
+        let has_lineage = columns.get(2) == Some(&"lineage");
+        let unassigned_column = Some(2 + has_lineage as usize)
+            .filter(|&i| columns.get(i) == Some(&"unassigned"));
+        let first_sample = 2 + has_lineage as usize + unassigned_column.is_some() as usize;
+        let samples = &columns[first_sample..];
+
+        let mut rows = Vec::new();
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let line_number = i + 2;
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != columns.len() {
+                return Err(AbundanceMatrixError::InvalidValue(format!(
+                    "expected {} columns at line {}, found {}", columns.len(), line_number, fields.len()
+                )));
+            }
+
+            let taxid = fields[0].parse::<u32>().map_err(|_| {
+                AbundanceMatrixError::InvalidValue(format!("invalid taxid '{}' at line {}", fields[0], line_number))
+            })?;
+            let lineage = if has_lineage {
+                fields[2].split(';').map(|s| s.trim()).filter(|s| !s.is_empty()).map(|s| s.to_string()).collect()
+            } else {
+                Vec::new()
+            };
+            let values = fields[first_sample..].iter()
+                .map(|v| v.trim().parse::<f64>().map_err(|_| {
+                    AbundanceMatrixError::InvalidValue(format!("invalid abundance '{}' at line {}", v, line_number))
+                }))
+                .collect::<AbundanceResult<Vec<f64>>>()?;
+
+            let mut taxon = TaxonAnnotation::from_lineage(taxid, Some(fields[1].to_string()), None, lineage);
+            if let Some(i) = unassigned_column {
+                taxon.unassigned |= fields[i].trim().parse::<bool>().map_err(|_| {
+                    AbundanceMatrixError::InvalidValue(format!("invalid unassigned flag '{}' at line {}", fields[i], line_number))
+                })?;
+            }
+            rows.push((taxid, taxon, values));
+        }
+
+        let mut matrix = Self::new(Self::infer_level(rows.iter().map(|(_, taxon, _)| taxon)));
+        matrix.set_include_lineage(has_lineage);
+        matrix.set_force_include_unclassified(rows.iter().any(|(taxid, _, _)| *taxid == UNCLASSIFIED_TAXID));
+        let mut cols = Vec::with_capacity(samples.len());
+        for (i, sample) in samples.iter().enumerate() {
+            let total = rows.iter().map(|(_, _, values)| values[i]).sum();
+            matrix.set_sample_total(sample, total);
+            cols.push(matrix.intern_sample(sample));
+        }
+        // Rows are added in file order, so each column is filled in row order
+        for (taxid, taxon, values) in rows {
+            let row = matrix.intern_taxon(taxid, || taxon);
+            for (&col, value) in cols.iter().zip(values) {
+                matrix.columns[col].set(row, value);
+            }
+        }
+        Ok(matrix)
+    }
 }
 
-/// Gets the full name of the taxonomic level
-/// 
-/// # Arguments
-/// * `level` - Taxonomic level code (K, P, C, O, F, G, S)
-/// 
-/// # Returns
-/// * `Option<&str>` - Full name of the taxonomic level if valid
-#[allow(dead_code)]
-pub fn get_taxonomic_level_name(level: &str) -> Option<&str> {
-    TAXON_LEVELS.iter()
-        .find(|(code, _)| *code == level)
-        .map(|(_, name)| *name)
-} 
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(rank: &str, taxid: u32, name: &str, clade: u64, children: Vec<TaxonEntry>) -> TaxonEntry {
+        let direct = clade - children.iter().map(|c| c.clade_reads).sum::<u64>();
+        let mut entry = TaxonEntry::new(0.0, clade, direct, rank.to_string(), taxid, name.to_string(), 0);
+        entry.children = children;
+        entry
+    }
+
+    fn report() -> KrakenReport {
+        let bacteria = entry("D", 2, "Bacteria", 60, vec![
+            entry("G", 561, "Escherichia", 40, vec![entry("S", 562, "Escherichia coli", 30, vec![])]),
+            entry("S", 77133, "uncultured bacterium", 20, vec![]),
+        ]);
+        let archaea = entry("D", 2157, "Archaea", 20, vec![entry("S", 115547, "uncultured bacterium", 20, vec![])]);
+        let root = entry("R", 1, "root", 80, vec![bacteria, archaea]);
+        KrakenReport::new(root, Some(entry("U", 0, "unclassified", 20, vec![])))
+    }
+
+    #[test]
+    fn test_rows_keyed_by_taxid() {
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.set_force_include_unclassified(true);
+        matrix.add_sample(&report(), "sample", 0.0, false);
+
+        // Homonyms in different domains stay separate rows
+        assert_eq!(matrix.taxids(), vec![UNCLASSIFIED_TAXID, 562, 77133, 115547]);
+        assert_eq!(matrix.abundance(77133, "sample"), 20.0);
+        assert_eq!(matrix.abundance(115547, "sample"), 20.0);
+
+        let archaeal = matrix.taxon(115547).unwrap();
+        assert_eq!(archaeal.name, "uncultured bacterium");
+        assert_eq!(archaeal.lineage, vec!["d__Archaea", "s__uncultured bacterium"]);
+        assert_eq!(matrix.taxon(562).unwrap().lineage, vec!["d__Bacteria", "g__Escherichia", "s__Escherichia coli"]);
+    }
+
+    #[test]
+    fn test_write_matrix_with_lineage() {
+        let mut matrix = AbundanceMatrix::new("G".parse().unwrap());
+        matrix.set_include_lineage(true);
+        matrix.add_sample(&report(), "sample", 0.0, true);
+
+        let path = std::env::temp_dir().join(format!("krakenclip_matrix_{}.tsv", std::process::id()));
+        matrix.write_matrix(path.to_str().unwrap()).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(content, "taxid\tname\tlineage\tsample\n561\tEscherichia\td__Bacteria;g__Escherichia\t40.000000\n");
+    }
+
+    #[test]
+    fn test_tsv_round_trip() {
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.set_force_include_unclassified(true);
+        matrix.set_include_lineage(true);
+        matrix.add_sample(&report(), "s1", 0.0, false);
+        matrix.add_sample(&report(), "s2", 25.0, false);
+
+        let dir = std::env::temp_dir();
+        let first = dir.join(format!("krakenclip_roundtrip1_{}.tsv", std::process::id()));
+        let second = dir.join(format!("krakenclip_roundtrip2_{}.tsv", std::process::id()));
+        matrix.write_matrix(first.to_str().unwrap()).unwrap();
+
+        let read = AbundanceMatrix::read_matrix(first.to_str().unwrap()).unwrap();
+        assert_eq!(read.level(), RankSelector::Exact(Rank::Species(0)));
+        assert_eq!(read.taxids(), matrix.taxids());
+        assert_eq!(read.taxon(562).unwrap().rank, Rank::Species(0));
+        assert_eq!(read.abundance(562, "s2"), 30.0);
+        assert_eq!(read.abundance(77133, "s2"), 0.0);
+
+        read.write_matrix(second.to_str().unwrap()).unwrap();
+        let original = std::fs::read_to_string(&first).unwrap();
+        let written = std::fs::read_to_string(&second).unwrap();
+        std::fs::remove_file(&first).unwrap();
+        std::fs::remove_file(&second).unwrap();
+        assert_eq!(original, written);
+    }
+
+    #[test]
+    fn test_merge_partials() {
+        let mut serial = AbundanceMatrix::new("S".parse().unwrap());
+        serial.set_force_include_unclassified(true);
+        let mut merged = AbundanceMatrix::new("S".parse().unwrap());
+        for (sample, min_abundance) in [("s1", 0.0), ("s2", 25.0)] {
+            serial.add_sample(&report(), sample, min_abundance, false);
+            let mut partial = AbundanceMatrix::new("S".parse().unwrap());
+            partial.set_force_include_unclassified(true);
+            partial.add_sample(&report(), sample, min_abundance, false);
+            merged.merge(partial);
+        }
+
+        assert_eq!(merged.sample_names(), serial.sample_names());
+        assert_eq!(merged.taxids(), serial.taxids());
+        for taxid in serial.taxids() {
+            for sample in ["s1", "s2"] {
+                assert_eq!(merged.abundance(taxid, sample), serial.abundance(taxid, sample));
+            }
+        }
+    }
+
+    #[test]
+    fn test_rarefy_drops_shallow_samples() {
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.add_sample(&report(), "deep", 0.0, false);
+        matrix.set_abundance(562, matrix.taxon(562).unwrap().clone(), "shallow", 10.0);
+
+        let rarefy = Normalization::Rarefy { depth: Some(40), seed: 3 };
+        assert_eq!(matrix.apply_normalization(&rarefy).unwrap(), vec!["shallow"]);
+        assert_eq!(matrix.sample_names(), vec!["deep"]);
+        let depth: f64 = matrix.taxids().iter().map(|&taxid| matrix.abundance(taxid, "deep")).sum();
+        assert_eq!(depth, 40.0);
+    }
+
+    #[test]
+    fn test_rarefy_skips_rows_outside_level() {
+        // Sample a has 70 species reads, b has 50 with many unclassified reads
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.set_force_include_unclassified(true);
+        matrix.set_unassigned_reads(UnassignedReads::PerParent);
+        matrix.add_sample(&report(), "a", 0.0, false);
+        matrix.set_abundance(562, matrix.taxon(562).unwrap().clone(), "b", 50.0);
+        matrix.set_abundance(UNCLASSIFIED_TAXID, matrix.taxon(UNCLASSIFIED_TAXID).unwrap().clone(), "b", 500.0);
+
+        let rarefy = Normalization::Rarefy { depth: None, seed: 3 };
+        assert!(matrix.apply_normalization(&rarefy).unwrap().is_empty());
+        let species: f64 = [562, 77133, 115547].iter().map(|&taxid| matrix.abundance(taxid, "a")).sum();
+        assert_eq!(species, 50.0);
+        assert_eq!(matrix.abundance(UNCLASSIFIED_TAXID, "a"), 20.0);
+        assert_eq!(matrix.abundance(561, "a"), 10.0);
+        assert_eq!(matrix.abundance(562, "b"), 50.0);
+        assert_eq!(matrix.abundance(UNCLASSIFIED_TAXID, "b"), 500.0);
+    }
+
+    #[test]
+    fn test_rarefy_drops_empty_samples() {
+        // The control has only unclassified reads, so the default depth comes from the other sample
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.set_force_include_unclassified(true);
+        matrix.add_sample(&report(), "sample", 0.0, false);
+        matrix.set_abundance(UNCLASSIFIED_TAXID, matrix.taxon(UNCLASSIFIED_TAXID).unwrap().clone(), "control", 100.0);
+
+        let rarefy = Normalization::Rarefy { depth: None, seed: 0 };
+        assert_eq!(matrix.apply_normalization(&rarefy).unwrap(), vec!["control"]);
+        assert_eq!(matrix.sample_names(), vec!["sample"]);
+        assert_eq!(matrix.taxon_values("sample").iter().map(|&(_, value)| value).sum::<f64>(), 70.0);
+
+        let mut control = AbundanceMatrix::new("S".parse().unwrap());
+        control.set_abundance(UNCLASSIFIED_TAXID, matrix.taxon(UNCLASSIFIED_TAXID).unwrap().clone(), "control", 100.0);
+        assert!(control.apply_normalization(&rarefy).is_err());
+        assert!(matrix.apply_normalization(&Normalization::Rarefy { depth: Some(0), seed: 0 }).is_err());
+    }
+
+    #[test]
+    fn test_prevalence_filter() {
+        // E. coli has 30% of the reads in s1 and 5% in s2, the other species 20% in both
+        let build = || {
+            let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+            matrix.set_force_include_unclassified(true);
+            matrix.add_sample(&report(), "s1", 0.0, false);
+            matrix.add_sample(&report(), "s2", 0.0, false);
+            let species = matrix.taxon(562).unwrap().clone();
+            matrix.set_abundance(562, species, "s2", 5.0);
+            matrix
+        };
+
+        let mut matrix = build();
+        let filter = PrevalenceFilter { min_abundance: 25.0, min_samples: 2, min_total_reads: None };
+        assert_eq!(matrix.filter_taxa(&filter, false), 3);
+        assert_eq!(matrix.taxids(), vec![UNCLASSIFIED_TAXID]);
+
+        // Either criterion keeps a taxon
+        let mut matrix = build();
+        let filter = PrevalenceFilter { min_abundance: 25.0, min_samples: 1, min_total_reads: Some(40.0) };
+        assert_eq!(matrix.filter_taxa(&filter, true), 0);
+        let filter = PrevalenceFilter { min_abundance: 25.0, min_samples: 1, min_total_reads: None };
+        assert_eq!(matrix.filter_taxa(&filter, true), 2);
+        assert_eq!(matrix.taxids(), vec![UNCLASSIFIED_TAXID, 562, OTHER_TAXID]);
+        assert_eq!(matrix.abundance(OTHER_TAXID, "s1"), 40.0);
+        assert_eq!(matrix.abundance(562, "s2"), 5.0);
+        assert_eq!(matrix.taxon(OTHER_TAXID).unwrap().name, "Other");
+    }
+
+    #[test]
+    fn test_keep_top_taxa() {
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.set_force_include_unclassified(true);
+        matrix.add_sample(&report(), "s1", 0.0, false);
+        let archaeal = matrix.taxon(115547).unwrap().clone();
+        matrix.set_abundance(115547, archaeal, "s2", 100.0);
+
+        // Means: 115547 = 60, 562 = 15, 77133 = 10; maximum: 115547 = 100
+        assert_eq!(matrix.keep_top_taxa(2, TaxonRanking::Mean, true), 1);
+        assert_eq!(matrix.taxids(), vec![UNCLASSIFIED_TAXID, 562, 115547, OTHER_TAXID]);
+        assert_eq!(matrix.abundance(OTHER_TAXID, "s1"), 20.0);
+        assert_eq!(matrix.abundance(UNCLASSIFIED_TAXID, "s1"), 20.0);
+
+        // The existing "Other" row takes the newly removed taxa
+        assert_eq!(matrix.keep_top_taxa(1, TaxonRanking::Prevalence, true), 1);
+        assert_eq!(matrix.taxids(), vec![UNCLASSIFIED_TAXID, 115547, OTHER_TAXID]);
+        assert_eq!(matrix.abundance(OTHER_TAXID, "s1"), 50.0);
+        assert_eq!("median".parse::<TaxonRanking>().unwrap(), TaxonRanking::Median);
+    }
+
+    #[test]
+    fn test_unassigned_reads() {
+        // 10 reads are assigned directly to Escherichia and 20 to Bacteria in a species matrix
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.set_unassigned_reads(UnassignedReads::PerParent);
+        let mut report = report();
+        report.root.children[0].direct_reads = 20;
+        report.root.children[0].clade_reads += 20;
+        report.root.clade_reads += 20;
+        matrix.add_sample(&report, "sample", 0.0, false);
+
+        let escherichia = matrix.taxon(561).unwrap();
+        assert_eq!(escherichia.name, "Escherichia (unassigned species)");
+        assert_eq!(escherichia.lineage, vec!["d__Bacteria", "g__Escherichia"]);
+        assert_eq!(matrix.abundance(561, "sample"), 10.0);
+        assert_eq!(matrix.abundance(2, "sample"), 20.0);
+        let sum: f64 = matrix.taxids().iter().map(|&taxid| matrix.abundance(taxid, "sample")).sum();
+        assert_eq!(sum, report.root.clade_reads as f64);
+
+        let mut single = AbundanceMatrix::new("S".parse().unwrap());
+        single.set_unassigned_reads(UnassignedReads::Single);
+        single.add_sample(&report, "sample", 25.0, false);
+        assert_eq!(single.taxids(), vec![562, UNASSIGNED_TAXID]);
+        assert_eq!(single.taxon(UNASSIGNED_TAXID).unwrap().name, "Unassigned at S");
+        assert_eq!(single.abundance(UNASSIGNED_TAXID, "sample"), 30.0);
+    }
+
+    #[test]
+    fn test_unassigned_reads_below_level() {
+        // A species (with a strain) directly under a family, and a strain directly under a genus
+        let family = entry("F", 543, "Enterobacteriaceae", 40, vec![
+            entry("S", 20, "Species without genus", 10, vec![entry("S1", 21, "Strain", 5, vec![])]),
+            entry("G", 561, "Escherichia", 30, vec![
+                entry("S", 562, "Escherichia coli", 20, vec![]),
+                entry("S1", 83333, "Strain without species", 4, vec![]),
+            ]),
+        ]);
+        let report = KrakenReport::new(entry("R", 1, "root", 40, vec![entry("D", 2, "Bacteria", 40, vec![family])]), None);
+
+        let mut genus = AbundanceMatrix::new("G".parse().unwrap());
+        genus.set_unassigned_reads(UnassignedReads::PerParent);
+        genus.add_sample(&report, "sample", 0.0, false);
+        assert_eq!(genus.taxids(), vec![543, 561]);
+        let family = genus.taxon(543).unwrap();
+        assert_eq!(family.name, "Enterobacteriaceae (unassigned genus)");
+        assert_eq!(family.rank, Rank::Family(0));
+        assert_eq!(genus.abundance(543, "sample"), 10.0);
+
+        let mut species = AbundanceMatrix::new("S".parse().unwrap());
+        species.set_unassigned_reads(UnassignedReads::PerParent);
+        species.add_sample(&report, "sample", 0.0, false);
+        assert_eq!(species.taxids(), vec![561, 562, 20]);
+        assert_eq!(species.abundance(561, "sample"), 10.0);
+        assert_eq!(species.abundance(20, "sample"), 10.0);
+        assert!(species.taxon(83333).is_none());
+    }
+
+    #[test]
+    fn test_filters_keep_unassigned_rows() {
+        // The 10 reads assigned directly to Escherichia are below every threshold
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.set_unassigned_reads(UnassignedReads::PerParent);
+        matrix.add_sample(&report(), "s1", 0.0, false);
+        matrix.add_sample(&report(), "s2", 0.0, false);
+        assert!(matrix.is_special_taxon(561));
+        assert!(!matrix.is_special_taxon(562));
+
+        let filter = PrevalenceFilter { min_abundance: 25.0, min_samples: 2, min_total_reads: None };
+        matrix.filter_taxa(&filter, true);
+        assert_eq!(matrix.taxids(), vec![561, 562, OTHER_TAXID]);
+
+        // The rows are recognised by the unassigned column, not by their name, when the matrix is read back
+        let path = std::env::temp_dir().join(format!("krakenclip_unassigned_{}.tsv", std::process::id()));
+        matrix.set_include_lineage(true);
+        matrix.write_matrix(path.to_str().unwrap()).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap()
+            .replace("Escherichia (unassigned species)", "Escherichia");
+        std::fs::write(&path, &content).unwrap();
+        let read = AbundanceMatrix::read_matrix(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(content.starts_with("taxid\tname\tlineage\tunassigned\ts1\ts2\n"));
+        assert!(read.is_special_taxon(561));
+        assert!(!read.is_special_taxon(562));
+        assert_eq!(read.level(), matrix.level());
+
+        assert_eq!(matrix.keep_top_taxa(0, TaxonRanking::Mean, true), 1);
+        assert_eq!(matrix.taxids(), vec![561, OTHER_TAXID]);
+        assert_eq!(matrix.abundance(561, "s1"), 10.0);
+        assert!(matrix.taxon_values("s1").is_empty());
+    }
+
+    #[test]
+    fn test_write_long() {
+        let mut matrix = AbundanceMatrix::new("G".parse().unwrap());
+        matrix.set_force_include_unclassified(true);
+        matrix.add_sample(&report(), "sample", 0.0, false);
+
+        let path = std::env::temp_dir().join(format!("krakenclip_long_{}.tsv", std::process::id()));
+        matrix.write_long(path.to_str().unwrap()).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(content, "sample\ttaxid\tname\trank\treads\trelative_abundance\tlineage\n\
+            sample\t0\tUnclassified\tU\t20\t20.000000\t\n\
+            sample\t561\tEscherichia\tG\t40\t40.000000\td__Bacteria;g__Escherichia\n");
+    }
+
+    #[test]
+    fn test_write_taxonomy_table() {
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.set_force_include_unclassified(true);
+        matrix.add_sample(&report(), "sample", 0.0, false);
+
+        let path = std::env::temp_dir().join(format!("krakenclip_taxonomy_{}.tsv", std::process::id()));
+        let write = |format| {
+            matrix.write_taxonomy_table(path.to_str().unwrap(), format).unwrap();
+            std::fs::read_to_string(&path).unwrap()
+        };
+        let table = write(TaxonomyFormat::Table);
+        let qiime = write(TaxonomyFormat::Qiime2);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(table, "taxid\tDomain\tKingdom\tPhylum\tClass\tOrder\tFamily\tGenus\tSpecies\n\
+            0\t\t\t\t\t\t\t\t\n\
+            562\tBacteria\t\t\t\t\t\tEscherichia\tEscherichia coli\n\
+            77133\tBacteria\t\t\t\t\t\t\tuncultured bacterium\n\
+            115547\tArchaea\t\t\t\t\t\t\tuncultured bacterium\n");
+        assert_eq!(qiime, "Feature ID\tTaxon\n\
+            0\tUnassigned\n\
+            562\td__Bacteria; p__; c__; o__; f__; g__Escherichia; s__Escherichia coli\n\
+            77133\td__Bacteria; p__; c__; o__; f__; g__; s__uncultured bacterium\n\
+            115547\td__Archaea; p__; c__; o__; f__; g__; s__uncultured bacterium\n");
+        assert_eq!("QIIME2".parse::<TaxonomyFormat>().unwrap(), TaxonomyFormat::Qiime2);
+        assert!("mothur".parse::<TaxonomyFormat>().is_err());
+    }
+
+    #[test]
+    fn test_sample_order() {
+        let mut matrix = AbundanceMatrix::new("G".parse().unwrap());
+        for sample in ["a", "b", "c"] {
+            matrix.add_sample(&report(), sample, 0.0, false);
+        }
+        assert_eq!(matrix.sample_names(), vec!["a", "b", "c"]);
+
+        matrix.set_sample_order(vec!["c".to_string(), "a".to_string()]);
+        assert_eq!(matrix.sample_names(), vec!["c", "a", "b"]);
+    }
+}
@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{BufReader, BufWriter, Write};
 use std::error::Error;
 use serde_json::{json, Value};
-use chrono;
-use crate::krk_parser::{KrakenReport, TaxonEntry};
+use crate::abundance_matrix::{AbundanceMatrix, TaxonAnnotation};
+use crate::rank::Rank;
+use crate::sample_metadata::SampleMetadata;
 
 /// Optimized buffer size for write operations
 const BUFFER_SIZE: usize = 256 * 1024; // 256KB
@@ -44,16 +45,26 @@ impl From<serde_json::Error> for BiomError {
 /// Specialized result type for BIOM functions
 pub type BiomResult<T> = Result<T, BiomError>;
 
+/// Metadata of a BIOM row or column
+type Metadata = HashMap<String, Value>;
+
+/// BIOM 1.0 format specification URL
+const BIOM_FORMAT_URL: &str = "http://biom-format.org/documentation/format_versions/biom-1.0.html";
+
 /// Structure to efficiently store BIOM format data
+/// 
+/// Abundances are kept as sparse (row, column, value) triplets with rows
+/// in the order of `row_ids` and columns in the order of `column_ids`.
+/// Only non-zero values are stored.
 #[derive(Default)]
 pub struct BiomTable {
-    /// Matrix of abundances (rows are taxa, columns are samples)
-    pub data: Vec<Vec<f64>>,
-    /// Row metadata (taxonomic information)
-    pub rows: Vec<HashMap<String, String>>,
+    /// Non-zero abundances as (row, column, value) (rows are taxa, columns are samples)
+    pub data: Vec<(usize, usize, f64)>,
+    /// Row metadata (taxonomic information, including the `taxonomy` lineage array)
+    pub rows: Vec<HashMap<String, Value>>,
     /// Column metadata (sample information)
     pub columns: Vec<HashMap<String, String>>,
-    /// Row IDs (taxon names)
+    /// Row IDs (taxids)
     pub row_ids: Vec<String>,
     /// Column IDs (sample names)
     pub column_ids: Vec<String>,
@@ -76,75 +87,307 @@ pub struct BiomTable {
 }
 
 impl BiomTable {
-    /// Creates a new BIOM table from a Kraken report
+    /// Creates a new BIOM table from a merged abundance matrix
     /// 
     /// # Arguments
-    /// * `report` - Parsed Kraken2 report
-    /// * `sample_name` - Name of the sample
-    /// * `normalize` - If true, normalizes abundances to percentages
-    pub fn from_kraken_report(report: &KrakenReport, sample_name: &str, normalize: bool) -> Self {
-        let mut table = Self::default();
-        
-        // Set basic BIOM metadata
-        table.date = chrono::Local::now().to_rfc3339();
-        table.generated_by = "KrakenClip".to_string();
-        table.id = format!("krakenclip_{}", sample_name);
-        table.table_type = "OTU table".to_string();
-        table.format_url = "http://biom-format.org/documentation/format_versions/biom-1.0.html".to_string();
-        table.matrix_type = "dense".to_string();
-        table.element_type = "float".to_string();
-        
-        // Process the report
-        let mut row_data = Vec::new();
-        let mut row_metadata = Vec::new();
-        let mut row_ids = Vec::new();
-        
-        // Process root and its children
-        Self::process_node(&report.root, &mut row_data, &mut row_metadata, &mut row_ids, normalize);
-        
-        // Process unclassified if present
-        if let Some(ref unclassified) = report.unclassified {
-            Self::process_node(unclassified, &mut row_data, &mut row_metadata, &mut row_ids, normalize);
-        }
-        
-        // Set the data
-        table.data = vec![row_data];
-        table.rows = row_metadata;
-        table.row_ids = row_ids.clone();
-        table.column_ids = vec![sample_name.to_string()];
-        table.shape = (row_ids.len(), 1);
-        
-        table
+    /// * `matrix` - Abundance matrix with one column per sample
+    /// 
+    /// # Implementation Details
+    /// The table has the same rows, columns and values as the TSV output of the
+    /// matrix, so the taxonomic level and all filters are already applied. Rows
+    /// are identified by taxid and carry the name, rank and `taxonomy` lineage
+    /// of the taxon, and `unassigned: true` for the rows of reads classified
+    /// above the level. Tables with whole-number values (absolute counts) are
+    /// written with the "int" element type.
+    pub fn from_abundance_matrix(matrix: &AbundanceMatrix) -> Self {
+        let samples = matrix.sample_names();
+        let sparse_rows = matrix.sparse_rows();
+        
+        let mut data = Vec::new();
+        let mut rows = Vec::with_capacity(sparse_rows.len());
+        let mut row_ids = Vec::with_capacity(sparse_rows.len());
+        
+        for (row, (taxid, entries)) in sparse_rows.into_iter().enumerate() {
+            data.extend(entries.into_iter().map(|(col, value)| (row, col, value)));
+            
+            // Add metadata
+            let mut metadata = HashMap::new();
+            if let Some(taxon) = matrix.taxon(taxid) {
+                metadata.insert("name".to_string(), json!(taxon.name));
+                metadata.insert("rank".to_string(), json!(taxon.rank.to_string()));
+                metadata.insert("taxonomy".to_string(), json!(taxon.lineage));
+                if taxon.unassigned {
+                    metadata.insert("unassigned".to_string(), json!(true));
+                }
+            }
+            rows.push(metadata);
+            row_ids.push(taxid.to_string());
+        }
+        
+        let is_integer = data.iter().all(|(_, _, value)| value.fract() == 0.0);
+        
+        Self {
+            shape: (row_ids.len(), samples.len()),
+            data,
+            rows,
+            columns: vec![HashMap::new(); samples.len()],
+            row_ids,
+            column_ids: samples,
+            matrix_type: "sparse".to_string(),
+            element_type: if is_integer { "int" } else { "float" }.to_string(),
+            date: chrono::Local::now().to_rfc3339(),
+            generated_by: "KrakenClip".to_string(),
+            id: format!("krakenclip_{}", matrix.level()),
+            table_type: "OTU table".to_string(),
+            format_url: BIOM_FORMAT_URL.to_string(),
+        }
     }
     
-    /// Processes a taxonomic node and its children recursively
-    fn process_node(
-        node: &TaxonEntry,
-        row_data: &mut Vec<f64>,
-        row_metadata: &mut Vec<HashMap<String, String>>,
-        row_ids: &mut Vec<String>,
-        normalize: bool
-    ) {
-        // Add current node
-        let abundance = if normalize {
-            node.percentage as f64
-        } else {
-            node.clade_reads as f64
+    /// Attaches sample metadata to the columns of the table
+    /// 
+    /// # Arguments
+    /// * `metadata` - Sample metadata table; columns without a record keep empty metadata
+    /// 
+    /// # Implementation Details
+    /// Every metadata field is written for each listed sample, with empty
+    /// strings for missing values, so all columns share the same keys as
+    /// expected by QIIME and phyloseq.
+    pub fn set_sample_metadata(&mut self, metadata: &SampleMetadata) {
+        for (sample, columns) in self.column_ids.iter().zip(self.columns.iter_mut()) {
+            if let Some(record) = metadata.get(sample) {
+                for field in &metadata.fields {
+                    let value = record.values.get(field).cloned().unwrap_or_default();
+                    columns.insert(field.clone(), value);
+                }
+            }
+        }
+    }
+    
+    /// Reads a BIOM 1.0 table in JSON format
+    /// 
+    /// # Arguments
+    /// * `input_file` - Path to the BIOM file
+    /// 
+    /// # Returns
+    /// * `BiomResult<BiomTable>` - Table with both dense and sparse data stored as triplets
+    pub fn read_json(input_file: &str) -> BiomResult<Self> {
+        let file = File::open(input_file)?;
+        let reader = BufReader::with_capacity(BUFFER_SIZE, file);
+        let json: Value = serde_json::from_reader(reader)?;
+        Self::from_json(&json)
+    }
+    
+    /// Builds a table from a parsed BIOM 1.0 JSON document
+    pub fn from_json(json: &Value) -> BiomResult<Self> {
+        let field = |name: &str| json.get(name)
+            .ok_or_else(|| BiomError::InvalidData(format!("missing field '{}'", name)));
+        let text = |name: &str| json.get(name).and_then(|v| v.as_str()).unwrap_or("").to_string();
+        
+        // Matrix shape
+        let shape = field("shape")?.as_array()
+            .filter(|shape| shape.len() == 2)
+            .and_then(|shape| Some((shape[0].as_u64()? as usize, shape[1].as_u64()? as usize)))
+            .ok_or_else(|| BiomError::InvalidData("'shape' must be [rows, columns]".to_string()))?;
+        
+        // Row and column identifiers and metadata
+        let (row_ids, rows) = Self::parse_entries(field("rows")?, "rows")?;
+        let (column_ids, column_metadata) = Self::parse_entries(field("columns")?, "columns")?;
+        if row_ids.len() != shape.0 || column_ids.len() != shape.1 {
+            return Err(BiomError::InvalidData(format!(
+                "shape {:?} does not match {} rows and {} columns", shape, row_ids.len(), column_ids.len()
+            )));
+        }
+        let columns = column_metadata.into_iter().map(|metadata| {
+            metadata.into_iter().map(|(key, value)| {
+                let value = match value {
+                    Value::String(s) => s,
+                    other => other.to_string(),
+                };
+                (key, value)
+            }).collect()
+        }).collect();
+        
+        // Matrix data, always stored as sparse triplets
+        let matrix_type = text("matrix_type");
+        let data = match matrix_type.as_str() {
+            "sparse" => Self::parse_sparse_data(field("data")?, shape)?,
+            "dense" => Self::parse_dense_data(field("data")?, shape)?,
+            other => return Err(BiomError::InvalidData(format!("unsupported matrix type '{}'", other))),
         };
         
-        row_data.push(abundance);
-        row_ids.push(node.name.clone());
+        let element_type = match text("matrix_element_type") {
+            t if t.is_empty() => "float".to_string(),
+            t => t,
+        };
+        
+        Ok(Self {
+            data,
+            rows,
+            columns,
+            row_ids,
+            column_ids,
+            matrix_type,
+            element_type,
+            shape,
+            date: text("date"),
+            generated_by: text("generated_by"),
+            id: text("id"),
+            table_type: text("type"),
+            format_url: text("format_url"),
+        })
+    }
+    
+    /// Parses the "rows" or "columns" array into identifiers and metadata
+    fn parse_entries(entries: &Value, name: &str) -> BiomResult<(Vec<String>, Vec<Metadata>)> {
+        let entries = entries.as_array()
+            .ok_or_else(|| BiomError::InvalidData(format!("'{}' must be an array", name)))?;
+        let mut ids = Vec::with_capacity(entries.len());
+        let mut metadata = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let id = match entry.get("id") {
+                Some(Value::String(s)) => s.clone(),
+                Some(Value::Number(n)) => n.to_string(),
+                _ => return Err(BiomError::InvalidData(format!("entry without an id in '{}'", name))),
+            };
+            let entry_metadata = match entry.get("metadata") {
+                Some(Value::Object(map)) => map.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+                _ => HashMap::new(),
+            };
+            ids.push(id);
+            metadata.push(entry_metadata);
+        }
+        Ok((ids, metadata))
+    }
+    
+    /// Parses sparse [row, column, value] data
+    fn parse_sparse_data(data: &Value, shape: (usize, usize)) -> BiomResult<Vec<(usize, usize, f64)>> {
+        let entries = data.as_array()
+            .ok_or_else(|| BiomError::InvalidData("'data' must be an array".to_string()))?;
+        let mut triplets = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let triplet = entry.as_array()
+                .filter(|t| t.len() == 3)
+                .and_then(|t| Some((t[0].as_u64()? as usize, t[1].as_u64()? as usize, t[2].as_f64()?)))
+                .filter(|(row, col, _)| *row < shape.0 && *col < shape.1)
+                .ok_or_else(|| BiomError::InvalidData(format!("invalid sparse entry {}", entry)))?;
+            if triplet.2 != 0.0 {
+                triplets.push(triplet);
+            }
+        }
+        // Keep the row-major order used by the writer
+        triplets.sort_by_key(|&(row, col, _)| (row, col));
+        Ok(triplets)
+    }
+    
+    /// Parses dense data (one array of values per row)
+    fn parse_dense_data(data: &Value, shape: (usize, usize)) -> BiomResult<Vec<(usize, usize, f64)>> {
+        let rows = data.as_array()
+            .filter(|rows| rows.len() == shape.0)
+            .ok_or_else(|| BiomError::InvalidData(format!("dense 'data' must have {} rows", shape.0)))?;
+        let mut triplets = Vec::new();
+        for (row, values) in rows.iter().enumerate() {
+            let values = values.as_array()
+                .filter(|values| values.len() == shape.1)
+                .ok_or_else(|| BiomError::InvalidData(format!("dense row {} must have {} values", row, shape.1)))?;
+            for (col, value) in values.iter().enumerate() {
+                let value = value.as_f64()
+                    .ok_or_else(|| BiomError::InvalidData(format!("invalid value {} at row {}", value, row)))?;
+                if value != 0.0 {
+                    triplets.push((row, col, value));
+                }
+            }
+        }
+        Ok(triplets)
+    }
+    
+    /// Converts the table into an abundance matrix with one column per sample
+    /// 
+    /// # Returns
+    /// * `BiomResult<AbundanceMatrix>` - Matrix keyed by the row ids, which must be taxids
+    /// 
+    /// # Implementation Details
+    /// Names and ranks come from the "name" and "rank" row metadata when present,
+    /// otherwise from the deepest element of the "taxonomy" metadata. Rows with
+    /// `unassigned: true` metadata are unassigned rows. Column sums
+    /// are used as sample totals.
+    pub fn to_abundance_matrix(&self) -> BiomResult<AbundanceMatrix> {
+        let mut taxa = Vec::with_capacity(self.row_ids.len());
+        let mut seen = std::collections::HashSet::with_capacity(self.row_ids.len());
+        for (id, metadata) in self.row_ids.iter().zip(&self.rows) {
+            let taxid = id.parse::<u32>()
+                .map_err(|_| BiomError::InvalidData(format!("row id '{}' is not a taxid", id)))?;
+            if !seen.insert(taxid) {
+                return Err(BiomError::InvalidData(format!("duplicate row id '{}'", id)));
+            }
+            let name = metadata.get("name").and_then(|v| v.as_str()).map(|s| s.to_string());
+            let rank = metadata.get("rank").and_then(|v| v.as_str()).map(Rank::from_code);
+            let lineage = match metadata.get("taxonomy") {
+                Some(Value::Array(items)) => items.iter()
+                    .filter_map(|v| v.as_str())
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                Some(Value::String(s)) => s.split(';')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+                _ => Vec::new(),
+            };
+            let mut taxon = TaxonAnnotation::from_lineage(taxid, name, rank, lineage);
+            taxon.unassigned |= metadata.get("unassigned").and_then(|v| v.as_bool()).unwrap_or(false);
+            taxa.push((taxid, taxon));
+        }
         
-        // Add metadata
-        let mut metadata = HashMap::new();
-        metadata.insert("taxid".to_string(), node.taxid.to_string());
-        metadata.insert("rank".to_string(), node.rank.clone());
-        metadata.insert("level".to_string(), node.level.to_string());
-        row_metadata.push(metadata);
+        let mut matrix = AbundanceMatrix::new(AbundanceMatrix::infer_level(taxa.iter().map(|(_, taxon)| taxon)));
+        matrix.set_include_lineage(taxa.iter().any(|(_, taxon)| !taxon.lineage.is_empty()));
         
-        // Process children
-        for child in &node.children {
-            Self::process_node(child, row_data, row_metadata, row_ids, normalize);
+        let mut totals = vec![0.0; self.column_ids.len()];
+        for &(_, col, value) in &self.data {
+            totals[col] += value;
+        }
+        for (sample, total) in self.column_ids.iter().zip(totals) {
+            matrix.set_sample_total(sample, total);
+        }
+        
+        // Rows without any non-zero value are kept with explicit zeros
+        let mut has_data = vec![false; taxa.len()];
+        for &(row, col, value) in &self.data {
+            let (taxid, ref taxon) = taxa[row];
+            matrix.set_abundance(taxid, taxon.clone(), &self.column_ids[col], value);
+            has_data[row] = true;
+        }
+        if let Some(first_sample) = self.column_ids.first() {
+            for ((taxid, taxon), has_data) in taxa.into_iter().zip(has_data) {
+                if !has_data {
+                    matrix.set_abundance(taxid, taxon, first_sample, 0.0);
+                }
+            }
+        }
+        
+        Ok(matrix)
+    }
+    
+    /// Formats a matrix value according to the element type
+    fn value_to_json(&self, value: f64) -> Value {
+        if self.element_type == "int" {
+            json!(value as i64)
+        } else {
+            json!(value)
+        }
+    }
+    
+    /// Builds the "data" field for the matrix type of the table
+    fn data_to_json(&self) -> Value {
+        if self.matrix_type == "dense" {
+            let mut dense = vec![vec![self.value_to_json(0.0); self.shape.1]; self.shape.0];
+            for &(row, col, value) in &self.data {
+                dense[row][col] = self.value_to_json(value);
+            }
+            json!(dense)
+        } else {
+            Value::Array(self.data.iter().map(|&(row, col, value)| {
+                json!([row, col, self.value_to_json(value)])
+            }).collect())
         }
     }
     
@@ -170,17 +413,17 @@ impl BiomTable {
             "matrix_type": self.matrix_type,
             "matrix_element_type": self.element_type,
             "shape": self.shape,
-            "data": self.data,
-            "rows": self.rows.iter().map(|metadata| {
+            "data": self.data_to_json(),
+            "rows": self.row_ids.iter().zip(&self.rows).map(|(id, metadata)| {
                 json!({
-                    "id": metadata.get("taxid").unwrap(),
+                    "id": id,
                     "metadata": metadata
                 })
             }).collect::<Vec<Value>>(),
-            "columns": self.column_ids.iter().map(|id| {
+            "columns": self.column_ids.iter().zip(&self.columns).map(|(id, metadata)| {
                 json!({
                     "id": id,
-                    "metadata": {}
+                    "metadata": metadata
                 })
             }).collect::<Vec<Value>>()
         });
@@ -191,4 +434,157 @@ impl BiomTable {
         
         Ok(())
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::krk_parser::{KrakenReport, TaxonEntry};
+
+    fn report(coli: u64, shigella: u64) -> KrakenReport {
+        let mut genus = TaxonEntry::new(0.0, coli, 0, "G".to_string(), 561, "Escherichia".to_string(), 1);
+        genus.children.push(TaxonEntry::new(0.0, coli, coli, "S".to_string(), 562, "Escherichia coli".to_string(), 2));
+        let mut root = TaxonEntry::new(0.0, coli + shigella, 0, "R".to_string(), 1, "root".to_string(), 0);
+        root.children.push(genus);
+        if shigella > 0 {
+            root.children.push(TaxonEntry::new(0.0, shigella, shigella, "S".to_string(), 623, "Shigella flexneri".to_string(), 1));
+        }
+        KrakenReport::new(root, None)
+    }
+
+    #[test]
+    fn test_from_abundance_matrix() {
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.add_sample(&report(30, 10), "b", 0.0, false);
+        matrix.add_sample(&report(50, 0), "a", 0.0, false);
+
+        let table = BiomTable::from_abundance_matrix(&matrix);
+        assert_eq!(table.shape, (2, 2));
+        assert_eq!(table.column_ids, vec!["a", "b"]);
+        assert_eq!(table.row_ids, vec!["562", "623"]);
+        assert_eq!(table.data, vec![(0, 0, 50.0), (0, 1, 30.0), (1, 1, 10.0)]);
+        assert_eq!(table.element_type, "int");
+        assert_eq!(table.rows[0]["taxonomy"], json!(["g__Escherichia", "s__Escherichia coli"]));
+        assert_eq!(table.data_to_json(), json!([[0, 0, 50], [0, 1, 30], [1, 1, 10]]));
+    }
+
+    #[test]
+    fn test_unassigned_rows_metadata() {
+        // 5 reads assigned directly to the root are kept in a per-parent row
+        let mut report = report(30, 10);
+        report.root.direct_reads = 5;
+        report.root.clade_reads += 5;
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.set_unassigned_reads(crate::abundance_matrix::UnassignedReads::PerParent);
+        matrix.add_sample(&report, "a", 0.0, false);
+
+        let mut table = BiomTable::from_abundance_matrix(&matrix);
+        let row = table.row_ids.iter().position(|id| id == "1").unwrap();
+        assert_eq!(table.rows[row]["unassigned"], json!(true));
+        assert_eq!(table.rows.iter().filter(|metadata| metadata.contains_key("unassigned")).count(), 1);
+
+        // The flag, not the name, marks the row
+        table.rows[row].insert("name".to_string(), json!("Root reads"));
+        let read = table.to_abundance_matrix().unwrap();
+        assert!(read.is_special_taxon(1));
+        assert!(!read.is_special_taxon(562));
+        assert_eq!(read.taxon_values("a").len(), 2);
+    }
+
+    #[test]
+    fn test_sample_metadata_columns() {
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.add_sample(&report(30, 10), "b", 0.0, false);
+        matrix.add_sample(&report(50, 0), "a", 0.0, false);
+
+        let metadata = SampleMetadata::from_reader("sample_id\tgroup\tday\nb\ttreated\t2\n".as_bytes()).unwrap();
+        let mut table = BiomTable::from_abundance_matrix(&matrix);
+        table.set_sample_metadata(&metadata);
+        assert!(table.columns[0].is_empty());
+        assert_eq!(table.columns[1]["group"], "treated");
+        assert_eq!(table.columns[1]["day"], "2");
+    }
+
+    fn biom_json(matrix_type: &str, data: Value) -> Value {
+        json!({
+            "id": "collaborator",
+            "format": "Biological Observation Matrix 1.0.0",
+            "type": "OTU table",
+            "matrix_type": matrix_type,
+            "matrix_element_type": "int",
+            "shape": [3, 2],
+            "data": data,
+            "rows": [
+                {"id": "562", "metadata": {"taxonomy": ["k__Bacteria", "g__Escherichia", "s__Escherichia coli"]}},
+                {"id": "623", "metadata": {"taxonomy": "k__Bacteria; g__Shigella; s__Shigella flexneri"}},
+                {"id": "1280", "metadata": null}
+            ],
+            "columns": [
+                {"id": "A", "metadata": {"site": "gut", "depth": 3}},
+                {"id": "B", "metadata": null}
+            ]
+        })
+    }
+
+    #[test]
+    fn test_read_dense_and_sparse() {
+        let dense = BiomTable::from_json(&biom_json("dense", json!([[5, 0], [0, 7], [0, 0]]))).unwrap();
+        let sparse = BiomTable::from_json(&biom_json("sparse", json!([[1, 1, 7], [0, 0, 5]]))).unwrap();
+        assert_eq!(dense.data, vec![(0, 0, 5.0), (1, 1, 7.0)]);
+        assert_eq!(sparse.data, dense.data);
+        assert_eq!(dense.row_ids, vec!["562", "623", "1280"]);
+        assert_eq!(dense.columns[0]["site"], "gut");
+        assert_eq!(dense.columns[0]["depth"], "3");
+
+        let matrix = dense.to_abundance_matrix().unwrap();
+        assert_eq!(matrix.sample_names(), vec!["A", "B"]);
+        assert_eq!(matrix.abundance(562, "A"), 5.0);
+        assert_eq!(matrix.abundance(623, "B"), 7.0);
+        assert_eq!(matrix.taxon(623).unwrap().name, "Shigella flexneri");
+        assert_eq!(matrix.taxon(562).unwrap().rank, Rank::Species(0));
+        // Rows without data are kept
+        assert_eq!(matrix.taxon(1280).unwrap().name, "1280");
+        assert_eq!(matrix.taxids().len(), 3);
+    }
+
+    #[test]
+    fn test_invalid_tables() {
+        let out_of_range = biom_json("sparse", json!([[3, 0, 1]]));
+        assert!(matches!(BiomTable::from_json(&out_of_range), Err(BiomError::InvalidData(_))));
+        let bad_shape = biom_json("dense", json!([[1, 2]]));
+        assert!(matches!(BiomTable::from_json(&bad_shape), Err(BiomError::InvalidData(_))));
+
+        let mut named_rows = biom_json("sparse", json!([]));
+        named_rows["rows"][0]["id"] = json!("OTU_1");
+        let table = BiomTable::from_json(&named_rows).unwrap();
+        assert!(matches!(table.to_abundance_matrix(), Err(BiomError::InvalidData(_))));
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        matrix.add_sample(&report(30, 10), "b", 0.0, false);
+        matrix.add_sample(&report(50, 0), "a", 0.0, false);
+
+        for matrix_type in ["sparse", "dense"] {
+            let mut table = BiomTable::from_abundance_matrix(&matrix);
+            table.matrix_type = matrix_type.to_string();
+            let path = std::env::temp_dir().join(format!("krakenclip_{}_{}.biom", matrix_type, std::process::id()));
+            table.write_json(path.to_str().unwrap()).unwrap();
+            let read = BiomTable::read_json(path.to_str().unwrap()).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(read.data, table.data);
+            assert_eq!(read.row_ids, table.row_ids);
+            assert_eq!(read.column_ids, table.column_ids);
+            assert_eq!(read.rows, table.rows);
+            assert_eq!(read.element_type, "int");
+
+            // BIOM -> matrix -> BIOM keeps rows, columns and values
+            let again = BiomTable::from_abundance_matrix(&read.to_abundance_matrix().unwrap());
+            assert_eq!(again.data, table.data);
+            assert_eq!(again.row_ids, table.row_ids);
+            assert_eq!(again.rows, table.rows);
+        }
+    }
+}
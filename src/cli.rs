@@ -1,5 +1,5 @@
 use clap::{Parser, Subcommand, Args};
-use crate::krk_parser;
+use crate::krk_parser::{self, KrakenReport};
 use std::time::Instant;
 use memory_stats::memory_stats;
 use std::fs::File;
@@ -10,13 +10,26 @@ use crate::logkrk_parser;
 use crate::sequence_processor;
 use crate::generate_test_data;
 use std::path::Path;
-use chrono;
-use crate::abundance_matrix::{AbundanceMatrix, validate_taxonomic_level};
+use crate::abundance_matrix::{AbundanceMatrix, PrevalenceFilter, TaxonRanking, TaxonomyFormat, UnassignedReads};
+use crate::rank::{self, Rank, RankSelector};
 use crate::biom::BiomTable;
+use crate::sample_metadata::SampleMetadata;
+use crate::normalization::{Normalization, DEFAULT_PSEUDOCOUNT};
+use crate::gtdb::{self, TranslationDirection, TranslationTable};
+use crate::diversity::{self, AlphaDiversity, BetaMetric, DistanceMatrix, TaxonomyTree};
+use crate::ordination;
+use crate::differential;
+use crate::combine_reports;
+use crate::report_transform::{self, PruneThresholds};
+use crate::report_diff::{self, DiffFilter, Presence};
 use std::error::Error;
+use rayon::prelude::*;
 
 const BUFFER_SIZE: usize = 512 * 1024; // 512KB buffer for I/O
 
+/// Reports parsed per thread in each batch of the 'abundance-matrix' command
+const REPORTS_PER_THREAD: usize = 4;
+
 /// KrakenClip - High-performance Kraken2 data processing toolkit
 #[derive(Parser)]
 #[command(version = "0.2.0", author = "Author", about = "A high-performance toolkit for processing Kraken2 reports, logs, and sequence files")]
@@ -36,7 +49,36 @@ enum Commands {
     
     /// Generates taxonomic abundance matrices from multiple reports
     #[command(name = "abundance-matrix")]
-    AbundanceMatrix(AbundanceMatrixArgs),
+    AbundanceMatrix(Box<AbundanceMatrixArgs>),
+    
+    /// Re-expresses a Kraken2 report in NCBI or GTDB taxonomy
+    Translate(TranslateArgs),
+    
+    /// Converts between BIOM tables and abundance matrix TSV files
+    Convert(ConvertArgs),
+    
+    /// Computes diversity indices of samples
+    Diversity(DiversityArgs),
+    
+    /// Computes PCoA coordinates and a UPGMA tree of the samples from a distance matrix
+    Ordinate(OrdinateArgs),
+    
+    /// Tests every taxon of an abundance matrix for differences between sample groups
+    #[command(name = "compare-groups")]
+    CompareGroups(CompareGroupsArgs),
+    
+    /// Compares two Kraken2 reports taxon by taxon
+    Diff(DiffArgs),
+    
+    /// Sums several Kraken2 reports (lanes, technical replicates) into one report
+    #[command(name = "combine-reports")]
+    CombineReports(CombineReportsArgs),
+    
+    /// Removes low-abundance taxa from a Kraken2 report
+    Prune(PruneArgs),
+    
+    /// Folds the taxa of a Kraken2 report below a rank into their ancestor at that rank
+    Collapse(CollapseArgs),
     
     /// Generates test data for performance testing
     #[command(name = "generate-test-data")]
@@ -112,21 +154,33 @@ struct ExtractArgs {
 #[derive(Args)]
 struct AbundanceMatrixArgs {
     /// Input Kraken2 report files (can be multiple)
-    #[arg(required = true)]
+    #[arg(required_unless_present_any = ["manifest", "input_glob"], conflicts_with = "manifest")]
     input: Vec<String>,
     
+    /// Manifest TSV listing the reports (sample_id, report_path and optional metadata columns such as group)
+    #[arg(long, conflicts_with_all = ["input_glob", "sample_metadata"])]
+    manifest: Option<String>,
+    
+    /// Glob pattern of input reports (e.g. 'runs/**/*.kreport'), quoted so the shell does not expand it
+    #[arg(long = "input-glob")]
+    input_glob: Vec<String>,
+    
     /// Output file for the abundance matrix
     #[arg(short, long)]
     output: String,
     
-    /// Output format (tsv or biom)
+    /// Output format (tsv, biom or long, with one line per sample and taxon holding reads and relative abundance)
     #[arg(long, default_value = "tsv")]
     format: String,
     
-    /// Taxonomic level for aggregating abundances
+    /// Taxonomic level for aggregating abundances (e.g. S, S1, or G+ for genus and below)
     #[arg(long, default_value = "S")]
     level: String,
     
+    /// Comma-separated levels (or "all") to write one matrix per level from a single pass over the reports
+    #[arg(long, conflicts_with = "level")]
+    levels: Option<String>,
+    
     /// Minimum abundance threshold (0.0-100.0)
     #[arg(long = "min-abundance", default_value = "0.0")]
     min_abundance: f64,
@@ -139,6 +193,22 @@ struct AbundanceMatrixArgs {
     #[arg(long = "include-unclassified")]
     include_unclassified: bool,
     
+    /// Reads classified above the level: none, parent ("Escherichia (unassigned species)" rows) or single ("Unassigned at S")
+    #[arg(long, default_value = "none")]
+    unassigned: String,
+    
+    /// Add a lineage column (d__;p__;...;s__) to the TSV matrix
+    #[arg(long)]
+    lineage: bool,
+    
+    /// Also write the taxonomy of the matrix rows (Domain..Species) to this file
+    #[arg(long = "taxonomy-table")]
+    taxonomy_table: Option<String>,
+    
+    /// Layout of the taxonomy table: table (one column per rank, for phyloseq) or qiime2 (Feature ID and Taxon)
+    #[arg(long = "taxonomy-format", default_value = "table", requires = "taxonomy_table")]
+    taxonomy_format: String,
+    
     /// Transform counts to proportions
     #[arg(long)]
     proportions: bool,
@@ -146,6 +216,340 @@ struct AbundanceMatrixArgs {
     /// Use absolute read counts without converting to proportions
     #[arg(long = "absolute-counts")]
     absolute_counts: bool,
+    
+    /// Keep only taxa reaching --prevalence-abundance in at least this many samples
+    #[arg(long = "min-prevalence", conflicts_with = "normalize")]
+    min_prevalence: Option<usize>,
+    
+    /// Abundance (% of the sample reads) for a sample to count towards --min-prevalence
+    #[arg(long = "prevalence-abundance", default_value = "0.0", requires = "min_prevalence")]
+    prevalence_abundance: f64,
+    
+    /// Keep only taxa with at least this many reads over all samples (or passing --min-prevalence)
+    #[arg(long = "min-total-reads", conflicts_with = "normalize")]
+    min_total_reads: Option<f64>,
+    
+    /// Add the reads of the taxa removed by the cohort filters to an "Other" row
+    #[arg(long)]
+    other: bool,
+    
+    /// Keep only the N most abundant taxa after normalization, summing the rest into an "Other" row
+    #[arg(long)]
+    top: Option<usize>,
+    
+    /// Statistic used to pick the top taxa (mean, median, max or prevalence)
+    #[arg(long = "rank-by", default_value = "mean", requires = "top")]
+    rank_by: String,
+    
+    /// Normalization of the read counts (tss, clr, rarefy, css or log10) instead of percentages
+    #[arg(long, conflicts_with_all = ["normalize", "proportions", "absolute_counts"])]
+    normalization: Option<String>,
+    
+    /// Pseudocount added to every value before the CLR log-ratio
+    #[arg(long, default_value_t = DEFAULT_PSEUDOCOUNT)]
+    pseudocount: f64,
+    
+    /// Number of reads kept per sample by rarefaction (the smallest sample by default)
+    #[arg(long = "rarefaction-depth")]
+    rarefaction_depth: Option<u64>,
+    
+    /// Seed of the random generator used by rarefaction
+    #[arg(long, default_value = "0")]
+    seed: u64,
+    
+    /// NCBI/GTDB metadata TSV used to translate reports into a common taxonomy
+    #[arg(long = "translation-table", requires = "translate_to")]
+    translation_table: Option<String>,
+    
+    /// Target taxonomy for translated reports (gtdb or ncbi)
+    #[arg(long = "translate-to", requires = "translation_table")]
+    translate_to: Option<String>,
+    
+    /// Number of threads used to parse the reports (0 uses all available cores)
+    #[arg(long, default_value = "0")]
+    threads: usize,
+    
+    /// Sample metadata TSV (sample_id column, optional file column) used to name samples and annotate BIOM columns
+    #[arg(long = "sample-metadata")]
+    sample_metadata: Option<String>,
+    
+    /// Metadata field used to order the sample columns (from --sample-metadata or --manifest)
+    #[arg(long = "sort-by")]
+    sort_by: Option<String>,
+}
+
+/// Arguments for the 'translate' command
+#[derive(Args)]
+struct TranslateArgs {
+    /// Kraken2 report file
+    report: String,
+    
+    /// NCBI/GTDB metadata TSV (ncbi_taxid, gtdb_taxid, gtdb_taxonomy, ncbi_taxonomy columns)
+    #[arg(long)]
+    table: String,
+    
+    /// Target taxonomy (gtdb or ncbi)
+    #[arg(long, default_value = "gtdb")]
+    to: String,
+    
+    /// Output file for the translated report
+    #[arg(short, long)]
+    output: String,
+    
+    /// Output format (kreport or json)
+    #[arg(long, default_value = "kreport")]
+    format: String,
+}
+
+/// Arguments for the 'convert' command
+#[derive(Args)]
+struct ConvertArgs {
+    /// Input BIOM 1.0 (JSON) table or abundance matrix (TSV)
+    input: String,
+    
+    /// Output file
+    #[arg(short, long)]
+    output: String,
+    
+    /// Output format (tsv or biom), by default the opposite of the input format
+    #[arg(long)]
+    to: Option<String>,
+    
+    /// Matrix type for BIOM output (sparse or dense)
+    #[arg(long = "matrix-type", default_value = "sparse")]
+    matrix_type: String,
+}
+
+/// Arguments for the 'diversity' command
+#[derive(Args)]
+struct DiversityArgs {
+    #[command(subcommand)]
+    command: DiversityCommands,
+}
+
+/// Available diversity analyses
+#[derive(Subcommand)]
+enum DiversityCommands {
+    /// Per-sample richness, Shannon, Simpson, inverse Simpson, Pielou evenness, Chao1 and ACE
+    Alpha(AlphaDiversityArgs),
+    
+    /// Distance matrix between samples (Bray-Curtis, Jaccard, Aitchison or UniFrac on the taxonomy tree)
+    Beta(BetaDiversityArgs),
+}
+
+/// Arguments for the 'diversity alpha' command
+#[derive(Args)]
+struct AlphaDiversityArgs {
+    /// Input Kraken2 report files (can be multiple)
+    #[arg(required_unless_present = "matrix", conflicts_with = "matrix")]
+    input: Vec<String>,
+    
+    /// Abundance matrix (TSV or BIOM) with absolute read counts, instead of reports
+    #[arg(long, conflicts_with = "level")]
+    matrix: Option<String>,
+    
+    /// Output file for the diversity table (TSV, one line per sample)
+    #[arg(short, long)]
+    output: String,
+    
+    /// Taxonomic level at which taxa are counted (e.g. S, G or S+)
+    #[arg(long, default_value = "S")]
+    level: String,
+    
+    /// Number of threads used to parse the reports (0 uses all available cores)
+    #[arg(long, default_value = "0")]
+    threads: usize,
+}
+
+/// Arguments for the 'diversity beta' command
+#[derive(Args)]
+struct BetaDiversityArgs {
+    /// Input Kraken2 report files (can be multiple)
+    #[arg(required_unless_present = "matrix", conflicts_with = "matrix")]
+    input: Vec<String>,
+    
+    /// Abundance matrix (TSV or BIOM) instead of reports (not for UniFrac, which needs the report trees)
+    #[arg(long, conflicts_with = "level")]
+    matrix: Option<String>,
+    
+    /// Output file for the square distance matrix (TSV)
+    #[arg(short, long)]
+    output: String,
+    
+    /// Distance metric (braycurtis, jaccard, aitchison, unifrac or weighted-unifrac)
+    #[arg(long, default_value = "braycurtis")]
+    metric: String,
+    
+    /// Taxonomic level at which taxa are counted (e.g. S, G or S+)
+    #[arg(long, default_value = "S")]
+    level: String,
+    
+    /// Pseudocount added to every value before the CLR transform of the Aitchison distance
+    #[arg(long, default_value_t = DEFAULT_PSEUDOCOUNT)]
+    pseudocount: f64,
+    
+    /// Number of threads used to parse the reports and compute the distances (0 uses all available cores)
+    #[arg(long, default_value = "0")]
+    threads: usize,
+}
+
+/// Arguments for the 'ordinate' command
+#[derive(Args)]
+struct OrdinateArgs {
+    /// Distance matrix TSV (as written by 'diversity beta')
+    #[arg(required_unless_present = "matrix", conflicts_with = "matrix")]
+    input: Option<String>,
+    
+    /// Abundance matrix (TSV or BIOM) to compute the distances from, instead of a distance matrix
+    #[arg(long)]
+    matrix: Option<String>,
+    
+    /// Distance metric used with --matrix (braycurtis, jaccard or aitchison)
+    #[arg(long, default_value = "braycurtis", requires = "matrix")]
+    metric: String,
+    
+    /// Pseudocount added to every value before the CLR transform of the Aitchison distance
+    #[arg(long, default_value_t = DEFAULT_PSEUDOCOUNT, requires = "matrix")]
+    pseudocount: f64,
+    
+    /// Output file for the PCoA coordinates (TSV, one line per sample)
+    #[arg(short, long)]
+    output: String,
+    
+    /// Output file for the variance explained per axis (by default the output name with a "_variance" suffix)
+    #[arg(long = "variance-output")]
+    variance_output: Option<String>,
+    
+    /// Maximum number of axes written (all axes with a positive eigenvalue by default)
+    #[arg(long)]
+    axes: Option<usize>,
+    
+    /// Output file for the UPGMA (average-linkage) tree of the samples in Newick format
+    #[arg(long)]
+    tree: Option<String>,
+}
+
+/// Arguments for the 'compare-groups' command
+#[derive(Args)]
+struct CompareGroupsArgs {
+    /// Abundance matrix (TSV or BIOM), preferably of read counts
+    input: String,
+    
+    /// Sample metadata TSV (sample_id column) holding the group of each sample
+    #[arg(long = "sample-metadata")]
+    sample_metadata: String,
+    
+    /// Metadata field defining the groups
+    #[arg(long)]
+    group: String,
+    
+    /// Group the fold changes are computed against (the first group by name by default)
+    #[arg(long)]
+    reference: Option<String>,
+    
+    /// Pseudocount added to every value before the CLR transform
+    #[arg(long, default_value_t = DEFAULT_PSEUDOCOUNT)]
+    pseudocount: f64,
+    
+    /// Minimum number of grouped samples where a taxon is present for it to be tested
+    #[arg(long = "min-samples", default_value = "1")]
+    min_samples: usize,
+    
+    /// Output file (TSV, one line per tested taxon)
+    #[arg(short, long)]
+    output: String,
+}
+
+/// Arguments for the 'diff' command
+#[derive(Args)]
+struct DiffArgs {
+    /// First Kraken2 report (A, e.g. before)
+    report_a: String,
+    
+    /// Second Kraken2 report (B, e.g. after)
+    report_b: String,
+    
+    /// Output file (TSV, one line per taxon)
+    #[arg(short, long)]
+    output: String,
+    
+    /// Taxonomic rank of the listed taxa (e.g. S, G, S1 or S+; all ranks by default)
+    #[arg(long)]
+    rank: Option<String>,
+    
+    /// Minimum clade reads in at least one of the reports
+    #[arg(long = "min-reads", default_value = "1")]
+    min_reads: u64,
+    
+    /// Reads added to both reports before computing the log2 fold change
+    #[arg(long, default_value = "1")]
+    pseudocount: f64,
+    
+    /// Do not print the tree view of the differences
+    #[arg(long = "no-tree")]
+    no_tree: bool,
+}
+
+/// Arguments for the 'combine-reports' command
+#[derive(Args)]
+struct CombineReportsArgs {
+    /// Kraken2 report files to combine
+    #[arg(required = true, num_args = 1..)]
+    input: Vec<String>,
+    
+    /// Output file for the combined report
+    #[arg(short, long)]
+    output: String,
+    
+    /// Add the clade and direct reads of each input as extra columns (KrakenTools combine_kreports layout)
+    #[arg(long = "per-input")]
+    per_input: bool,
+    
+    /// Number of threads used to parse the reports (0 uses all available cores)
+    #[arg(long, default_value = "0")]
+    threads: usize,
+}
+
+/// Arguments for the 'prune' command
+#[derive(Args)]
+struct PruneArgs {
+    /// Kraken2 report file
+    report: String,
+    
+    /// Output file for the pruned report
+    #[arg(short, long)]
+    output: String,
+    
+    /// Minimum clade reads of a kept taxon
+    #[arg(long = "min-reads", required_unless_present = "min_percent")]
+    min_reads: Option<u64>,
+    
+    /// Minimum percentage of the reads of the report in the clade of a kept taxon
+    #[arg(long = "min-percent")]
+    min_percent: Option<f64>,
+    
+    /// Output format (kreport or json)
+    #[arg(long, default_value = "kreport")]
+    format: String,
+}
+
+/// Arguments for the 'collapse' command
+#[derive(Args)]
+struct CollapseArgs {
+    /// Kraken2 report file
+    report: String,
+    
+    /// Output file for the collapsed report
+    #[arg(short, long)]
+    output: String,
+    
+    /// Deepest rank of the collapsed report (e.g. G, F or S)
+    #[arg(long)]
+    rank: String,
+    
+    /// Output format (kreport or json)
+    #[arg(long, default_value = "kreport")]
+    format: String,
 }
 
 /// Arguments for the 'generate-test-data' command
@@ -171,7 +575,19 @@ pub fn run_cli() {
     let result = match cli.command {
         Commands::Analyze(args) => run_analyze(args),
         Commands::Extract(args) => run_extract(args),
-        Commands::AbundanceMatrix(args) => run_abundance_matrix(args),
+        Commands::AbundanceMatrix(args) => run_abundance_matrix(*args),
+        Commands::Translate(args) => run_translate(args),
+        Commands::Convert(args) => run_convert(args),
+        Commands::Ordinate(args) => run_ordinate(args),
+        Commands::CompareGroups(args) => run_compare_groups(args),
+        Commands::Diff(args) => run_diff(args),
+        Commands::CombineReports(args) => run_combine_reports(args),
+        Commands::Prune(args) => run_prune(args),
+        Commands::Collapse(args) => run_collapse(args),
+        Commands::Diversity(args) => match args.command {
+            DiversityCommands::Alpha(args) => run_alpha_diversity(args),
+            DiversityCommands::Beta(args) => run_beta_diversity(args),
+        },
         Commands::GenerateTestData(args) => run_generate_test_data(args),
     };
 
@@ -315,7 +731,7 @@ fn run_extract(args: ExtractArgs) -> Result<(), Box<dyn Error>> {
             // Cambiar la siguiente línea si total_sequences no se usa después
             let total_sequences = count_sequences_in_file(&args.sequence)?;
             
-            match sequence_processor::process_sequence_files(&[args.sequence.clone()], &readids, &args.output, args.exclude) {
+            match sequence_processor::process_sequence_files(std::slice::from_ref(&args.sequence), &readids, &args.output, args.exclude) {
                 Ok(_) => {
                     println!("Sequences extracted successfully to {}", args.output);
                     println!("{} sequences matching {} taxids", 
@@ -327,13 +743,13 @@ fn run_extract(args: ExtractArgs) -> Result<(), Box<dyn Error>> {
                         readids.len()
                     );
                 }
-                Err(e) => return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))),
+                Err(e) => return Err(Box::new(std::io::Error::other(e.to_string()))),
             }
                     
                     // Generate statistics file if requested
             if let Some(ref stats_file) = args.stats_output {
                         match generate_statistics_file(
-                    &stats_file,
+                    stats_file,
                             &taxid_readid_map,
                             &original_taxids,
                     total_sequences,
@@ -469,7 +885,7 @@ fn generate_statistics_file(
     }
     
     // Sort by number of sequences (descending)
-    stats.sort_by(|a, b| b.1.cmp(&a.1));
+    stats.sort_by_key(|b| std::cmp::Reverse(b.1));
     
     // Calculate percentages for the summary
     let percent_extracted = if total_sequences > 0 {
@@ -608,77 +1024,725 @@ fn find_parents_dfs(
 
 /// Implements the 'abundance-matrix' command
 fn run_abundance_matrix(args: AbundanceMatrixArgs) -> Result<(), Box<dyn Error>> {
-    // Validate the taxonomic level
-    if !validate_taxonomic_level(&args.level) {
-        return Err(format!("Error: The taxonomic level '{}' is not valid. Use K, P, C, O, F, G or S.", args.level).into());
+    // Validate the taxonomic levels
+    let levels: Vec<RankSelector> = match args.levels {
+        Some(ref levels) => rank::parse_rank_list(levels),
+        None => args.level.parse().map(|level| vec![level]),
+    }
+    .map_err(|e| format!("Error: {}", e))?;
+
+    // Load the translation table when reports from NCBI and GTDB databases are combined
+    let translation = match (&args.translation_table, &args.translate_to) {
+        (Some(table), Some(to)) => Some(load_translation_table(table, to)?),
+        _ => None,
+    };
+
+    // Validate the output format before any report is parsed
+    if args.format != "tsv" && args.format != "biom" && args.format != "long" {
+        return Err(format!("Error: Unsupported output format '{}'. Use 'tsv', 'biom' or 'long'.", args.format).into());
+    }
+    // The long format holds both the read counts and the relative abundances
+    let long_format = args.format == "long";
+    if long_format && (args.normalize || args.normalization.is_some()) {
+        return Err("Error: The long format needs read counts and cannot be combined with --normalize or --normalization".into());
+    }
+
+    // Load the sample metadata (or manifest) used to name and order the samples
+    let metadata = match args.sample_metadata.as_ref().or(args.manifest.as_ref()) {
+        Some(path) => {
+            let metadata = SampleMetadata::from_tsv(path)
+                .map_err(|e| format!("Error reading sample metadata '{}': {}", path, e))?;
+            if metadata.is_empty() {
+                return Err(format!("Error: The sample metadata '{}' contains no samples", path).into());
+            }
+            println!("Loaded metadata for {} samples from {}", metadata.len(), path);
+            Some(metadata)
+        }
+        None => None,
+    };
+    if let Some(ref field) = args.sort_by {
+        match metadata {
+            Some(ref metadata) if metadata.fields.contains(field) => {}
+            Some(_) => return Err(format!("Error: Unknown metadata field '{}' for --sort-by", field).into()),
+            None => return Err("Error: --sort-by requires --sample-metadata or --manifest".into()),
+        }
+    }
+
+    // Parse the normalization with its parameters
+    let normalization = match args.normalization {
+        Some(ref name) => {
+            let normalization = match name.parse::<Normalization>().map_err(|e| format!("Error: {}", e))? {
+                Normalization::Clr { .. } => Normalization::Clr { pseudocount: args.pseudocount },
+                Normalization::Rarefy { .. } => Normalization::Rarefy { depth: args.rarefaction_depth, seed: args.seed },
+                other => other,
+            };
+            println!("Normalization: {}", normalization);
+            Some(normalization)
+        }
+        None => None,
+    };
+
+    let prevalence_filter = PrevalenceFilter {
+        min_abundance: args.prevalence_abundance,
+        min_samples: args.min_prevalence.unwrap_or(0),
+        min_total_reads: args.min_total_reads,
+    };
+    if args.other && !prevalence_filter.is_active() {
+        return Err("Error: --other requires --min-prevalence or --min-total-reads".into());
     }
+    let ranking: TaxonRanking = args.rank_by.parse().map_err(|e| format!("Error: {}", e))?;
+    let unassigned: UnassignedReads = args.unassigned.parse().map_err(|e| format!("Error: {}", e))?;
+    let taxonomy_format: TaxonomyFormat = args.taxonomy_format.parse().map_err(|e| format!("Error: {}", e))?;
 
-    match args.format.as_str() {
-        "tsv" => {
-            // Create a new abundance matrix
-            let mut matrix = AbundanceMatrix::new(&args.level);
+    // Resolve every input up front, so a missing report fails the run before any parsing
+    let inputs = resolve_inputs(&args, metadata.as_ref())?;
+    println!("Found {} input reports", inputs.len());
+
+    // Create one abundance matrix per level, so each report is parsed only once
+    let new_matrices = || -> Vec<AbundanceMatrix> {
+        levels.iter().map(|level| {
+            let mut matrix = AbundanceMatrix::new(*level);
             matrix.set_force_include_unclassified(args.include_unclassified);
+            matrix.set_unassigned_reads(unassigned);
+            matrix.set_include_lineage(args.lineage);
+            matrix
+        }).collect()
+    };
+    for level in &levels {
+        println!("Aggregating abundances at {} level ({})", level.rank().name(), level);
+    }
+    let mut matrices = new_matrices();
 
-            // Process each input file
-            for (i, file) in args.input.iter().enumerate() {
-                // Use the filename as the sample name (removing extension and path)
-                let default_name = format!("sample_{}", i + 1);
-                let sample_name = Path::new(file)
-                    .file_stem()
-                    .and_then(|s| s.to_str())
-                    .unwrap_or(&default_name);
-                
-                println!("Processing sample: {}", sample_name);
-                
-                // Parse the report and add it to the matrix with proper error handling
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build()
+        .map_err(|e| format!("Error creating thread pool: {}", e))?;
+    println!("Parsing reports with {} threads", pool.current_num_threads());
+
+    // Parse the reports in parallel, one batch at a time, so that only the partial
+    // matrices of the current batch are kept in memory. Each worker builds the
+    // matrices of its own sample, and the partials are merged in input order.
+    let batch_size = pool.current_num_threads() * REPORTS_PER_THREAD;
+    for batch in inputs.chunks(batch_size) {
+        let partials = pool.install(|| {
+            batch.par_iter().map(|(sample_name, file)| -> Result<Vec<AbundanceMatrix>, Box<dyn Error + Send + Sync>> {
                 let (report, _) = krk_parser::parse_kraken2_report(file)
                     .map_err(|e| format!("Error parsing Kraken2 report file '{}': {}", file, e))?;
-                matrix.add_sample(&report, sample_name, args.min_abundance, args.normalize);
+                let report = translate_if_needed(report, translation.as_ref());
+                let mut partials = new_matrices();
+                for partial in partials.iter_mut() {
+                    partial.add_sample(&report, sample_name, args.min_abundance, args.normalize);
+                }
+                Ok(partials)
+            }).collect::<Result<Vec<_>, _>>()
+        }).map_err(|e| e.to_string())?;
+
+        for ((sample_name, _), sample_partials) in batch.iter().zip(partials) {
+            println!("Processing sample: {}", sample_name);
+            for (matrix, partial) in matrices.iter_mut().zip(sample_partials) {
+                matrix.merge(partial);
             }
+        }
+    }
+
+    for (matrix, level) in matrices.iter_mut().zip(&levels) {
+        // Apply the cohort filters to the read counts, once every sample is known
+        if prevalence_filter.is_active() {
+            let removed = matrix.filter_taxa(&prevalence_filter, args.other);
+            println!("Removed {} taxa below the prevalence or read thresholds at {} level", removed, level);
+        }
 
+        // Logarithms cannot be summed into "Other", so the top taxa are then picked
+        // from the read counts and "Other" is transformed like the other rows
+        let top_before_normalization = normalization.as_ref().is_some_and(|n| n.is_logarithmic());
+        if let Some(top) = args.top.filter(|_| top_before_normalization) {
+            let removed = matrix.keep_top_taxa(top, ranking, true);
+            println!("Summed {} taxa outside the top {} by {} into 'Other' at {} level", removed, top, args.rank_by, level);
+        }
+
+        if let Some(ref normalization) = normalization {
+            // Normalize the read counts of the whole cohort
+            let dropped = matrix.apply_normalization(normalization)
+                .map_err(|e| format!("Error normalizing the abundance matrix: {}", e))?;
+            for sample in dropped {
+                eprintln!("Warning: Sample '{}' has fewer reads than the rarefaction depth and was removed", sample);
+            }
+        } else {
             // Convert counts to proportions (default behavior unless --absolute-counts is specified)
             let convert_to_proportions = args.proportions || !args.absolute_counts;
-            if convert_to_proportions && !args.normalize {
+            if convert_to_proportions && !args.normalize && !long_format {
                 matrix.transform_to_proportions();
             }
+        }
+
+        // Keep the most abundant taxa of the final values for plotting
+        if let Some(top) = args.top.filter(|_| !top_before_normalization) {
+            let removed = matrix.keep_top_taxa(top, ranking, true);
+            println!("Summed {} taxa outside the top {} by {} into 'Other' at {} level", removed, top, args.rank_by, level);
+        }
+
+        if let (Some(metadata), Some(field)) = (&metadata, &args.sort_by) {
+            let mut samples = matrix.sample_names();
+            metadata.sort_samples(&mut samples, field)?;
+            matrix.set_sample_order(samples);
+        }
+
+        let output = if args.levels.is_some() {
+            level_output_path(&args.output, level)
+        } else {
+            args.output.clone()
+        };
 
+        if args.format == "biom" {
+            // Generate a single BIOM table with one column per sample
+            let mut biom_table = BiomTable::from_abundance_matrix(matrix);
+            if let Some(ref metadata) = metadata {
+                biom_table.set_sample_metadata(metadata);
+            }
+            match biom_table.write_json(&output) {
+                Ok(_) => println!("BIOM format output successfully generated in: {}", output),
+                Err(e) => return Err(format!("Error generating BIOM output: {}", e).into()),
+            }
+        } else if long_format {
+            // Generate one line per sample and taxon
+            match matrix.write_long(&output) {
+                Ok(_) => println!("Long format output successfully generated in: {}", output),
+                Err(e) => return Err(format!("Error generating long format output: {}", e).into()),
+            }
+        } else {
             // Generate the abundance matrix in TSV format
-            match matrix.write_matrix(&args.output) {
-                Ok(_) => println!("Abundance matrix successfully generated in: {}", args.output),
+            match matrix.write_matrix(&output) {
+                Ok(_) => println!("Abundance matrix successfully generated in: {}", output),
                 Err(e) => return Err(format!("Error generating abundance matrix: {}", e).into()),
             }
+        }
+
+        if let Some(ref taxonomy_table) = args.taxonomy_table {
+            let taxonomy_output = if args.levels.is_some() {
+                level_output_path(taxonomy_table, level)
+            } else {
+                taxonomy_table.clone()
+            };
+            match matrix.write_taxonomy_table(&taxonomy_output, taxonomy_format) {
+                Ok(_) => println!("Taxonomy table successfully generated in: {}", taxonomy_output),
+                Err(e) => return Err(format!("Error generating taxonomy table: {}", e).into()),
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+/// Resolves the (sample name, report path) pairs of the 'abundance-matrix' command
+/// 
+/// Inputs come from the manifest, or from the positional arguments and the
+/// `--input-glob` patterns. Samples are named after the metadata or the file
+/// stem, and every report must exist and map to a distinct sample name.
+fn resolve_inputs(args: &AbundanceMatrixArgs, metadata: Option<&SampleMetadata>) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut inputs = Vec::new();
+    if let (Some(path), Some(manifest)) = (&args.manifest, metadata) {
+        inputs = manifest.manifest_inputs()
+            .map_err(|e| format!("Error reading manifest '{}': {}", path, e))?;
+    } else {
+        let mut files = args.input.clone();
+        for pattern in &args.input_glob {
+            let mut matches = Vec::new();
+            for entry in glob::glob(pattern).map_err(|e| format!("Error: Invalid glob pattern '{}': {}", pattern, e))? {
+                let path = entry.map_err(|e| format!("Error reading '{}': {}", e.path().display(), e.error()))?;
+                if path.is_file() {
+                    matches.push(path.to_string_lossy().into_owned());
+                }
+            }
+            if matches.is_empty() {
+                return Err(format!("Error: No reports match the pattern '{}'", pattern).into());
+            }
+            matches.sort();
+            files.extend(matches);
+        }
+
+        for (i, file) in files.into_iter().enumerate() {
+            // Use the sample mapped to the file in the metadata, or the filename (removing extension and path)
+            let sample_name = metadata
+                .and_then(|metadata| metadata.sample_for_file(&file))
+                .or_else(|| Path::new(&file).file_stem().and_then(|s| s.to_str()))
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| format!("sample_{}", i + 1));
+            if let Some(metadata) = metadata {
+                if metadata.get(&sample_name).is_none() {
+                    eprintln!("Warning: Sample '{}' ({}) has no entry in the sample metadata", sample_name, file);
+                }
+            }
+            inputs.push((sample_name, file));
+        }
+    }
+
+    let mut sample_files: HashMap<&str, &str> = HashMap::with_capacity(inputs.len());
+    for (sample_name, file) in &inputs {
+        if !Path::new(file).is_file() {
+            return Err(format!("Error: Input report '{}' (sample '{}') does not exist", file, sample_name).into());
+        }
+        // Two files with the same name in different directories would be merged into one column
+        if let Some(previous) = sample_files.insert(sample_name, file) {
+            return Err(format!(
+                "Error: Files '{}' and '{}' both map to sample '{}'. Use --sample-metadata or --manifest to name them.",
+                previous, file, sample_name
+            ).into());
+        }
+    }
+    Ok(inputs)
+}
+
+/// Builds the output path of the matrix for one level, e.g. "matrix.tsv" -> "matrix_G.tsv"
+/// Selections including more specific ranks use a "plus" suffix ("matrix_Gplus.tsv")
+fn level_output_path(output: &str, level: &RankSelector) -> String {
+    suffixed_output_path(output, &level.to_string().replace('+', "plus"))
+}
+
+/// Adds a suffix to the file stem of an output path, e.g. ("pcoa.tsv", "variance") -> "pcoa_variance.tsv"
+fn suffixed_output_path(output: &str, suffix: &str) -> String {
+    let path = Path::new(output);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("abundance");
+    let file_name = match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) => format!("{}_{}.{}", stem, suffix, ext),
+        None => format!("{}_{}", stem, suffix),
+    };
+    path.with_file_name(file_name).to_string_lossy().into_owned()
+}
+
+/// Loads an NCBI/GTDB translation table for the given target taxonomy
+fn load_translation_table(path: &str, to: &str) -> Result<TranslationTable, Box<dyn Error>> {
+    let direction: TranslationDirection = to.parse()?;
+    let table = TranslationTable::from_tsv(path, direction)
+        .map_err(|e| format!("Error reading translation table '{}': {}", path, e))?;
+    if table.is_empty() {
+        return Err(format!("Error: The translation table '{}' contains no usable rows", path).into());
+    }
+    println!("Loaded {} taxid translations from {}", table.len(), path);
+    Ok(table)
+}
+
+/// Translates a report unless it already uses the target taxonomy of the table
+fn translate_if_needed(report: krk_parser::KrakenReport, table: Option<&TranslationTable>) -> krk_parser::KrakenReport {
+    match table {
+        Some(table) if table.needs_translation(&report) => gtdb::translate_report(&report, table),
+        _ => report,
+    }
+}
+
+/// Implements the 'translate' command
+fn run_translate(args: TranslateArgs) -> Result<(), Box<dyn Error>> {
+    if args.format != "kreport" && args.format != "json" {
+        return Err(format!("Error: Unsupported output format '{}'. Use 'kreport' or 'json'.", args.format).into());
+    }
+    let table = load_translation_table(&args.table, &args.to)?;
+    
+    let (report, _) = krk_parser::parse_kraken2_report(&args.report)
+        .map_err(|e| format!("Error parsing Kraken2 report file '{}': {}", args.report, e))?;
+    
+    if !table.needs_translation(&report) {
+        println!("Report '{}' already uses the {} taxonomy", args.report, args.to);
+    }
+    let translated = translate_if_needed(report, Some(&table));
+    
+    write_transformed_report(&translated, &args.output, &args.format)
+}
+
+/// Checks whether a file is a BIOM JSON table (as opposed to a TSV matrix)
+fn is_biom_file(path: &str) -> Result<bool, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)
+        .map_err(|e| format!("Error opening '{}': {}", path, e))?);
+    let mut first_line = String::new();
+    reader.read_line(&mut first_line)?;
+    Ok(first_line.trim_start().starts_with('{'))
+}
+
+/// Reads an abundance matrix from either a BIOM JSON table or a TSV matrix
+fn read_abundance_table(path: &str) -> Result<AbundanceMatrix, Box<dyn Error>> {
+    let matrix = if is_biom_file(path)? {
+        BiomTable::read_json(path)
+            .and_then(|table| table.to_abundance_matrix())
+            .map_err(|e| format!("Error reading BIOM table '{}': {}", path, e))?
+    } else {
+        AbundanceMatrix::read_matrix(path)
+            .map_err(|e| format!("Error reading abundance matrix '{}': {}", path, e))?
+    };
+    Ok(matrix)
+}
+
+/// Implements the 'convert' command
+fn run_convert(args: ConvertArgs) -> Result<(), Box<dyn Error>> {
+    let input_is_biom = is_biom_file(&args.input)?;
+    let to = match args.to.as_deref() {
+        Some(to) => to.to_string(),
+        None if input_is_biom => "tsv".to_string(),
+        None => "biom".to_string(),
+    };
+    if args.matrix_type != "sparse" && args.matrix_type != "dense" {
+        return Err(format!("Error: Unsupported matrix type '{}'. Use 'sparse' or 'dense'.", args.matrix_type).into());
+    }
+    
+    let matrix = read_abundance_table(&args.input)?;
+    println!("Read {} taxa and {} samples from {}", matrix.taxids().len(), matrix.sample_names().len(), args.input);
+    
+    match to.as_str() {
+        "tsv" => match matrix.write_matrix(&args.output) {
+            Ok(_) => println!("Abundance matrix successfully generated in: {}", args.output),
+            Err(e) => return Err(format!("Error generating abundance matrix: {}", e).into()),
         },
         "biom" => {
-            // Process each input file and create BIOM tables
-            for (i, file) in args.input.iter().enumerate() {
-                let default_name = format!("sample_{}", i + 1);
-                let sample_name = Path::new(file)
-                    .file_stem()
-                    .and_then(|s| s.to_str())
-                    .unwrap_or(&default_name);
-                
-                println!("Processing sample: {}", sample_name);
-                
-                // Parse the report
-                let (report, _) = krk_parser::parse_kraken2_report(file)
-                    .map_err(|e| format!("Error parsing Kraken2 report file '{}': {}", file, e))?;
-                
-                // Create BIOM table
-                let biom_table = BiomTable::from_kraken_report(&report, sample_name, args.normalize);
-                
-                // Write BIOM output
-                match biom_table.write_json(&args.output) {
-                    Ok(_) => println!("BIOM format output successfully generated in: {}", args.output),
-                    Err(e) => return Err(format!("Error generating BIOM output: {}", e).into()),
-                }
+            let mut biom_table = BiomTable::from_abundance_matrix(&matrix);
+            biom_table.matrix_type = args.matrix_type.clone();
+            match biom_table.write_json(&args.output) {
+                Ok(_) => println!("BIOM format output successfully generated in: {}", args.output),
+                Err(e) => return Err(format!("Error generating BIOM output: {}", e).into()),
             }
         },
-        _ => return Err(format!("Error: Unsupported output format '{}'. Use 'tsv' or 'biom'.", args.format).into()),
+        _ => return Err(format!("Error: Unsupported output format '{}'. Use 'tsv' or 'biom'.", to).into()),
     }
     
     Ok(())
 }
 
+/// Names the samples of the 'diversity' commands after the file stems of their reports
+/// 
+/// Every report must exist and map to a distinct sample name.
+fn report_samples(files: &[String]) -> Result<Vec<(String, &str)>, Box<dyn Error>> {
+    let mut sample_files: HashMap<String, &str> = HashMap::with_capacity(files.len());
+    let mut inputs = Vec::with_capacity(files.len());
+    for (i, file) in files.iter().enumerate() {
+        if !Path::new(file).is_file() {
+            return Err(format!("Error: Input report '{}' does not exist", file).into());
+        }
+        let sample_name = Path::new(file).file_stem()
+            .and_then(|s| s.to_str())
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| format!("sample_{}", i + 1));
+        if let Some(previous) = sample_files.insert(sample_name.clone(), file) {
+            return Err(format!("Error: Files '{}' and '{}' both map to sample '{}'", previous, file, sample_name).into());
+        }
+        inputs.push((sample_name, file.as_str()));
+    }
+    Ok(inputs)
+}
+
+/// Read counts of the taxa of a sample, as used by the alpha diversity indices
+fn taxon_counts(matrix: &AbundanceMatrix, sample: &str) -> Vec<f64> {
+    matrix.taxon_values(sample).into_iter().map(|(_, value)| value).collect()
+}
+
+/// Implements the 'diversity alpha' command
+fn run_alpha_diversity(args: AlphaDiversityArgs) -> Result<(), Box<dyn Error>> {
+    let results: Vec<(String, AlphaDiversity)> = match args.matrix {
+        Some(ref path) => {
+            // Diversity of every sample of an existing matrix
+            let matrix = read_abundance_table(path)?;
+            println!("Read {} taxa and {} samples from {}", matrix.taxids().len(), matrix.sample_names().len(), path);
+            matrix.sample_names().into_iter()
+                .map(|sample| {
+                    let alpha = AlphaDiversity::from_counts(&taxon_counts(&matrix, &sample))
+                        .map_err(|e| format!("Error computing the diversity of sample '{}': {}", sample, e))?;
+                    Ok((sample, alpha))
+                })
+                .collect::<Result<_, Box<dyn Error>>>()?
+        }
+        None => {
+            let level: RankSelector = args.level.parse().map_err(|e| format!("Error: {}", e))?;
+            let inputs = report_samples(&args.input)?;
+            println!("Counting taxa at {} level ({})", level.rank().name(), level);
+
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(args.threads)
+                .build()
+                .map_err(|e| format!("Error creating thread pool: {}", e))?;
+            // Each worker keeps only the indices of its sample, so reports are dropped once counted
+            pool.install(|| {
+                inputs.par_iter().map(|(sample_name, file)| -> Result<(String, AlphaDiversity), Box<dyn Error + Send + Sync>> {
+                    let (report, _) = krk_parser::parse_kraken2_report(file)
+                        .map_err(|e| format!("Error parsing Kraken2 report file '{}': {}", file, e))?;
+                    let mut matrix = AbundanceMatrix::new(level);
+                    matrix.add_sample(&report, sample_name, 0.0, false);
+                    let alpha = AlphaDiversity::from_counts(&taxon_counts(&matrix, sample_name))
+                        .map_err(|e| format!("Error computing the diversity of sample '{}': {}", sample_name, e))?;
+                    Ok((sample_name.clone(), alpha))
+                }).collect::<Result<Vec<_>, _>>()
+            }).map_err(|e| e.to_string())?
+        }
+    };
+
+    match diversity::write_alpha_diversity(&args.output, &results) {
+        Ok(_) => println!("Alpha diversity of {} samples successfully generated in: {}", results.len(), args.output),
+        Err(e) => return Err(format!("Error generating alpha diversity table: {}", e).into()),
+    }
+    
+    Ok(())
+}
+
+/// Implements the 'diversity beta' command
+fn run_beta_diversity(args: BetaDiversityArgs) -> Result<(), Box<dyn Error>> {
+    let metric = match args.metric.parse::<BetaMetric>().map_err(|e| format!("Error: {}", e))? {
+        BetaMetric::Aitchison { .. } => BetaMetric::Aitchison { pseudocount: args.pseudocount },
+        other => other,
+    };
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build()
+        .map_err(|e| format!("Error creating thread pool: {}", e))?;
+
+    let (matrix, tree) = match args.matrix {
+        Some(ref path) => {
+            if metric.needs_tree() {
+                return Err("Error: UniFrac needs the taxonomy tree of the reports and cannot be computed from --matrix".into());
+            }
+            let matrix = read_abundance_table(path)?;
+            println!("Read {} taxa and {} samples from {}", matrix.taxids().len(), matrix.sample_names().len(), path);
+            (matrix, None)
+        }
+        None => {
+            let level: RankSelector = args.level.parse().map_err(|e| format!("Error: {}", e))?;
+            let inputs = report_samples(&args.input)?;
+            println!("Counting taxa at {} level ({})", level.rank().name(), level);
+
+            // Each worker builds the partial matrix and taxonomy tree of its sample,
+            // and the partials are merged in input order
+            let partials = pool.install(|| {
+                inputs.par_iter().map(|(sample_name, file)| -> Result<(AbundanceMatrix, TaxonomyTree), Box<dyn Error + Send + Sync>> {
+                    let (report, _) = krk_parser::parse_kraken2_report(file)
+                        .map_err(|e| format!("Error parsing Kraken2 report file '{}': {}", file, e))?;
+                    let mut matrix = AbundanceMatrix::new(level);
+                    matrix.add_sample(&report, sample_name, 0.0, false);
+                    let tree = if metric.needs_tree() { TaxonomyTree::from_report(&report) } else { TaxonomyTree::default() };
+                    Ok((matrix, tree))
+                }).collect::<Result<Vec<_>, _>>()
+            }).map_err(|e| e.to_string())?;
+
+            let mut matrix = AbundanceMatrix::new(level);
+            let mut tree = TaxonomyTree::default();
+            for (partial, partial_tree) in partials {
+                matrix.merge(partial);
+                tree.merge(partial_tree);
+            }
+            if !tree.is_empty() {
+                println!("Taxonomy tree with {} taxa below the root", tree.len());
+            }
+            (matrix, Some(tree))
+        }
+    };
+
+    println!("Computing {} distances between {} samples", args.metric, matrix.sample_names().len());
+    let distances = pool.install(|| diversity::beta_diversity(&matrix, metric, tree.as_ref()))
+        .map_err(|e| format!("Error computing distances: {}", e))?;
+    match distances.write_tsv(&args.output) {
+        Ok(_) => println!("Distance matrix successfully generated in: {}", args.output),
+        Err(e) => return Err(format!("Error generating distance matrix: {}", e).into()),
+    }
+    
+    Ok(())
+}
+
+/// Implements the 'ordinate' command
+fn run_ordinate(args: OrdinateArgs) -> Result<(), Box<dyn Error>> {
+    let distances = match (&args.input, &args.matrix) {
+        (_, Some(path)) => {
+            let metric = match args.metric.parse::<BetaMetric>().map_err(|e| format!("Error: {}", e))? {
+                BetaMetric::Aitchison { .. } => BetaMetric::Aitchison { pseudocount: args.pseudocount },
+                other => other,
+            };
+            if metric.needs_tree() {
+                return Err("Error: UniFrac needs the taxonomy tree of the reports; compute it with 'diversity beta' first".into());
+            }
+            let matrix = read_abundance_table(path)?;
+            println!("Read {} taxa and {} samples from {}", matrix.taxids().len(), matrix.sample_names().len(), path);
+            diversity::beta_diversity(&matrix, metric, None)
+                .map_err(|e| format!("Error computing distances: {}", e))?
+        }
+        (Some(path), None) => DistanceMatrix::read_tsv(path)
+            .map_err(|e| format!("Error reading distance matrix '{}': {}", path, e))?,
+        (None, None) => return Err("Error: A distance matrix or --matrix is required".into()),
+    };
+    println!("Ordinating {} samples", distances.len());
+
+    let pcoa = ordination::pcoa(&distances).map_err(|e| format!("Error computing the PCoA: {}", e))?;
+    for (axis, proportion) in pcoa.proportion_explained.iter().enumerate().take(3) {
+        println!("PC{} explains {:.2}% of the variance", axis + 1, proportion * 100.0);
+    }
+    match pcoa.write_coordinates(&args.output, args.axes) {
+        Ok(_) => println!("PCoA coordinates successfully generated in: {}", args.output),
+        Err(e) => return Err(format!("Error generating PCoA coordinates: {}", e).into()),
+    }
+    let variance_output = args.variance_output.clone()
+        .unwrap_or_else(|| suffixed_output_path(&args.output, "variance"));
+    match pcoa.write_variance(&variance_output) {
+        Ok(_) => println!("Variance explained successfully generated in: {}", variance_output),
+        Err(e) => return Err(format!("Error generating variance explained: {}", e).into()),
+    }
+
+    if let Some(ref tree) = args.tree {
+        let newick = ordination::upgma(&distances);
+        match std::fs::write(tree, format!("{}\n", newick)) {
+            Ok(_) => println!("UPGMA tree successfully generated in: {}", tree),
+            Err(e) => return Err(format!("Error generating UPGMA tree: {}", e).into()),
+        }
+    }
+    
+    Ok(())
+}
+
+/// Implements the 'compare-groups' command
+fn run_compare_groups(args: CompareGroupsArgs) -> Result<(), Box<dyn Error>> {
+    let metadata = SampleMetadata::from_tsv(&args.sample_metadata)
+        .map_err(|e| format!("Error reading sample metadata '{}': {}", args.sample_metadata, e))?;
+    if !metadata.fields.contains(&args.group) {
+        return Err(format!("Error: Unknown metadata field '{}' for --group", args.group).into());
+    }
+    let matrix = read_abundance_table(&args.input)?;
+    println!("Read {} taxa and {} samples from {}", matrix.taxids().len(), matrix.sample_names().len(), args.input);
+
+    let mut groups = HashMap::new();
+    for sample in matrix.sample_names() {
+        match metadata.value(&sample, &args.group) {
+            Some(group) => { groups.insert(sample, group.to_string()); }
+            None => eprintln!("Warning: Sample '{}' has no '{}' value and is left out", sample, args.group),
+        }
+    }
+
+    let comparison = differential::compare_groups(
+        &matrix, &groups, args.reference.as_deref(), args.pseudocount, args.min_samples
+    ).map_err(|e| format!("Error comparing groups: {}", e))?;
+    let sizes: Vec<String> = comparison.groups.iter().zip(&comparison.group_sizes)
+        .map(|(group, size)| format!("{} ({} samples)", group, size))
+        .collect();
+    println!("Compared {} taxa between {}", comparison.taxa.len(), sizes.join(", "));
+    let significant = comparison.taxa.iter().filter(|taxon| taxon.q_value < 0.05).count();
+    println!("{} taxa have a q-value below 0.05", significant);
+
+    match comparison.write_tsv(&args.output, &matrix) {
+        Ok(_) => println!("Group comparison successfully generated in: {}", args.output),
+        Err(e) => return Err(format!("Error generating group comparison: {}", e).into()),
+    }
+    
+    Ok(())
+}
+
+/// Implements the 'diff' command
+fn run_diff(args: DiffArgs) -> Result<(), Box<dyn Error>> {
+    let rank = match args.rank {
+        Some(ref rank) => Some(rank.parse::<RankSelector>().map_err(|e| format!("Error: {}", e))?),
+        None => None,
+    };
+    if args.pseudocount <= 0.0 {
+        return Err("Error: The pseudocount must be positive".into());
+    }
+    let (report_a, _) = krk_parser::parse_kraken2_report(&args.report_a)
+        .map_err(|e| format!("Error parsing Kraken2 report file '{}': {}", args.report_a, e))?;
+    let (report_b, _) = krk_parser::parse_kraken2_report(&args.report_b)
+        .map_err(|e| format!("Error parsing Kraken2 report file '{}': {}", args.report_b, e))?;
+
+    let diff = report_diff::diff_reports(&report_a, &report_b, args.pseudocount);
+    let filter = DiffFilter { rank, min_reads: args.min_reads };
+    let taxa = diff.filtered(&filter);
+    let count = |presence: Presence| taxa.iter().filter(|taxon| taxon.presence() == presence).count();
+    println!(
+        "{} taxa: {} in both reports, {} only in {}, {} only in {}",
+        taxa.len(), count(Presence::Both), count(Presence::OnlyA), args.report_a, count(Presence::OnlyB), args.report_b
+    );
+
+    if !args.no_tree {
+        diff.print_tree(&filter);
+    }
+    match diff.write_tsv(&args.output, &filter) {
+        Ok(_) => println!("Report diff successfully generated in: {}", args.output),
+        Err(e) => return Err(format!("Error generating report diff: {}", e).into()),
+    }
+    
+    Ok(())
+}
+
+/// Implements the 'combine-reports' command
+fn run_combine_reports(args: CombineReportsArgs) -> Result<(), Box<dyn Error>> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build()
+        .map_err(|e| format!("Error creating thread pool: {}", e))?;
+    let reports = pool.install(|| {
+        args.input.par_iter().map(|file| -> Result<KrakenReport, Box<dyn Error + Send + Sync>> {
+            let (report, _) = krk_parser::parse_kraken2_report(file)
+                .map_err(|e| format!("Error parsing Kraken2 report file '{}': {}", file, e))?;
+            Ok(report)
+        }).collect::<Result<Vec<_>, _>>()
+    }).map_err(|e| e.to_string())?;
+
+    let combined = combine_reports::combine_reports(&reports)
+        .map_err(|e| format!("Error combining reports: {}", e))?;
+    let classified = combined.report.root.clade_reads;
+    let unclassified = combined.report.unclassified.as_ref().map_or(0, |entry| entry.clade_reads);
+    println!(
+        "Combined {} reports: {} classified and {} unclassified reads",
+        combined.inputs(), classified, unclassified
+    );
+
+    let names = if args.per_input { Some(args.input.as_slice()) } else { None };
+    match combined.write_report(&args.output, names) {
+        Ok(_) => println!("Combined report successfully generated in: {}", args.output),
+        Err(e) => return Err(format!("Error generating combined report: {}", e).into()),
+    }
+    
+    Ok(())
+}
+
+/// Writes a transformed report as a Kraken2 report or JSON
+fn write_transformed_report(report: &KrakenReport, output: &str, format: &str) -> Result<(), Box<dyn Error>> {
+    let result = match format {
+        "kreport" => krk_parser::write_kraken_report(report, output),
+        "json" => krk_parser::write_json_report(report, output),
+        _ => return Err(format!("Error: Unsupported output format '{}'. Use 'kreport' or 'json'.", format).into()),
+    };
+    match result {
+        Ok(_) => println!("Report successfully generated in: {}", output),
+        Err(e) => return Err(format!("Error generating report: {}", e).into()),
+    }
+    Ok(())
+}
+
+/// Implements the 'prune' command
+fn run_prune(args: PruneArgs) -> Result<(), Box<dyn Error>> {
+    if args.format != "kreport" && args.format != "json" {
+        return Err(format!("Error: Unsupported output format '{}'. Use 'kreport' or 'json'.", args.format).into());
+    }
+    let thresholds = PruneThresholds {
+        min_reads: args.min_reads.unwrap_or(0),
+        min_percent: args.min_percent.unwrap_or(0.0),
+    };
+    let (report, _) = krk_parser::parse_kraken2_report(&args.report)
+        .map_err(|e| format!("Error parsing Kraken2 report file '{}': {}", args.report, e))?;
+
+    let (pruned, summary) = report_transform::prune_report(&report, &thresholds);
+    println!(
+        "Pruned {} of {} taxa, moving {} reads to their nearest remaining ancestor",
+        summary.taxa_removed, report.taxon_map.len(), summary.reads_reassigned
+    );
+    write_transformed_report(&pruned, &args.output, &args.format)
+}
+
+/// Implements the 'collapse' command
+fn run_collapse(args: CollapseArgs) -> Result<(), Box<dyn Error>> {
+    if args.format != "kreport" && args.format != "json" {
+        return Err(format!("Error: Unsupported output format '{}'. Use 'kreport' or 'json'.", args.format).into());
+    }
+    let rank: Rank = args.rank.parse().map_err(|e| format!("Error: {}", e))?;
+    if rank.letter().is_none() {
+        return Err(format!("Error: Cannot collapse to the '{}' rank", args.rank).into());
+    }
+    let (report, _) = krk_parser::parse_kraken2_report(&args.report)
+        .map_err(|e| format!("Error parsing Kraken2 report file '{}': {}", args.report, e))?;
+
+    let (collapsed, summary) = report_transform::collapse_report(&report, rank);
+    println!(
+        "Collapsed {} taxa to {} level ({}), moving {} reads to their ancestor",
+        summary.taxa_removed, rank.name(), rank, summary.reads_reassigned
+    );
+    write_transformed_report(&collapsed, &args.output, &args.format)
+}
+
 /// Implements the 'generate-test-data' command
 fn run_generate_test_data(args: GenerateTestDataArgs) -> Result<(), Box<dyn Error>> {
     // Add aggregated information as needed
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::error::Error;
+use crate::krk_parser::{write_kraken_report, write_report_line, KrakenReport, TaxonEntry};
+
+/// Optimized buffer size for writing combined reports
+const BUFFER_SIZE: usize = 256 * 1024; // 256KB
+
+/// Specific errors for combining reports
+#[derive(Debug)]
+pub enum CombineError {
+    IoError(std::io::Error),
+    InvalidData(String),
+}
+
+impl std::fmt::Display for CombineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(e) => write!(f, "I/O error: {}", e),
+            Self::InvalidData(s) => write!(f, "Invalid data: {}", s),
+        }
+    }
+}
+
+impl Error for CombineError {}
+
+impl From<std::io::Error> for CombineError {
+    fn from(e: std::io::Error) -> Self {
+        Self::IoError(e)
+    }
+}
+
+/// Specialized result type for combining reports
+pub type CombineResult<T> = Result<T, CombineError>;
+
+/// Sum of several Kraken2 reports, with the counts of each input
+#[derive(Debug)]
+pub struct CombinedReport {
+    /// Summed report, with percentages of the combined reads
+    pub report: KrakenReport,
+    /// Clade and direct reads of each taxid in each input
+    counts: HashMap<u32, Vec<(u64, u64)>>,
+    /// Number of combined reports
+    inputs: usize,
+}
+
+/// Sums reports taxon by taxon
+///
+/// # Arguments
+/// * `reports` - Reports to combine, e.g. the lanes or technical replicates of one library
+///
+/// # Returns
+/// * `CombineResult<CombinedReport>` - Combined report, or an error when the reports
+///   have different root taxa
+///
+/// # Implementation Details
+/// The trees are merged by taxid under each parent, and the clade and direct
+/// reads of each taxon are summed. Percentages are recomputed from the
+/// combined classified and unclassified reads, and siblings are sorted by
+/// decreasing clade reads as Kraken2 does.
+pub fn combine_reports(reports: &[KrakenReport]) -> CombineResult<CombinedReport> {
+    let first = reports.first()
+        .ok_or_else(|| CombineError::InvalidData("no reports to combine".to_string()))?;
+    if let Some(other) = reports.iter().find(|report| report.root.taxid != first.root.taxid) {
+        return Err(CombineError::InvalidData(format!(
+            "the reports have different root taxa ({} and {})", first.root.taxid, other.root.taxid
+        )));
+    }
+
+    let mut root = empty_entry(&first.root);
+    let mut unclassified: Option<TaxonEntry> = None;
+    let mut counts: HashMap<u32, Vec<(u64, u64)>> = HashMap::new();
+    for (input, report) in reports.iter().enumerate() {
+        merge_entry(&mut root, &report.root);
+        if let Some(ref entry) = report.unclassified {
+            let target = unclassified.get_or_insert_with(|| empty_entry(entry));
+            target.clade_reads += entry.clade_reads;
+            target.direct_reads += entry.direct_reads;
+        }
+        for entry in report.unclassified.iter().chain(std::iter::once(&report.root)) {
+            record_counts(entry, input, reports.len(), &mut counts);
+        }
+    }
+
+    let total = root.clade_reads + unclassified.as_ref().map_or(0, |entry| entry.clade_reads);
+    finish_entry(&mut root, 0, total);
+    if let Some(ref mut entry) = unclassified {
+        finish_entry(entry, 0, total);
+    }
+
+    Ok(CombinedReport { report: KrakenReport::new(root, unclassified), counts, inputs: reports.len() })
+}
+
+/// Copy of a taxon without reads or children
+fn empty_entry(entry: &TaxonEntry) -> TaxonEntry {
+    let mut empty = TaxonEntry::new(0.0, 0, 0, entry.rank_code.clone(), entry.taxid, entry.name.clone(), entry.depth);
+    // The rank of GTDB names comes from their prefix rather than from the code
+    empty.rank = entry.rank;
+    empty
+}
+
+/// Adds the reads of a subtree to the matching subtree of the combined tree
+fn merge_entry(target: &mut TaxonEntry, source: &TaxonEntry) {
+    target.clade_reads += source.clade_reads;
+    target.direct_reads += source.direct_reads;
+    let mut positions: HashMap<u32, usize> = target.children.iter()
+        .enumerate()
+        .map(|(i, child)| (child.taxid, i))
+        .collect();
+    for child in &source.children {
+        let position = *positions.entry(child.taxid).or_insert_with(|| {
+            target.children.push(empty_entry(child));
+            target.children.len() - 1
+        });
+        merge_entry(&mut target.children[position], child);
+    }
+}
+
+/// Records the reads of each taxon of an input, keeping the first line of a repeated taxid
+fn record_counts(entry: &TaxonEntry, input: usize, inputs: usize, counts: &mut HashMap<u32, Vec<(u64, u64)>>) {
+    let values = counts.entry(entry.taxid).or_insert_with(|| vec![(0, 0); inputs]);
+    if values[input] == (0, 0) {
+        values[input] = (entry.clade_reads, entry.direct_reads);
+    }
+    for child in &entry.children {
+        record_counts(child, input, inputs, counts);
+    }
+}
+
+/// Sets the percentages and depths of a combined subtree and sorts its children
+fn finish_entry(entry: &mut TaxonEntry, depth: usize, total: u64) {
+    entry.percentage = if total > 0 { (entry.clade_reads as f64 * 100.0 / total as f64) as f32 } else { 0.0 };
+    entry.clade_fragments = entry.clade_reads;
+    entry.direct_fragments = entry.direct_reads;
+    entry.depth = depth;
+    entry.level = depth;
+    entry.children.sort_by_key(|child| std::cmp::Reverse(child.clade_reads));
+    for child in &mut entry.children {
+        finish_entry(child, depth + 1, total);
+    }
+}
+
+impl CombinedReport {
+    /// Number of combined reports
+    pub fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    /// Clade and direct reads of a taxon in each input (zero where it is missing)
+    pub fn input_counts(&self, taxid: u32) -> Option<&[(u64, u64)]> {
+        self.counts.get(&taxid).map(|counts| counts.as_slice())
+    }
+
+    /// Writes the combined report
+    ///
+    /// # Arguments
+    /// * `output_path` - Path to the output file
+    /// * `input_names` - Names of the inputs to add their clade and direct reads
+    ///   as extra columns, or None for a standard 6-column Kraken2 report
+    ///
+    /// # Implementation Details
+    /// With per-input columns the layout follows KrakenTools' combine_kreports:
+    /// "#" header lines name the inputs, and each line holds the percentage,
+    /// the combined clade and direct reads, then the clade and direct reads of
+    /// each input before the rank code, taxid and indented name. This layout is
+    /// not a Kraken2 report and cannot be read back as one.
+    pub fn write_report(&self, output_path: &str, input_names: Option<&[String]>) -> CombineResult<()> {
+        let names = match input_names {
+            Some(names) => names,
+            None => return Ok(write_kraken_report(&self.report, output_path)?),
+        };
+        if names.len() != self.inputs {
+            return Err(CombineError::InvalidData(format!(
+                "{} input names for {} combined reports", names.len(), self.inputs
+            )));
+        }
+        let file = File::create(output_path)?;
+        let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+
+        let total = self.report.root.clade_reads
+            + self.report.unclassified.as_ref().map_or(0, |entry| entry.clade_reads);
+        writeln!(writer, "#Number of Samples: {}", names.len())?;
+        writeln!(writer, "#Total Number of Reads: {}", total)?;
+        for (i, name) in names.iter().enumerate() {
+            writeln!(writer, "#S{}\t{}", i + 1, name)?;
+        }
+        write!(writer, "#perc\ttot_all\ttot_lvl")?;
+        for i in 1..=names.len() {
+            write!(writer, "\t{}_all\t{}_lvl", i, i)?;
+        }
+        writeln!(writer, "\tlvl_type\ttaxid\tname")?;
+
+        if let Some(ref unclassified) = self.report.unclassified {
+            self.write_entry(&mut writer, unclassified, 0)?;
+        }
+        self.write_entry(&mut writer, &self.report.root, 0)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the line of a taxon and of its descendants with the reads of each input
+    fn write_entry<W: Write>(&self, writer: &mut W, entry: &TaxonEntry, depth: usize) -> std::io::Result<()> {
+        let counts: Vec<u64> = self.input_counts(entry.taxid).unwrap_or(&[])
+            .iter()
+            .flat_map(|&(clade, direct)| [clade, direct])
+            .collect();
+        write_report_line(writer, entry, depth, &counts)?;
+        for child in &entry.children {
+            self.write_entry(writer, child, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::krk_parser::parse_kraken2_report;
+
+    fn entry(clade_reads: u64, direct_reads: u64, rank: &str, taxid: u32, name: &str, children: Vec<TaxonEntry>) -> TaxonEntry {
+        let mut entry = TaxonEntry::new(0.0, clade_reads, direct_reads, rank.to_string(), taxid, name.to_string(), 0);
+        entry.children = children;
+        entry
+    }
+
+    fn reports() -> Vec<KrakenReport> {
+        let lane1 = KrakenReport::new(
+            entry(90, 10, "R", 1, "root", vec![
+                entry(80, 0, "G", 561, "Escherichia", vec![entry(80, 80, "S", 562, "Escherichia coli", Vec::new())]),
+            ]),
+            Some(entry(10, 10, "U", 0, "unclassified", Vec::new())),
+        );
+        let lane2 = KrakenReport::new(
+            entry(100, 0, "R", 1, "root", vec![
+                entry(40, 0, "G", 561, "Escherichia", vec![entry(40, 40, "S", 562, "Escherichia coli", Vec::new())]),
+                entry(60, 60, "G", 590, "Salmonella", Vec::new()),
+            ]),
+            None,
+        );
+        vec![lane1, lane2]
+    }
+
+    #[test]
+    fn test_combine_reports() {
+        let combined = combine_reports(&reports()).unwrap();
+        let root = &combined.report.root;
+        assert_eq!((root.clade_reads, root.direct_reads), (190, 10));
+        assert_eq!(combined.report.unclassified.as_ref().unwrap().clade_reads, 10);
+        // Siblings are sorted by decreasing clade reads
+        let children: Vec<(u32, u64)> = root.children.iter().map(|child| (child.taxid, child.clade_reads)).collect();
+        assert_eq!(children, vec![(561, 120), (590, 60)]);
+        assert!((root.children[0].percentage - 60.0).abs() < 1e-4);
+        assert_eq!(root.children[0].children[0].depth, 2);
+        assert_eq!(combined.input_counts(590), Some(&[(0, 0), (60, 60)][..]));
+        assert_eq!(combined.input_counts(0), Some(&[(10, 10), (0, 0)][..]));
+
+        let mismatched = KrakenReport::new(entry(5, 5, "R", 131567, "cellular organisms", Vec::new()), None);
+        assert!(combine_reports(&[reports().remove(0), mismatched]).is_err());
+        assert!(combine_reports(&[]).is_err());
+    }
+
+    #[test]
+    fn test_write_report() {
+        let combined = combine_reports(&reports()).unwrap();
+        let dir = std::env::temp_dir().join(format!("krakenclip_combine_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let standard = dir.join("combined.kreport");
+        combined.write_report(standard.to_str().unwrap(), None).unwrap();
+        let text = std::fs::read_to_string(&standard).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "  5.00\t10\t10\tU\t0\tunclassified");
+        assert_eq!(lines[3], " 60.00\t120\t120\tS\t562\t    Escherichia coli");
+        let (report, _) = parse_kraken2_report(standard.to_str().unwrap()).unwrap();
+        assert_eq!(report.root.clade_reads, 190);
+        assert_eq!(report.root.children[1].name, "Salmonella");
+
+        let names = vec!["lane1".to_string(), "lane2".to_string()];
+        let per_input = dir.join("per_input.tsv");
+        combined.write_report(per_input.to_str().unwrap(), Some(&names)).unwrap();
+        let text = std::fs::read_to_string(&per_input).unwrap();
+        assert!(text.starts_with("#Number of Samples: 2\n#Total Number of Reads: 200\n#S1\tlane1\n"));
+        assert!(text.contains("#perc\ttot_all\ttot_lvl\t1_all\t1_lvl\t2_all\t2_lvl\tlvl_type\ttaxid\tname\n"));
+        assert!(text.contains(" 30.00\t60\t60\t0\t0\t60\t60\tG\t590\t  Salmonella\n"));
+        assert!(combined.write_report(per_input.to_str().unwrap(), Some(&names[..1])).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
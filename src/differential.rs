@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::error::Error;
+use crate::abundance_matrix::AbundanceMatrix;
+use crate::stats::{self, TestResult};
+
+/// Optimized buffer size for writing comparison tables
+const BUFFER_SIZE: usize = 256 * 1024; // 256KB
+
+/// Specific errors for the differential abundance module
+#[derive(Debug)]
+pub enum DifferentialError {
+    IoError(std::io::Error),
+    InvalidData(String),
+}
+
+impl std::fmt::Display for DifferentialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(e) => write!(f, "I/O error: {}", e),
+            Self::InvalidData(s) => write!(f, "Invalid data: {}", s),
+        }
+    }
+}
+
+impl Error for DifferentialError {}
+
+impl From<std::io::Error> for DifferentialError {
+    fn from(e: std::io::Error) -> Self {
+        Self::IoError(e)
+    }
+}
+
+/// Specialized result type for differential abundance functions
+pub type DifferentialResult<T> = Result<T, DifferentialError>;
+
+/// Comparison of the abundances of one taxon between the groups
+#[derive(Debug, Clone)]
+pub struct TaxonComparison {
+    /// Taxid of the matrix row
+    pub taxid: u32,
+    /// Mann-Whitney U (two groups) or Kruskal-Wallis H (more groups) on the relative abundances
+    pub test: TestResult,
+    /// Benjamini-Hochberg adjusted p-value
+    pub q_value: f64,
+    /// Mean CLR-transformed abundance in each group
+    pub mean_clr: Vec<f64>,
+    /// Fraction of the samples of each group where the taxon is present
+    pub prevalence: Vec<f64>,
+}
+
+/// Differential abundance of the taxa of a matrix between sample groups
+#[derive(Debug, Clone)]
+pub struct GroupComparison {
+    /// Group names in column order
+    pub groups: Vec<String>,
+    /// Number of samples in each group
+    pub group_sizes: Vec<usize>,
+    /// Position of the reference group of the fold changes
+    pub reference: usize,
+    /// Comparison of each tested taxon, by increasing p-value
+    pub taxa: Vec<TaxonComparison>,
+}
+
+impl GroupComparison {
+    /// Log2 fold change of the CLR abundance of a group against the reference group
+    pub fn log2_fold_change(&self, taxon: &TaxonComparison, group: usize) -> f64 {
+        (taxon.mean_clr[group] - taxon.mean_clr[self.reference]) / std::f64::consts::LN_2
+    }
+
+    /// Writes one line per taxon with its test, q-value, per-group CLR means and
+    /// prevalences, and the fold change of each group against the reference
+    ///
+    /// # Arguments
+    /// * `output_file` - Path to the output file
+    /// * `matrix` - Matrix the comparison was computed from, for the taxon names
+    pub fn write_tsv(&self, output_file: &str, matrix: &AbundanceMatrix) -> DifferentialResult<()> {
+        let file = File::create(output_file)?;
+        let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+        let test = if self.groups.len() == 2 { "mann-whitney" } else { "kruskal-wallis" };
+        let others: Vec<usize> = (0..self.groups.len()).filter(|&group| group != self.reference).collect();
+
+        write!(writer, "taxid\tname\ttest\tstatistic\tp_value\tq_value")?;
+        for group in &self.groups {
+            write!(writer, "\tmean_clr_{}\tprevalence_{}", group, group)?;
+        }
+        for &group in &others {
+            write!(writer, "\tlog2_fold_change_{}_vs_{}", self.groups[group], self.groups[self.reference])?;
+        }
+        writeln!(writer)?;
+
+        for taxon in &self.taxa {
+            let name = matrix.taxon(taxon.taxid).map(|t| t.name.as_str()).unwrap_or("");
+            write!(
+                writer, "{}\t{}\t{}\t{}\t{}\t{}",
+                taxon.taxid, name, test,
+                format_value(taxon.test.statistic), format_value(taxon.test.p_value), format_value(taxon.q_value)
+            )?;
+            for (mean, prevalence) in taxon.mean_clr.iter().zip(&taxon.prevalence) {
+                write!(writer, "\t{:.6}\t{:.6}", mean, prevalence)?;
+            }
+            for &group in &others {
+                write!(writer, "\t{:.6}", self.log2_fold_change(taxon, group))?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Formats a statistic at full precision for a TSV table, with "NA" for undefined values
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NA".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Compares the abundance of every taxon between groups of samples
+///
+/// # Arguments
+/// * `matrix` - Abundance matrix, preferably of read counts
+/// * `sample_groups` - Group of each sample; samples without a group are left out
+/// * `reference` - Group the fold changes are computed against (the first group by name when None)
+/// * `pseudocount` - Pseudocount added before the CLR transform
+/// * `min_samples` - Minimum number of grouped samples where a taxon is present for it to be tested
+///
+/// # Returns
+/// * `DifferentialResult<GroupComparison>` - Tested taxa sorted by p-value, then in matrix order
+///
+/// # Implementation Details
+/// The rank tests compare the relative abundances (value over the sample
+/// total), so that samples of different depths can be compared. The fold
+/// changes compare the means of the CLR-transformed abundances,
+/// `ln(x + pseudocount)` minus its mean over all taxa of the sample. The
+/// "Unclassified", "Unassigned" and "Other" rows are not tested and are not
+/// part of the totals or of the CLR transform.
+pub fn compare_groups(
+    matrix: &AbundanceMatrix,
+    sample_groups: &HashMap<String, String>,
+    reference: Option<&str>,
+    pseudocount: f64,
+    min_samples: usize,
+) -> DifferentialResult<GroupComparison> {
+    if pseudocount <= 0.0 {
+        return Err(DifferentialError::InvalidData("the CLR pseudocount must be positive".to_string()));
+    }
+    let samples = matrix.sample_names();
+    let mut groups: Vec<String> = samples.iter().filter_map(|sample| sample_groups.get(sample)).cloned().collect();
+    groups.sort();
+    groups.dedup();
+    if groups.len() < 2 {
+        return Err(DifferentialError::InvalidData(format!(
+            "at least two groups are needed, found {}", groups.len()
+        )));
+    }
+    let reference = match reference {
+        Some(name) => groups.iter().position(|group| group == name).ok_or_else(|| {
+            DifferentialError::InvalidData(format!("reference group '{}' has no samples", name))
+        })?,
+        None => 0,
+    };
+    // Group position of each sample of the matrix
+    let sample_group: Vec<Option<usize>> = samples.iter()
+        .map(|sample| sample_groups.get(sample).and_then(|group| groups.iter().position(|g| g == group)))
+        .collect();
+    let mut group_sizes = vec![0; groups.len()];
+    for group in sample_group.iter().flatten() {
+        group_sizes[*group] += 1;
+    }
+
+    // Taxa present in the grouped samples
+    let rows: Vec<(u32, Vec<(usize, f64)>)> = matrix.sparse_rows().into_iter()
+        .filter(|(taxid, _)| !matrix.is_special_taxon(*taxid))
+        .map(|(taxid, values)| {
+            (taxid, values.into_iter().filter(|&(col, value)| sample_group[col].is_some() && value > 0.0).collect::<Vec<_>>())
+        })
+        .filter(|(_, values)| !values.is_empty())
+        .collect();
+
+    // Totals and mean logarithms of each sample for the relative and CLR abundances
+    let taxa = rows.len() as f64;
+    let mut totals = vec![0.0; samples.len()];
+    let mut log_sums = vec![0.0; samples.len()];
+    let mut present = vec![0usize; samples.len()];
+    for (_, values) in &rows {
+        for &(col, value) in values {
+            totals[col] += value;
+            log_sums[col] += (value + pseudocount).ln();
+            present[col] += 1;
+        }
+    }
+    let mean_logs: Vec<f64> = (0..samples.len())
+        .map(|col| (log_sums[col] + (taxa - present[col] as f64) * pseudocount.ln()) / taxa)
+        .collect();
+
+    let mut comparisons = Vec::new();
+    for (taxid, values) in rows {
+        if values.len() < min_samples {
+            continue;
+        }
+        let mut dense = vec![0.0; samples.len()];
+        for &(col, value) in &values {
+            dense[col] = value;
+        }
+        let mut relative = vec![Vec::new(); groups.len()];
+        let mut clr_sums = vec![0.0; groups.len()];
+        let mut prevalence = vec![0.0; groups.len()];
+        for (col, group) in sample_group.iter().enumerate() {
+            if let Some(group) = *group {
+                let value = dense[col];
+                relative[group].push(if totals[col] > 0.0 { value / totals[col] } else { 0.0 });
+                clr_sums[group] += (value + pseudocount).ln() - mean_logs[col];
+                if value > 0.0 {
+                    prevalence[group] += 1.0;
+                }
+            }
+        }
+        let test = if groups.len() == 2 {
+            stats::mann_whitney_u(&relative[0], &relative[1])
+        } else {
+            stats::kruskal_wallis(&relative)
+        };
+        comparisons.push(TaxonComparison {
+            taxid,
+            test,
+            q_value: f64::NAN,
+            mean_clr: clr_sums.iter().zip(&group_sizes).map(|(sum, &size)| sum / size as f64).collect(),
+            prevalence: prevalence.iter().zip(&group_sizes).map(|(count, &size)| count / size as f64).collect(),
+        });
+    }
+
+    let p_values: Vec<f64> = comparisons.iter().map(|taxon| taxon.test.p_value).collect();
+    for (taxon, q_value) in comparisons.iter_mut().zip(stats::benjamini_hochberg(&p_values)) {
+        taxon.q_value = q_value;
+    }
+    // Stable sort, so equal p-values keep the matrix order and undefined ones go last
+    comparisons.sort_by(|a, b| match (a.test.p_value.is_nan(), b.test.p_value.is_nan()) {
+        (false, false) => a.test.p_value.total_cmp(&b.test.p_value),
+        (nan_a, nan_b) => nan_a.cmp(&nan_b),
+    });
+
+    Ok(GroupComparison { groups, group_sizes, reference, taxa: comparisons })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abundance_matrix::TaxonAnnotation;
+    use crate::rank::Rank;
+
+    const EPSILON: f64 = 1e-9;
+
+    /// Matrix with taxon 1 enriched in group "b", taxon 2 even and taxon 3 only in one sample
+    fn matrix() -> (AbundanceMatrix, HashMap<String, String>) {
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        let counts = [
+            ("a1", [10.0, 90.0, 0.0]),
+            ("a2", [20.0, 80.0, 0.0]),
+            ("a3", [15.0, 85.0, 0.0]),
+            ("b1", [60.0, 40.0, 0.0]),
+            ("b2", [70.0, 30.0, 0.0]),
+            ("b3", [80.0, 20.0, 5.0]),
+            ("x", [1.0, 1.0, 1.0]),
+        ];
+        for (sample, values) in counts {
+            for (taxid, value) in [1, 2, 3].into_iter().zip(values) {
+                let annotation = TaxonAnnotation::from_lineage(taxid, Some(format!("taxon {}", taxid)), Some(Rank::Species(0)), Vec::new());
+                matrix.set_abundance(taxid, annotation, sample, value);
+            }
+        }
+        let groups = ["a1", "a2", "a3", "b1", "b2", "b3"].iter()
+            .map(|sample| (sample.to_string(), sample[..1].to_string()))
+            .collect();
+        (matrix, groups)
+    }
+
+    #[test]
+    fn test_two_groups() {
+        let (matrix, groups) = matrix();
+        let comparison = compare_groups(&matrix, &groups, None, 1.0, 1).unwrap();
+        assert_eq!(comparison.groups, vec!["a", "b"]);
+        assert_eq!(comparison.group_sizes, vec![3, 3]);
+        assert_eq!(comparison.taxa.len(), 3);
+
+        // Taxa 1 and 2 are completely separated: U = 0 or 9 with a p-value of 0.0809
+        let first = &comparison.taxa[0];
+        assert_eq!(first.taxid, 1);
+        assert_eq!(first.test.statistic, 0.0);
+        assert!((first.test.p_value - 0.08085559837005223).abs() < EPSILON);
+        assert!(comparison.log2_fold_change(first, 1) > 0.0);
+        assert_eq!(first.prevalence, vec![1.0, 1.0]);
+
+        let third = comparison.taxa.iter().find(|taxon| taxon.taxid == 3).unwrap();
+        assert_eq!(third.prevalence, vec![0.0, 1.0 / 3.0]);
+
+        // Taxa present in fewer samples are not tested
+        let filtered = compare_groups(&matrix, &groups, Some("b"), 1.0, 2).unwrap();
+        assert_eq!(filtered.taxa.len(), 2);
+        assert_eq!(filtered.reference, 1);
+        assert!(comparison.log2_fold_change(first, 1) + filtered.log2_fold_change(&filtered.taxa[0], 0) < EPSILON);
+    }
+
+    #[test]
+    fn test_clr_means() {
+        let (matrix, groups) = matrix();
+        let comparison = compare_groups(&matrix, &groups, None, 1.0, 1).unwrap();
+        // CLR values of a sample sum to zero, so the group means over all taxa do too
+        for group in 0..2 {
+            let sum: f64 = comparison.taxa.iter().map(|taxon| taxon.mean_clr[group]).sum();
+            assert!(sum.abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn test_invalid_groups() {
+        let (matrix, mut groups) = matrix();
+        assert!(compare_groups(&matrix, &groups, Some("c"), 1.0, 1).is_err());
+        assert!(compare_groups(&matrix, &groups, None, 0.0, 1).is_err());
+        groups.retain(|_, group| group == "a");
+        assert!(compare_groups(&matrix, &groups, None, 1.0, 1).is_err());
+    }
+}
@@ -0,0 +1,723 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::error::Error;
+use std::str::FromStr;
+use rayon::prelude::*;
+use crate::abundance_matrix::AbundanceMatrix;
+use crate::krk_parser::{KrakenReport, TaxonEntry};
+
+/// Optimized buffer size for writing diversity tables
+const BUFFER_SIZE: usize = 256 * 1024; // 256KB
+
+/// Largest difference accepted between d(i, j) and d(j, i), or between d(i, i) and 0,
+/// in a distance matrix read from a file (values are written with 6 decimals)
+const DISTANCE_TOLERANCE: f64 = 1e-6;
+
+/// Taxa with at most this many reads are "rare" in the ACE estimator
+pub const ACE_RARE_THRESHOLD: u64 = 10;
+
+/// Specific errors for the diversity module
+#[derive(Debug)]
+pub enum DiversityError {
+    IoError(std::io::Error),
+    InvalidData(String),
+}
+
+impl std::fmt::Display for DiversityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(e) => write!(f, "I/O error: {}", e),
+            Self::InvalidData(s) => write!(f, "Invalid data: {}", s),
+        }
+    }
+}
+
+impl Error for DiversityError {}
+
+impl From<std::io::Error> for DiversityError {
+    fn from(e: std::io::Error) -> Self {
+        Self::IoError(e)
+    }
+}
+
+/// Specialized result type for diversity functions
+pub type DiversityResult<T> = Result<T, DiversityError>;
+
+/// Alpha diversity indices of one sample
+///
+/// Indices that are undefined for a sample (e.g. evenness with a single taxon,
+/// or ACE when every rare taxon is a singleton) are NaN.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlphaDiversity {
+    /// Total reads over all taxa
+    pub reads: u64,
+    /// Observed number of taxa
+    pub richness: usize,
+    /// Shannon entropy, -sum(p ln p)
+    pub shannon: f64,
+    /// Gini-Simpson index, 1 - sum(p^2)
+    pub simpson: f64,
+    /// Inverse Simpson index, 1 / sum(p^2)
+    pub inverse_simpson: f64,
+    /// Pielou evenness, Shannon divided by ln(richness)
+    pub pielou: f64,
+    /// Bias-corrected Chao1 richness estimate
+    pub chao1: f64,
+    /// Abundance-based coverage estimator of richness
+    pub ace: f64,
+}
+
+impl AlphaDiversity {
+    /// Computes the alpha diversity of a sample from its read counts
+    ///
+    /// # Arguments
+    /// * `counts` - Read count of each taxon of the sample; zeros are ignored
+    ///
+    /// # Returns
+    /// * `DiversityResult<AlphaDiversity>` - The indices, or an error when the
+    ///   values are not whole read counts (Chao1 and ACE need singletons and doubletons)
+    ///
+    /// # Implementation Details
+    /// Chao1 is `S + F1 (F1 - 1) / (2 (F2 + 1))`, where F1 and F2 are the numbers
+    /// of singletons and doubletons. ACE follows Chao and Lee (1992) with taxa of
+    /// at most `ACE_RARE_THRESHOLD` reads treated as rare, as in vegan's `estimateR`.
+    pub fn from_counts(counts: &[f64]) -> DiversityResult<Self> {
+        if counts.iter().any(|&count| count.fract() != 0.0 || count < 0.0) {
+            return Err(DiversityError::InvalidData(
+                "alpha diversity needs absolute read counts".to_string(),
+            ));
+        }
+        let counts: Vec<u64> = counts.iter().map(|&count| count as u64).filter(|&count| count > 0).collect();
+        let reads: u64 = counts.iter().sum();
+        let richness = counts.len();
+
+        let mut shannon = 0.0;
+        let mut sum_squares = 0.0;
+        for &count in &counts {
+            let p = count as f64 / reads as f64;
+            shannon -= p * p.ln();
+            sum_squares += p * p;
+        }
+        let (simpson, inverse_simpson) = if richness > 0 {
+            (1.0 - sum_squares, 1.0 / sum_squares)
+        } else {
+            (f64::NAN, f64::NAN)
+        };
+        let pielou = if richness > 1 { shannon / (richness as f64).ln() } else { f64::NAN };
+        if richness == 0 {
+            shannon = f64::NAN;
+        }
+
+        let singletons = counts.iter().filter(|&&count| count == 1).count() as f64;
+        let doubletons = counts.iter().filter(|&&count| count == 2).count() as f64;
+        let chao1 = richness as f64 + singletons * (singletons - 1.0) / (2.0 * (doubletons + 1.0));
+
+        Ok(Self {
+            reads,
+            richness,
+            shannon,
+            simpson,
+            inverse_simpson,
+            pielou,
+            chao1,
+            ace: ace(&counts, singletons),
+        })
+    }
+}
+
+/// Abundance-based coverage estimator (ACE) of the richness of a sample
+///
+/// NaN when the rare taxa are all singletons, since the sample coverage is then zero.
+fn ace(counts: &[u64], singletons: f64) -> f64 {
+    let rare: Vec<u64> = counts.iter().copied().filter(|&count| count <= ACE_RARE_THRESHOLD).collect();
+    let abundant = (counts.len() - rare.len()) as f64;
+    if rare.is_empty() {
+        return abundant;
+    }
+    let rare_taxa = rare.len() as f64;
+    let rare_reads = rare.iter().sum::<u64>() as f64;
+    let coverage = 1.0 - singletons / rare_reads;
+    if coverage <= 0.0 {
+        return f64::NAN;
+    }
+    let pairs: f64 = rare.iter().map(|&count| (count * (count - 1)) as f64).sum();
+    let gamma = (rare_taxa / coverage * pairs / (rare_reads * (rare_reads - 1.0)) - 1.0).max(0.0);
+    abundant + rare_taxa / coverage + singletons / coverage * gamma
+}
+
+/// Formats an index for a TSV table, with "NA" for undefined values
+fn format_index(value: f64) -> String {
+    if value.is_nan() {
+        "NA".to_string()
+    } else {
+        format!("{:.6}", value)
+    }
+}
+
+/// Writes the alpha diversity of each sample as a TSV table
+///
+/// # Arguments
+/// * `output_file` - Path to the output file
+/// * `samples` - Sample names and their indices, in output order
+pub fn write_alpha_diversity(output_file: &str, samples: &[(String, AlphaDiversity)]) -> DiversityResult<()> {
+    let file = File::create(output_file)?;
+    let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+    writeln!(writer, "sample\treads\trichness\tshannon\tsimpson\tinverse_simpson\tpielou\tchao1\tace")?;
+    for (sample, alpha) in samples {
+        writeln!(
+            writer, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            sample, alpha.reads, alpha.richness,
+            format_index(alpha.shannon), format_index(alpha.simpson), format_index(alpha.inverse_simpson),
+            format_index(alpha.pielou), format_index(alpha.chao1), format_index(alpha.ace)
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Distance between the taxa of two samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BetaMetric {
+    /// Bray-Curtis dissimilarity, sum|a - b| / sum(a + b)
+    BrayCurtis,
+    /// Jaccard distance between the sets of taxa present in each sample
+    Jaccard,
+    /// Aitchison distance: Euclidean distance between CLR-transformed samples
+    Aitchison { pseudocount: f64 },
+    /// Unweighted UniFrac: fraction of the branch length of the taxonomy tree unique to one sample
+    UnweightedUnifrac,
+    /// Normalized weighted UniFrac: branch lengths weighted by the difference of relative abundances
+    WeightedUnifrac,
+}
+
+impl FromStr for BetaMetric {
+    type Err = DiversityError;
+
+    /// Parses a metric name (braycurtis, jaccard, aitchison, unifrac or weighted-unifrac) with default parameters
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "braycurtis" | "bray-curtis" | "bray" => Ok(Self::BrayCurtis),
+            "jaccard" => Ok(Self::Jaccard),
+            "aitchison" => Ok(Self::Aitchison { pseudocount: crate::normalization::DEFAULT_PSEUDOCOUNT }),
+            "unifrac" | "unweighted-unifrac" => Ok(Self::UnweightedUnifrac),
+            "weighted-unifrac" | "wunifrac" => Ok(Self::WeightedUnifrac),
+            _ => Err(DiversityError::InvalidData(format!(
+                "unknown metric '{}', use braycurtis, jaccard, aitchison, unifrac or weighted-unifrac", s
+            ))),
+        }
+    }
+}
+
+impl BetaMetric {
+    /// Whether the metric needs the taxonomy tree
+    pub fn needs_tree(&self) -> bool {
+        matches!(self, Self::UnweightedUnifrac | Self::WeightedUnifrac)
+    }
+}
+
+/// Taxonomy tree used as the phylogeny of UniFrac distances
+///
+/// The tree is stored as the parent of each taxid, merged from the reports of
+/// every sample. Each edge between a taxon and its parent has unit length, so
+/// the distance between two taxa is the number of rank steps between them.
+#[derive(Debug, Clone, Default)]
+pub struct TaxonomyTree {
+    parents: HashMap<u32, u32>,
+}
+
+impl TaxonomyTree {
+    /// Builds the tree of a single report
+    pub fn from_report(report: &KrakenReport) -> Self {
+        let mut tree = Self::default();
+        tree.add_report(report);
+        tree
+    }
+
+    /// Adds the taxa of a report; taxa already in the tree keep their first parent
+    pub fn add_report(&mut self, report: &KrakenReport) {
+        fn add_children(node: &TaxonEntry, parents: &mut HashMap<u32, u32>) {
+            for child in &node.children {
+                parents.entry(child.taxid).or_insert(node.taxid);
+                add_children(child, parents);
+            }
+        }
+        add_children(&report.root, &mut self.parents);
+    }
+
+    /// Adds the taxa of another tree, keeping the parents already known
+    pub fn merge(&mut self, other: TaxonomyTree) {
+        for (taxid, parent) in other.parents {
+            self.parents.entry(taxid).or_insert(parent);
+        }
+    }
+
+    /// Number of taxa with a parent
+    pub fn len(&self) -> usize {
+        self.parents.len()
+    }
+
+    /// Whether the tree has no edges
+    pub fn is_empty(&self) -> bool {
+        self.parents.is_empty()
+    }
+
+    /// Subtree spanned by the given taxa and their ancestors
+    ///
+    /// Nodes are ordered so that every node comes before its parent. Taxa
+    /// missing from the tree become roots of their own.
+    fn subtree(&self, taxids: &[u32]) -> Subtree {
+        // Depth of every ancestor of the taxa, counted from its root
+        let mut depths: HashMap<u32, usize> = HashMap::new();
+        for &taxid in taxids {
+            let mut path = Vec::new();
+            let mut current = taxid;
+            let base = loop {
+                if let Some(&depth) = depths.get(&current) {
+                    break depth + 1;
+                }
+                path.push(current);
+                match self.parents.get(&current) {
+                    // A parent already on the path would make a cycle
+                    Some(&parent) if !path.contains(&parent) => current = parent,
+                    _ => break 0,
+                }
+            };
+            for (offset, &node) in path.iter().rev().enumerate() {
+                depths.insert(node, base + offset);
+            }
+        }
+
+        let mut nodes: Vec<u32> = depths.keys().copied().collect();
+        nodes.sort_unstable_by_key(|taxid| (std::cmp::Reverse(depths[taxid]), *taxid));
+        let positions: HashMap<u32, usize> = nodes.iter().enumerate().map(|(i, &taxid)| (taxid, i)).collect();
+        Subtree {
+            parents: nodes.iter()
+                .map(|taxid| self.parents.get(taxid).and_then(|parent| positions.get(parent)).copied())
+                .collect(),
+            depths: nodes.iter().map(|taxid| depths[taxid] as f64).collect(),
+            positions,
+        }
+    }
+}
+
+/// Part of a taxonomy tree holding the taxa of a matrix, with nodes indexed by position
+struct Subtree {
+    /// Position of the parent of each node (None for roots, which have no branch above them)
+    parents: Vec<Option<usize>>,
+    /// Number of unit branches between each node and its root
+    depths: Vec<f64>,
+    /// Position of each taxid
+    positions: HashMap<u32, usize>,
+}
+
+/// Square matrix of distances between samples
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistanceMatrix {
+    /// Sample names in row (and column) order
+    pub samples: Vec<String>,
+    /// Distances in row-major order
+    distances: Vec<f64>,
+}
+
+impl DistanceMatrix {
+    /// Builds a distance matrix from its values in row-major order
+    ///
+    /// Fails when the matrix is not square, not symmetric, has a non-zero
+    /// diagonal or holds negative or undefined distances.
+    pub fn new(samples: Vec<String>, distances: Vec<f64>) -> DiversityResult<Self> {
+        let n = samples.len();
+        if distances.len() != n * n {
+            return Err(DiversityError::InvalidData(format!(
+                "{} distances for {} samples", distances.len(), n
+            )));
+        }
+        for i in 0..n {
+            for j in 0..n {
+                let (value, mirrored) = (distances[i * n + j], distances[j * n + i]);
+                if value.is_nan() || value < 0.0 {
+                    return Err(DiversityError::InvalidData(format!(
+                        "invalid distance between '{}' and '{}'", samples[i], samples[j]
+                    )));
+                }
+                if (i == j && value > DISTANCE_TOLERANCE) || (value - mirrored).abs() > DISTANCE_TOLERANCE {
+                    return Err(DiversityError::InvalidData(format!(
+                        "the distances between '{}' and '{}' are not symmetric", samples[i], samples[j]
+                    )));
+                }
+            }
+        }
+        Ok(Self { samples, distances })
+    }
+
+    /// Reads a distance matrix in the TSV layout written by `write_tsv`
+    pub fn read_tsv(input_file: &str) -> DiversityResult<Self> {
+        let file = File::open(input_file)?;
+        Self::from_reader(BufReader::with_capacity(BUFFER_SIZE, file))
+    }
+
+    /// Reads a distance matrix from any buffered reader
+    ///
+    /// The first line holds the sample names after an ignored first cell, and
+    /// each other line a sample name followed by its distances, in the same order.
+    pub fn from_reader<R: BufRead>(reader: R) -> DiversityResult<Self> {
+        let mut lines = reader.lines();
+        let header = match lines.next() {
+            Some(line) => line?,
+            None => return Err(DiversityError::InvalidData("empty distance matrix".to_string())),
+        };
+        let samples: Vec<String> = header.split('\t').skip(1).map(|s| s.trim().to_string()).collect();
+
+        let mut distances = Vec::with_capacity(samples.len() * samples.len());
+        let mut rows = 0;
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if rows >= samples.len() || fields[0].trim() != samples[rows] {
+                return Err(DiversityError::InvalidData(format!(
+                    "row '{}' does not match the column order", fields[0].trim()
+                )));
+            }
+            if fields.len() != samples.len() + 1 {
+                return Err(DiversityError::InvalidData(format!(
+                    "row '{}' has {} distances instead of {}", samples[rows], fields.len() - 1, samples.len()
+                )));
+            }
+            for field in &fields[1..] {
+                let value = field.trim().parse::<f64>().map_err(|_| {
+                    DiversityError::InvalidData(format!("invalid distance '{}' in row '{}'", field, samples[rows]))
+                })?;
+                distances.push(value);
+            }
+            rows += 1;
+        }
+        if rows != samples.len() {
+            return Err(DiversityError::InvalidData(format!("{} rows for {} samples", rows, samples.len())));
+        }
+        Self::new(samples, distances)
+    }
+
+    /// Number of samples
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Whether the matrix has no samples
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Distance between the samples at two positions
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        self.distances[i * self.samples.len() + j]
+    }
+
+    /// Writes the matrix as a TSV file, with the sample names as the first row and column
+    ///
+    /// The layout is the one used by QIIME2 and scikit-bio (empty top-left cell).
+    pub fn write_tsv(&self, output_file: &str) -> DiversityResult<()> {
+        let file = File::create(output_file)?;
+        let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+        for sample in &self.samples {
+            write!(writer, "\t{}", sample)?;
+        }
+        writeln!(writer)?;
+        for (i, sample) in self.samples.iter().enumerate() {
+            write!(writer, "{}", sample)?;
+            for j in 0..self.samples.len() {
+                write!(writer, "\t{}", format_index(self.get(i, j)))?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Computes the distances between every pair of samples of a matrix
+///
+/// # Arguments
+/// * `matrix` - Abundance matrix; the "Unclassified", "Unassigned" and "Other" rows are ignored
+/// * `metric` - Distance metric
+/// * `tree` - Taxonomy tree, required by the UniFrac metrics
+///
+/// # Returns
+/// * `DiversityResult<DistanceMatrix>` - Distances between the samples, in `sample_names()` order
+///
+/// # Implementation Details
+/// Samples are turned into dense vectors over the taxa of the matrix (or over
+/// the nodes of the tree for UniFrac), and the pairs of samples are computed
+/// in parallel on the current rayon pool.
+pub fn beta_diversity(matrix: &AbundanceMatrix, metric: BetaMetric, tree: Option<&TaxonomyTree>) -> DiversityResult<DistanceMatrix> {
+    if let BetaMetric::Aitchison { pseudocount } = metric {
+        if pseudocount <= 0.0 {
+            return Err(DiversityError::InvalidData("the Aitchison pseudocount must be positive".to_string()));
+        }
+    }
+    let samples = matrix.sample_names();
+    let sample_values: Vec<Vec<(u32, f64)>> = samples.iter().map(|sample| matrix.taxon_values(sample)).collect();
+
+    // Position of each taxon in the dense vectors
+    let mut taxids: Vec<u32> = sample_values.iter().flatten().map(|&(taxid, _)| taxid).collect();
+    taxids.sort_unstable();
+    taxids.dedup();
+
+    let subtree = match (metric.needs_tree(), tree) {
+        (true, Some(tree)) => Some(tree.subtree(&taxids)),
+        (true, None) => return Err(DiversityError::InvalidData("UniFrac needs the taxonomy tree of the reports".to_string())),
+        (false, _) => None,
+    };
+
+    // Mean root-to-taxon distance of each sample, weighted by the abundance of its own taxa
+    let mut mean_depths = vec![0.0; samples.len()];
+    let vectors: Vec<Vec<f64>> = match subtree {
+        Some(ref subtree) => sample_values.iter().zip(mean_depths.iter_mut()).map(|(values, mean_depth)| {
+            // Relative abundance of each clade of the tree
+            let total: f64 = values.iter().map(|&(_, value)| value).sum();
+            let mut clades = vec![0.0; subtree.parents.len()];
+            if total > 0.0 {
+                for &(taxid, value) in values {
+                    let node = subtree.positions[&taxid];
+                    clades[node] += value / total;
+                    *mean_depth += subtree.depths[node] * value / total;
+                }
+            }
+            for node in 0..clades.len() {
+                if let Some(parent) = subtree.parents[node] {
+                    clades[parent] += clades[node];
+                }
+            }
+            clades
+        }).collect(),
+        None => {
+            let positions: HashMap<u32, usize> = taxids.iter().enumerate().map(|(i, &taxid)| (taxid, i)).collect();
+            sample_values.iter().map(|values| {
+                let mut dense = vec![0.0; taxids.len()];
+                for &(taxid, value) in values {
+                    dense[positions[&taxid]] = value;
+                }
+                match metric {
+                    BetaMetric::Aitchison { pseudocount } => clr(&dense, pseudocount),
+                    _ => dense,
+                }
+            }).collect()
+        }
+    };
+
+    let n = samples.len();
+    let pairs: Vec<(usize, usize)> = (0..n).flat_map(|i| (i + 1..n).map(move |j| (i, j))).collect();
+    let values: Vec<f64> = pairs.par_iter()
+        .map(|&(i, j)| {
+            let (a, b) = (&vectors[i], &vectors[j]);
+            match (metric, &subtree) {
+                (BetaMetric::BrayCurtis, _) => bray_curtis(a, b),
+                (BetaMetric::Jaccard, _) => jaccard(a, b),
+                (BetaMetric::Aitchison { .. }, _) => euclidean(a, b),
+                (BetaMetric::UnweightedUnifrac, Some(subtree)) => unweighted_unifrac(a, b, subtree),
+                (BetaMetric::WeightedUnifrac, Some(subtree)) => {
+                    weighted_unifrac(a, b, subtree) / (mean_depths[i] + mean_depths[j]).max(f64::MIN_POSITIVE)
+                }
+                (_, None) => unreachable!("the subtree is built for the UniFrac metrics"),
+            }
+        })
+        .collect();
+
+    let mut distances = vec![0.0; n * n];
+    for (&(i, j), value) in pairs.iter().zip(values) {
+        distances[i * n + j] = value;
+        distances[j * n + i] = value;
+    }
+    Ok(DistanceMatrix { samples, distances })
+}
+
+/// Centered log-ratio of a dense vector
+fn clr(values: &[f64], pseudocount: f64) -> Vec<f64> {
+    let logs: Vec<f64> = values.iter().map(|&value| (value + pseudocount).ln()).collect();
+    let mean = logs.iter().sum::<f64>() / logs.len().max(1) as f64;
+    logs.into_iter().map(|value| value - mean).collect()
+}
+
+/// Bray-Curtis dissimilarity (0 between two empty samples)
+fn bray_curtis(a: &[f64], b: &[f64]) -> f64 {
+    let (difference, sum) = a.iter().zip(b)
+        .fold((0.0, 0.0), |(difference, sum), (&x, &y)| (difference + (x - y).abs(), sum + x + y));
+    if sum > 0.0 { difference / sum } else { 0.0 }
+}
+
+/// Jaccard distance between the taxa present in each sample (0 between two empty samples)
+fn jaccard(a: &[f64], b: &[f64]) -> f64 {
+    let (shared, union) = a.iter().zip(b)
+        .fold((0usize, 0usize), |(shared, union), (&x, &y)| {
+            (shared + (x > 0.0 && y > 0.0) as usize, union + (x > 0.0 || y > 0.0) as usize)
+        });
+    if union > 0 { 1.0 - shared as f64 / union as f64 } else { 0.0 }
+}
+
+/// Euclidean distance
+fn euclidean(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(&x, &y)| (x - y) * (x - y)).sum::<f64>().sqrt()
+}
+
+/// Unweighted UniFrac from the clade abundances of two samples
+fn unweighted_unifrac(a: &[f64], b: &[f64], subtree: &Subtree) -> f64 {
+    let (mut unique, mut observed) = (0usize, 0usize);
+    for node in (0..a.len()).filter(|&node| subtree.parents[node].is_some()) {
+        let (x, y) = (a[node] > 0.0, b[node] > 0.0);
+        unique += (x != y) as usize;
+        observed += (x || y) as usize;
+    }
+    if observed > 0 { unique as f64 / observed as f64 } else { 0.0 }
+}
+
+/// Weighted UniFrac from the relative clade abundances of two samples, before normalization
+///
+/// Dividing by the mean root-to-taxon distances of both samples gives the
+/// normalized weighted UniFrac, between 0 and 1.
+fn weighted_unifrac(a: &[f64], b: &[f64], subtree: &Subtree) -> f64 {
+    (0..a.len())
+        .filter(|&node| subtree.parents[node].is_some())
+        .map(|node| (a[node] - b[node]).abs())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 1e-9;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < EPSILON, "{} != {}", actual, expected);
+    }
+
+    fn entry(rank: &str, taxid: u32, clade: u64, children: Vec<TaxonEntry>) -> TaxonEntry {
+        let direct = clade - children.iter().map(|c| c.clade_reads).sum::<u64>();
+        let mut entry = TaxonEntry::new(0.0, clade, direct, rank.to_string(), taxid, format!("taxon {}", taxid), 0);
+        entry.children = children;
+        entry
+    }
+
+    /// Report with two genera: 10 (species 100 and 101) and 11 (species 110)
+    fn report(species: [u64; 3]) -> KrakenReport {
+        let [s100, s101, s110] = species;
+        let mut genera = Vec::new();
+        let first: Vec<TaxonEntry> = [(100, s100), (101, s101)].iter()
+            .filter(|(_, reads)| *reads > 0)
+            .map(|&(taxid, reads)| entry("S", taxid, reads, vec![]))
+            .collect();
+        if !first.is_empty() {
+            genera.push(entry("G", 10, s100 + s101, first));
+        }
+        if s110 > 0 {
+            genera.push(entry("G", 11, s110, vec![entry("S", 110, s110, vec![])]));
+        }
+        let total = s100 + s101 + s110;
+        KrakenReport::new(entry("R", 1, total, vec![entry("D", 2, total, genera)]), None)
+    }
+
+    fn beta(samples: &[(&str, [u64; 3])], metric: &str) -> DistanceMatrix {
+        let mut matrix = AbundanceMatrix::new("S".parse().unwrap());
+        let mut tree = TaxonomyTree::default();
+        for (sample, species) in samples {
+            let report = report(*species);
+            matrix.add_sample(&report, sample, 0.0, false);
+            tree.merge(TaxonomyTree::from_report(&report));
+        }
+        beta_diversity(&matrix, metric.parse().unwrap(), Some(&tree)).unwrap()
+    }
+
+    #[test]
+    fn test_beta_diversity() {
+        let samples = [("a", [6, 7, 4]), ("b", [10, 0, 6])];
+        // |6 - 10| + |7 - 0| + |4 - 6| = 13 over 33 reads
+        assert_close(beta(&samples, "braycurtis").get(0, 1), 13.0 / 33.0);
+        // Two shared taxa out of three
+        assert_close(beta(&samples, "jaccard").get(1, 0), 1.0 / 3.0);
+        assert_close(beta(&samples, "aitchison").get(0, 1), 2.0213942031586467);
+        let distances = beta(&samples, "bray");
+        assert_eq!(distances.samples, vec!["a", "b"]);
+        assert_eq!(distances.get(0, 0), 0.0);
+        assert!("euclidean".parse::<BetaMetric>().is_err());
+
+        let path = std::env::temp_dir().join(format!("krakenclip_distances_{}.tsv", std::process::id()));
+        distances.write_tsv(path.to_str().unwrap()).unwrap();
+        let read = DistanceMatrix::read_tsv(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.samples, distances.samples);
+        assert!((read.get(1, 0) - distances.get(1, 0)).abs() < DISTANCE_TOLERANCE);
+    }
+
+    #[test]
+    fn test_invalid_distance_matrix() {
+        assert!(DistanceMatrix::from_reader("\ta\tb\na\t0\t1\nb\t2\t0\n".as_bytes()).is_err());
+        assert!(DistanceMatrix::from_reader("\ta\tb\nb\t0\t1\na\t1\t0\n".as_bytes()).is_err());
+        assert!(DistanceMatrix::from_reader("\ta\tb\na\t0\t1\n".as_bytes()).is_err());
+        assert!(DistanceMatrix::from_reader("\ta\tb\na\t0\tNA\nb\tNA\t0\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_unifrac() {
+        let samples = [("a", [1, 0, 0]), ("b", [0, 0, 1]), ("c", [1, 1, 0])];
+        // a and b only share the branch above the domain: 4 unique branches out of 5
+        let unweighted = beta(&samples, "unifrac");
+        assert_close(unweighted.get(0, 1), 0.8);
+        assert_close(unweighted.get(0, 2), 0.25);
+        // Every species is 3 branches below the root, so the normalization is 3 + 3
+        let weighted = beta(&samples, "weighted-unifrac");
+        assert_close(weighted.get(0, 1), 4.0 / 6.0);
+        assert_close(weighted.get(2, 0), 1.0 / 6.0);
+
+        let matrix = AbundanceMatrix::new("S".parse().unwrap());
+        assert!(beta_diversity(&matrix, BetaMetric::UnweightedUnifrac, None).is_err());
+    }
+
+    #[test]
+    fn test_even_community() {
+        // Four equally abundant taxa: H = ln(4), Simpson = 1 - 1/4, perfect evenness
+        let alpha = AlphaDiversity::from_counts(&[25.0, 25.0, 0.0, 25.0, 25.0]).unwrap();
+        assert_eq!(alpha.reads, 100);
+        assert_eq!(alpha.richness, 4);
+        assert_close(alpha.shannon, 4f64.ln());
+        assert_close(alpha.simpson, 0.75);
+        assert_close(alpha.inverse_simpson, 4.0);
+        assert_close(alpha.pielou, 1.0);
+        // No singletons and no rare taxa, so both estimators equal the observed richness
+        assert_close(alpha.chao1, 4.0);
+        assert_close(alpha.ace, 4.0);
+    }
+
+    #[test]
+    fn test_reference_values() {
+        // 3 singletons, 2 doubletons and 97 reads; 8 rare taxa (at most
+        // ACE_RARE_THRESHOLD reads) holding 23 reads. The expected values were
+        // computed independently in Python with the formulas of vegan's
+        // `diversity()` (Shannon, Simpson, inverse Simpson) and `estimateR()`
+        // (bias-corrected Chao1, ACE)
+        let counts = [1.0, 1.0, 1.0, 2.0, 2.0, 3.0, 5.0, 8.0, 13.0, 21.0, 40.0];
+        let alpha = AlphaDiversity::from_counts(&counts).unwrap();
+        assert_eq!(alpha.reads, 97);
+        assert_eq!(alpha.richness, 11);
+        assert_close(alpha.shannon, 1.73362192193016);
+        assert_close(alpha.simpson, 0.7535338505686046);
+        assert_close(alpha.inverse_simpson, 4.057352307028892);
+        assert_close(alpha.pielou, 0.7229764959280327);
+        assert_close(alpha.chao1, 12.0);
+        assert_close(alpha.ace, 14.144545454545456);
+    }
+
+    #[test]
+    fn test_undefined_indices() {
+        let single = AlphaDiversity::from_counts(&[7.0]).unwrap();
+        assert_close(single.shannon, 0.0);
+        assert!(single.pielou.is_nan());
+        let singletons = AlphaDiversity::from_counts(&[1.0, 1.0]).unwrap();
+        assert!(singletons.ace.is_nan());
+        let empty = AlphaDiversity::from_counts(&[]).unwrap();
+        assert_eq!(empty.richness, 0);
+        assert!(empty.shannon.is_nan());
+        assert!(AlphaDiversity::from_counts(&[0.5, 2.0]).is_err());
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::error::Error;
+use std::str::FromStr;
+use crate::krk_parser::{KrakenReport, TaxonEntry};
+
+/// Optimized buffer size for reading translation tables
+const BUFFER_SIZE: usize = 512 * 1024; // 512KB
+
+/// GTDB rank prefixes and the Kraken rank code each one corresponds to
+/// "k__" is not used by GTDB but appears in Greengenes-style lineages
+const GTDB_RANK_PREFIXES: &[(char, &str)] = &[
+    ('d', "D"),
+    ('k', "K"),
+    ('p', "P"),
+    ('c', "C"),
+    ('o', "O"),
+    ('f', "F"),
+    ('g', "G"),
+    ('s', "S"),
+];
+
+/// Taxids generated for lineage nodes without an explicit taxid have this bit set,
+/// which keeps them clear of NCBI taxids and of most GTDB taxdump identifiers
+const SYNTHETIC_TAXID_FLAG: u32 = 0x8000_0000;
+
+/// Specific errors for the GTDB translation module
+#[derive(Debug)]
+pub enum GtdbError {
+    IoError(std::io::Error),
+    InvalidData(String),
+}
+
+impl std::fmt::Display for GtdbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(e) => write!(f, "I/O error: {}", e),
+            Self::InvalidData(s) => write!(f, "Invalid translation table: {}", s),
+        }
+    }
+}
+
+impl Error for GtdbError {}
+
+impl From<std::io::Error> for GtdbError {
+    fn from(e: std::io::Error) -> Self {
+        Self::IoError(e)
+    }
+}
+
+/// Specialized result type for GTDB functions
+pub type GtdbResult<T> = Result<T, GtdbError>;
+
+/// Splits a GTDB-style name into its Kraken rank code and bare name
+///
+/// # Arguments
+/// * `name` - Taxon name, e.g. "g__Escherichia"
+///
+/// # Returns
+/// * `Option<(&str, &str)>` - Rank code and name without prefix, or None for non-GTDB names
+pub fn split_gtdb_name(name: &str) -> Option<(&'static str, &str)> {
+    let bytes = name.as_bytes();
+    if bytes.len() < 3 || bytes[1] != b'_' || bytes[2] != b'_' {
+        return None;
+    }
+    GTDB_RANK_PREFIXES.iter()
+        .find(|(prefix, _)| *prefix as u8 == bytes[0])
+        .map(|(_, code)| (*code, &name[3..]))
+}
+
+/// Checks whether a report was produced with a GTDB-based database
+///
+/// A report is considered GTDB-based when any taxon below the root carries a GTDB rank prefix.
+pub fn is_gtdb_report(report: &KrakenReport) -> bool {
+    fn has_gtdb_name(node: &TaxonEntry) -> bool {
+        split_gtdb_name(&node.name).is_some() || node.children.iter().any(has_gtdb_name)
+    }
+    report.root.children.iter().any(has_gtdb_name)
+}
+
+/// Target taxonomy of a translation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranslationDirection {
+    /// Re-express NCBI-based reports in GTDB lineage
+    NcbiToGtdb,
+    /// Re-express GTDB-based reports in NCBI lineage
+    GtdbToNcbi,
+}
+
+impl FromStr for TranslationDirection {
+    type Err = GtdbError;
+
+    /// Parses the target taxonomy name ("gtdb" or "ncbi")
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gtdb" => Ok(Self::NcbiToGtdb),
+            "ncbi" => Ok(Self::GtdbToNcbi),
+            _ => Err(GtdbError::InvalidData(format!("unknown target taxonomy '{}', use 'gtdb' or 'ncbi'", s))),
+        }
+    }
+}
+
+impl TranslationDirection {
+    /// Columns holding the source taxid, the target taxid and the target lineage
+    fn columns(&self) -> (&'static str, &'static str, &'static str) {
+        match self {
+            Self::NcbiToGtdb => ("ncbi_taxid", "gtdb_taxid", "gtdb_taxonomy"),
+            Self::GtdbToNcbi => ("gtdb_taxid", "ncbi_taxid", "ncbi_taxonomy"),
+        }
+    }
+}
+
+/// Lineage that a source taxid translates to
+#[derive(Debug, Clone, PartialEq)]
+struct LineageTarget {
+    /// Prefixed lineage names from the top rank down (e.g. ["d__Bacteria", "p__Pseudomonadota"])
+    lineage: Vec<String>,
+    /// Taxid of the deepest lineage node, if known
+    taxid: Option<u32>,
+}
+
+/// Translation table between NCBI and GTDB taxonomies
+///
+/// The table is read from a tab-separated metadata file with a header line, such as the
+/// GTDB `bac120_metadata.tsv`/`ar53_metadata.tsv` files. The columns used are:
+/// * `ncbi_taxid` and `gtdb_taxid` - Taxids in each database
+/// * `gtdb_taxonomy` and `ncbi_taxonomy` - Lineages as `d__;p__;c__;o__;f__;g__;s__` strings
+///
+/// Only the source taxid and target lineage columns of the chosen direction are required.
+/// When several rows share a source taxid, the lineage is truncated to the ranks all rows agree on.
+pub struct TranslationTable {
+    direction: TranslationDirection,
+    targets: HashMap<u32, LineageTarget>,
+    /// Known target taxids by lineage path (e.g. "d__Bacteria;p__Pseudomonadota")
+    lineage_taxids: HashMap<String, u32>,
+}
+
+impl TranslationTable {
+    /// Loads a translation table from a TSV file
+    ///
+    /// # Arguments
+    /// * `path` - Path to the metadata TSV
+    /// * `direction` - Target taxonomy of the translation
+    pub fn from_tsv(path: &str, direction: TranslationDirection) -> GtdbResult<Self> {
+        let file = File::open(path)?;
+        Self::from_reader(BufReader::with_capacity(BUFFER_SIZE, file), direction)
+    }
+
+    /// Loads a translation table from any buffered reader
+    pub fn from_reader<R: BufRead>(reader: R, direction: TranslationDirection) -> GtdbResult<Self> {
+        let (source_column, target_column, lineage_column) = direction.columns();
+        let mut lines = reader.lines();
+
+        // Locate the columns from the header line
+        let header = match lines.next() {
+            Some(line) => line?,
+            None => return Err(GtdbError::InvalidData("empty file".to_string())),
+        };
+        let find_column = |name: &str| header.split('\t').position(|c| c.trim() == name);
+        let source_idx = find_column(source_column)
+            .ok_or_else(|| GtdbError::InvalidData(format!("missing column '{}'", source_column)))?;
+        let lineage_idx = find_column(lineage_column)
+            .ok_or_else(|| GtdbError::InvalidData(format!("missing column '{}'", lineage_column)))?;
+        let target_idx = find_column(target_column);
+
+        let mut table = Self {
+            direction,
+            targets: HashMap::new(),
+            lineage_taxids: HashMap::new(),
+        };
+
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let line_number = i + 2;
+
+            let source_taxid = fields.get(source_idx)
+                .and_then(|s| s.trim().parse::<u32>().ok())
+                .ok_or_else(|| GtdbError::InvalidData(format!("invalid {} at line {}", source_column, line_number)))?;
+            let lineage = parse_lineage(fields.get(lineage_idx).copied().unwrap_or(""));
+            if lineage.is_empty() {
+                continue;
+            }
+            let target_taxid = target_idx
+                .and_then(|idx| fields.get(idx))
+                .and_then(|s| s.trim().parse::<u32>().ok());
+
+            if let Some(taxid) = target_taxid {
+                table.lineage_taxids.insert(lineage.join(";"), taxid);
+            }
+            table.insert(source_taxid, LineageTarget { lineage, taxid: target_taxid });
+        }
+
+        Ok(table)
+    }
+
+    /// Adds a mapping, resolving conflicting rows for the same source taxid
+    fn insert(&mut self, source_taxid: u32, target: LineageTarget) {
+        match self.targets.get_mut(&source_taxid) {
+            Some(existing) if *existing != target => {
+                // Keep only the ranks on which all rows agree
+                let shared = existing.lineage.iter()
+                    .zip(&target.lineage)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if shared < existing.lineage.len() || existing.taxid != target.taxid {
+                    existing.lineage.truncate(shared);
+                    existing.taxid = None;
+                }
+            }
+            Some(_) => {}
+            None => {
+                self.targets.insert(source_taxid, target);
+            }
+        }
+    }
+
+    /// Number of source taxids in the table
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    /// Whether the table contains no mappings
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Checks whether a report is still in the source taxonomy of this table
+    pub fn needs_translation(&self, report: &KrakenReport) -> bool {
+        match self.direction {
+            TranslationDirection::NcbiToGtdb => !is_gtdb_report(report),
+            TranslationDirection::GtdbToNcbi => is_gtdb_report(report),
+        }
+    }
+}
+
+/// Parses a `d__X;p__Y;...` lineage string, stopping at the first empty rank (e.g. "s__")
+fn parse_lineage(lineage: &str) -> Vec<String> {
+    lineage.split(';')
+        .map(|s| s.trim())
+        .take_while(|s| split_gtdb_name(s).map(|(_, name)| !name.is_empty()).unwrap_or(false))
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Stable taxid for a lineage node with no taxid in the table (FNV-1a of the lineage path)
+fn synthetic_taxid(lineage_path: &str) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in lineage_path.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash | SYNTHETIC_TAXID_FLAG
+}
+
+/// Node of the translated tree while reads are being redistributed
+struct TargetNode {
+    name: String,
+    rank: String,
+    taxid: u32,
+    direct_reads: u64,
+    children: Vec<usize>,
+}
+
+/// Translated tree stored as an arena, with lookup by (parent, name)
+struct TargetTree {
+    nodes: Vec<TargetNode>,
+    index: HashMap<(usize, String), usize>,
+}
+
+impl TargetTree {
+    fn new() -> Self {
+        Self {
+            nodes: vec![TargetNode {
+                name: "root".to_string(),
+                rank: "R".to_string(),
+                taxid: 1,
+                direct_reads: 0,
+                children: Vec::new(),
+            }],
+            index: HashMap::new(),
+        }
+    }
+
+    /// Adds reads to the node at the end of a lineage, creating the path as needed
+    fn add_reads(&mut self, target: Option<&LineageTarget>, table: &TranslationTable, reads: u64) {
+        let mut current = 0;
+        if let Some(target) = target {
+            for depth in 0..target.lineage.len() {
+                let prefixed = &target.lineage[depth];
+                current = match self.index.get(&(current, prefixed.clone())) {
+                    Some(&idx) => idx,
+                    None => {
+                        let path = target.lineage[..=depth].join(";");
+                        let taxid = if depth + 1 == target.lineage.len() {
+                            target.taxid
+                        } else {
+                            None
+                        }
+                        .or_else(|| table.lineage_taxids.get(&path).copied())
+                        .unwrap_or_else(|| synthetic_taxid(&path));
+                        let (rank, bare_name) = split_gtdb_name(prefixed).unwrap_or(("-", prefixed));
+
+                        // GTDB reports keep the prefixed names, NCBI reports use bare names
+                        let name = match table.direction {
+                            TranslationDirection::NcbiToGtdb => prefixed.clone(),
+                            TranslationDirection::GtdbToNcbi => bare_name.to_string(),
+                        };
+
+                        let idx = self.nodes.len();
+                        self.nodes.push(TargetNode {
+                            name,
+                            rank: rank.to_string(),
+                            taxid,
+                            direct_reads: 0,
+                            children: Vec::new(),
+                        });
+                        self.nodes[current].children.push(idx);
+                        self.index.insert((current, prefixed.clone()), idx);
+                        idx
+                    }
+                };
+            }
+        }
+        self.nodes[current].direct_reads += reads;
+    }
+
+    /// Converts an arena node and its descendants into a taxonomy tree
+    fn to_entry(&self, idx: usize, depth: usize, total_reads: f64) -> TaxonEntry {
+        let node = &self.nodes[idx];
+        let mut children: Vec<TaxonEntry> = node.children.iter()
+            .map(|&child| self.to_entry(child, depth + 1, total_reads))
+            .collect();
+
+        // Kraken lists siblings by decreasing clade size
+        children.sort_by(|a, b| b.clade_reads.cmp(&a.clade_reads).then_with(|| a.name.cmp(&b.name)));
+
+        let clade_reads = node.direct_reads + children.iter().map(|c| c.clade_reads).sum::<u64>();
+        let percentage = if total_reads > 0.0 {
+            (clade_reads as f64 / total_reads * 100.0) as f32
+        } else {
+            0.0
+        };
+
+        let mut entry = TaxonEntry::new(percentage, clade_reads, node.direct_reads, node.rank.clone(), node.taxid, node.name.clone(), depth);
+        entry.children = children;
+        entry
+    }
+}
+
+/// Re-expresses a report in the target taxonomy of a translation table
+///
+/// # Arguments
+/// * `report` - Parsed Kraken2 report in the source taxonomy
+/// * `table` - Translation table
+///
+/// # Returns
+/// * `KrakenReport` - New report with the same reads distributed over the target lineage
+///
+/// # Implementation Details
+/// The direct reads of every taxon are moved to the lineage of its taxid in the table. Taxa
+/// missing from the table use the lineage of their closest translated ancestor, and reads with
+/// no translated ancestor stay at the root. Clade reads and percentages are then recomputed, so
+/// the total number of classified reads is preserved.
+pub fn translate_report(report: &KrakenReport, table: &TranslationTable) -> KrakenReport {
+    fn redistribute<'a>(node: &TaxonEntry, inherited: Option<&'a LineageTarget>, table: &'a TranslationTable, tree: &mut TargetTree) {
+        let target = table.targets.get(&node.taxid).or(inherited);
+        if node.direct_reads > 0 {
+            tree.add_reads(target, table, node.direct_reads);
+        }
+        for child in &node.children {
+            redistribute(child, target, table, tree);
+        }
+    }
+
+    let mut tree = TargetTree::new();
+    redistribute(&report.root, None, table, &mut tree);
+
+    let unclassified_reads = report.unclassified.as_ref().map(|u| u.clade_reads).unwrap_or(0);
+    let total_reads = (report.root.clade_reads + unclassified_reads) as f64;
+
+    let root = tree.to_entry(0, 0, total_reads);
+    let unclassified = report.unclassified.clone();
+    KrakenReport::new(root, unclassified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rank::Rank;
+
+    const TABLE: &str = "accession\tgtdb_taxid\tgtdb_taxonomy\tncbi_taxid\tncbi_taxonomy\n\
+        GB_1\t100\td__Bacteria;p__Pseudomonadota;c__Gammaproteobacteria;o__Enterobacterales;f__Enterobacteriaceae;g__Escherichia;s__Escherichia coli\t562\td__Bacteria;p__Proteobacteria;c__Gammaproteobacteria;o__Enterobacterales;f__Enterobacteriaceae;g__Escherichia;s__Escherichia coli\n\
+        GB_2\t101\td__Bacteria;p__Pseudomonadota;c__Gammaproteobacteria;o__Enterobacterales;f__Enterobacteriaceae;g__Escherichia;s__Escherichia flexneri\t623\td__Bacteria;p__Proteobacteria;c__Gammaproteobacteria;o__Enterobacterales;f__Enterobacteriaceae;g__Shigella;s__Shigella flexneri\n\
+        GB_3\t102\td__Bacteria;p__Pseudomonadota;c__Gammaproteobacteria;o__Enterobacterales;f__Enterobacteriaceae;g__Escherichia;s__\t561\td__Bacteria;p__Proteobacteria;c__Gammaproteobacteria;o__Enterobacterales;f__Enterobacteriaceae;g__Escherichia;s__\n";
+
+    fn entry(rank: &str, taxid: u32, name: &str, clade: u64, direct: u64, children: Vec<TaxonEntry>) -> TaxonEntry {
+        let mut entry = TaxonEntry::new(0.0, clade, direct, rank.to_string(), taxid, name.to_string(), 0);
+        entry.children = children;
+        entry
+    }
+
+    fn ncbi_report() -> KrakenReport {
+        let shigella = entry("G", 620, "Shigella", 30, 0, vec![entry("S", 623, "Shigella flexneri", 30, 30, vec![])]);
+        let escherichia = entry("G", 561, "Escherichia", 60, 10, vec![entry("S", 562, "Escherichia coli", 50, 50, vec![])]);
+        let family = entry("F", 543, "Enterobacteriaceae", 90, 0, vec![escherichia, shigella]);
+        let root = entry("R", 1, "root", 100, 10, vec![family]);
+        KrakenReport::new(root, Some(entry("U", 0, "unclassified", 100, 100, vec![])))
+    }
+
+    fn find<'a>(node: &'a TaxonEntry, name: &str) -> Option<&'a TaxonEntry> {
+        if node.name == name {
+            return Some(node);
+        }
+        node.children.iter().find_map(|c| find(c, name))
+    }
+
+    #[test]
+    fn test_split_gtdb_name() {
+        assert_eq!(split_gtdb_name("g__Escherichia"), Some(("G", "Escherichia")));
+        assert_eq!(split_gtdb_name("d__Archaea"), Some(("D", "Archaea")));
+        assert_eq!(split_gtdb_name("Escherichia"), None);
+        assert_eq!(split_gtdb_name("x__Escherichia"), None);
+    }
+
+    #[test]
+    fn test_translate_ncbi_to_gtdb() {
+        let table = TranslationTable::from_reader(TABLE.as_bytes(), TranslationDirection::NcbiToGtdb).unwrap();
+        assert_eq!(table.len(), 3);
+
+        let report = ncbi_report();
+        assert!(table.needs_translation(&report));
+        let translated = translate_report(&report, &table);
+        assert!(is_gtdb_report(&translated));
+
+        // Shigella flexneri is an Escherichia species in GTDB
+        let genus = find(&translated.root, "g__Escherichia").unwrap();
+        assert_eq!(genus.clade_reads, 90);
+        assert_eq!(genus.direct_reads, 10);
+        assert_eq!(genus.taxid, 102);
+        assert_eq!(genus.percentage, 45.0);
+        assert_eq!(find(&translated.root, "s__Escherichia flexneri").unwrap().taxid, 101);
+
+        // Unmapped reads stay at the root and totals are preserved
+        assert_eq!(translated.root.direct_reads, 10);
+        assert_eq!(translated.root.clade_reads, 100);
+        assert!(translated.taxon_map.contains_key(&100));
+    }
+
+    #[test]
+    fn test_translate_gtdb_to_ncbi() {
+        let table = TranslationTable::from_reader(TABLE.as_bytes(), TranslationDirection::GtdbToNcbi).unwrap();
+        let species = entry("S", 101, "s__Escherichia flexneri", 40, 40, vec![]);
+        let genus = entry("G", 102, "g__Escherichia", 40, 0, vec![species]);
+        let report = KrakenReport::new(entry("R", 1, "root", 40, 0, vec![genus]), None);
+
+        let translated = translate_report(&report, &table);
+        assert!(!is_gtdb_report(&translated));
+        let shigella = find(&translated.root, "Shigella flexneri").unwrap();
+        assert_eq!(shigella.taxid, 623);
+        assert_eq!(shigella.clade_reads, 40);
+        assert_eq!(find(&translated.root, "Shigella").unwrap().rank, Rank::Genus(0));
+    }
+
+    #[test]
+    fn test_conflicting_rows_keep_shared_lineage() {
+        let table = "ncbi_taxid\tgtdb_taxonomy\n\
+            1\td__Bacteria;p__A;c__B\n\
+            1\td__Bacteria;p__A;c__C\n";
+        let table = TranslationTable::from_reader(table.as_bytes(), TranslationDirection::NcbiToGtdb).unwrap();
+        assert_eq!(table.targets[&1].lineage, vec!["d__Bacteria", "p__A"]);
+        assert_eq!(table.targets[&1].taxid, None);
+    }
+
+    #[test]
+    fn test_missing_column() {
+        let result = TranslationTable::from_reader("ncbi_taxid\tother\n".as_bytes(), TranslationDirection::NcbiToGtdb);
+        assert!(matches!(result, Err(GtdbError::InvalidData(_))));
+    }
+}
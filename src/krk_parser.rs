@@ -2,9 +2,10 @@ use std::fs::File;
 use std::io::Read;
 use std::time::Instant;
 use memchr::memchr_iter;
-use fast_float;
 use std::io::Write;
 use std::path::Path;
+use crate::gtdb;
+use crate::rank::Rank;
 
 // Optimized constants for performance-critical operations
 // Buffer size is carefully chosen for optimal memory usage vs. throughput
@@ -16,12 +17,12 @@ const NEWLINE_CHAR: u8 = b'\n';
 
 /// Structure representing a node in the taxonomic tree
 /// Each node contains comprehensive information about a specific taxon
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TaxonEntry {
     pub percentage: f32,   // Percentage of reads in the sample assigned to this clade
     pub clade_reads: u64,  // Total reads assigned to this clade and its descendants
     pub direct_reads: u64, // Reads assigned directly to this taxon (not descendants)
-    pub rank: String,      // Taxonomic rank (e.g., "P" for phylum, "G" for genus)
+    pub rank: Rank,        // Taxonomic rank parsed from the rank code or GTDB name prefix (e.g., Phylum, Species(1))
     pub taxid: u32,        // NCBI Taxonomy identifier as u32 for memory efficiency
     pub name: String,      // Scientific name of the taxon
     #[allow(dead_code)]
@@ -33,7 +34,7 @@ pub struct TaxonEntry {
     pub taxon_id: u64,     // Taxon ID as u64 (for compatibility with external systems)
     pub clade_fragments: u64, // Alias for clade_reads (terminology varies by tool)
     pub direct_fragments: u64, // Alias for direct_reads (terminology varies by tool)
-    pub rank_code: String, // Alias for rank (maintained for compatibility)
+    pub rank_code: String, // Rank code exactly as written in the report (e.g., "P", "S1")
 }
 
 /// Main structure for representing a complete Kraken report
@@ -47,12 +48,12 @@ pub struct KrakenReport {
 
 impl TaxonEntry {
     // Create a new empty taxonomy node
-    fn new(percentage: f32, clade_reads: u64, direct_reads: u64, rank: String, taxid: u32, name: String, depth: usize) -> Self {
+    pub fn new(percentage: f32, clade_reads: u64, direct_reads: u64, rank: String, taxid: u32, name: String, depth: usize) -> Self {
         Self {
             percentage,
             clade_reads,
             direct_reads,
-            rank: rank.clone(), // Clone is needed as we use the value twice
+            rank: Rank::from_code(&rank),
             taxid,
             name,
             depth,
@@ -72,6 +73,39 @@ impl TaxonEntry {
     fn add_child(&mut self, child: TaxonEntry) {
         self.children.push(child);
     }
+
+    /// Scientific name without a GTDB rank prefix (e.g. "g__Escherichia" -> "Escherichia")
+    /// Names from NCBI-based databases are returned unchanged
+    pub fn scientific_name(&self) -> &str {
+        gtdb::split_gtdb_name(&self.name)
+            .map(|(_, name)| name)
+            .unwrap_or(&self.name)
+    }
+
+    /// Lineage element for this taxon in `d__;p__;...` notation (e.g. "g__Escherichia")
+    /// Only standard ranks from domain to species are part of a lineage
+    pub fn lineage_label(&self) -> Option<String> {
+        match self.rank.letter() {
+            Some(letter) if letter != 'R' && self.rank.depth() == 0 => {
+                Some(format!("{}__{}", letter.to_ascii_lowercase(), self.scientific_name()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl KrakenReport {
+    /// Build a report from an already assembled tree
+    /// The taxid lookup map is rebuilt from the given root
+    pub fn new(root: TaxonEntry, unclassified: Option<TaxonEntry>) -> Self {
+        let mut taxon_map = std::collections::HashMap::new();
+        build_taxon_map(&root, &mut taxon_map, 0);
+        Self {
+            root,
+            taxon_map,
+            unclassified,
+        }
+    }
 }
 
 impl Default for KrakenReport {
@@ -90,8 +124,9 @@ impl Default for KrakenReport {
 
 /// String cache to avoid memory duplication for common rank codes
 /// This significantly reduces memory usage for large reports
+/// Each code is stored with its parsed rank so that codes are only parsed once
 pub struct StringCache {
-    rank_codes: Vec<String>,
+    rank_codes: Vec<(String, Rank)>,
 }
 
 impl StringCache {
@@ -101,10 +136,11 @@ impl StringCache {
     /// Pre-populating with common values avoids allocations for frequent codes
     fn new() -> Self {
         // Pre-populate with common rank codes used in taxonomy
-        // D=Domain, P=Phylum, C=Class, O=Order, F=Family, G=Genus, S=Species, U=Unclassified
-        let mut rank_codes = Vec::with_capacity(10);
-        for code in ["D", "P", "C", "O", "F", "G", "S", "U"].iter() {
-            rank_codes.push((*code).to_string());
+        // D=Domain, P=Phylum, C=Class, O=Order, F=Family, G=Genus, S=Species, U=Unclassified,
+        // R=Root, K=Kingdom and S1=the first sub-level below species (strains)
+        let mut rank_codes = Vec::with_capacity(16);
+        for code in ["S", "G", "F", "O", "C", "P", "D", "U", "R", "K", "S1"].iter() {
+            rank_codes.push(((*code).to_string(), Rank::from_code(code)));
         }
         Self { rank_codes }
     }
@@ -115,22 +151,23 @@ impl StringCache {
     /// * `code` - The rank code to retrieve or add
     /// 
     /// # Returns
-    /// A clone of the cached string (reduces total allocations)
+    /// A clone of the cached string (reduces total allocations) and its parsed rank
     /// 
     /// # Performance characteristics
     /// Uses linear search which is efficient for small collections like this
     /// For the small set of rank codes, this outperforms a HashMap due to lower overhead
-    fn get_rank_code(&mut self, code: &str) -> String {
+    fn get_rank_code(&mut self, code: &str) -> (String, Rank) {
         // First check if code exists in cache to avoid allocation
-        for existing in &self.rank_codes {
+        for (existing, rank) in &self.rank_codes {
             if existing == code {
-                return existing.clone();
+                return (existing.clone(), *rank);
             }
         }
         // If not in cache, add it
         let code_string = code.to_string();
-        self.rank_codes.push(code_string.clone());
-        code_string
+        let rank = Rank::from_code(code);
+        self.rank_codes.push((code_string.clone(), rank));
+        (code_string, rank)
     }
 }
 
@@ -183,7 +220,7 @@ pub fn parse_line(line: &[u8], string_cache: &mut StringCache, line_number: Opti
     // Using fast_float for improved float parsing performance
     let percentage_bytes = &line[field_starts[0]..field_ends[0]];
     let percentage = match std::str::from_utf8(percentage_bytes) {
-        Ok(s) => fast_float::parse::<f32, _>(s).unwrap_or_else(|_| {
+        Ok(s) => fast_float::parse::<f32, _>(s.trim()).unwrap_or_else(|_| {
             if let Some(line_num) = line_number {
                 eprintln!("Warning: Failed to parse percentage value '{}' at line {}", s, line_num);
             }
@@ -242,7 +279,16 @@ pub fn parse_line(line: &[u8], string_cache: &mut StringCache, line_number: Opti
             ""
         }
     };
-    let rank_code = string_cache.get_rank_code(rank_str);
+    let (rank_code, mut rank) = string_cache.get_rank_code(rank_str);
+    // GTDB-based databases encode the rank as a name prefix (d__, p__, ...)
+    // Kraken2 may report these ranks with an intermediate code (e.g. "R1" for d__),
+    // so the prefix gives the rank whenever the reported code is not a plain rank letter.
+    // The code itself is kept as written so that the report can be written back unchanged
+    if let Some((gtdb_rank, _)) = gtdb::split_gtdb_name(name) {
+        if rank_str.len() != 1 || rank_str == "-" {
+            rank = Rank::from_code(gtdb_rank);
+        }
+    }
     
     // Parse taxon ID
     let taxon_bytes = &line[field_starts[4]..field_ends[4]];
@@ -267,7 +313,7 @@ pub fn parse_line(line: &[u8], string_cache: &mut StringCache, line_number: Opti
         percentage,
         clade_fragments,
         direct_fragments,
-        rank_code: rank_code.clone(),
+        rank_code,
         taxid: taxon_id as u32,
         name: name.to_string(),
         depth: level,
@@ -275,7 +321,7 @@ pub fn parse_line(line: &[u8], string_cache: &mut StringCache, line_number: Opti
         taxon_id,
         clade_reads: clade_fragments,
         direct_reads: direct_fragments,
-        rank: rank_code,
+        rank,
     })
 }
 
@@ -459,19 +505,14 @@ pub fn parse_kraken2_report(file_path: &str) -> Result<(KrakenReport, f64), std:
         TaxonEntry::new(0.0, 0, 0, "R".to_string(), 1, "root".to_string(), 0)
     };
     
-    // Build a map from taxon IDs to indices for fast lookups
-    let mut taxon_map = std::collections::HashMap::new();
-    build_taxon_map(&root, &mut taxon_map, 0);
+    // Build the report, including the map from taxon IDs to indices for fast lookups
+    let report = KrakenReport::new(root, unclassified);
     
     // Calculate total parsing time
     let duration = start_time.elapsed().as_secs_f64();
     
     // Return the constructed report and parsing duration
-    Ok((KrakenReport {
-        unclassified,
-        root,
-        taxon_map,
-    }, duration))
+    Ok((report, duration))
 }
 
 // Build taxon map for quick access by ID
@@ -497,7 +538,7 @@ pub fn write_json_report(report: &KrakenReport, output_path: &str) -> std::io::R
         let mut json = serde_json::json!({
             "name": node.name,
             "taxid": node.taxid,
-            "rank": node.rank,
+            "rank": node.rank_code,
             "percentage": node.percentage,
             "clade_reads": node.clade_reads,
             "direct_reads": node.direct_reads,
@@ -533,6 +574,53 @@ pub fn write_json_report(report: &KrakenReport, output_path: &str) -> std::io::R
     Ok(())
 }
 
+/// Writes a report in the tab-separated Kraken2 layout
+///
+/// # Arguments
+/// * `report` - Report to write
+/// * `output_path` - Path to the output file
+///
+/// # Implementation Details
+/// Lines follow Kraken2's own `%6.2f` percentage precision, with two spaces of
+/// name indentation per level below the root and the unclassified line first.
+/// Rank codes are written as read (e.g. "R1" for GTDB domains), so parsing
+/// the written file gives back the same tree.
+pub fn write_kraken_report(report: &KrakenReport, output_path: &str) -> std::io::Result<()> {
+    fn write_subtree<W: Write>(writer: &mut W, entry: &TaxonEntry, depth: usize) -> std::io::Result<()> {
+        write_report_line(writer, entry, depth, &[])?;
+        for child in &entry.children {
+            write_subtree(writer, child, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    let file = File::create(Path::new(output_path))?;
+    let mut writer = std::io::BufWriter::with_capacity(BUFFER_SIZE, file);
+    if let Some(ref unclassified) = report.unclassified {
+        write_subtree(&mut writer, unclassified, 0)?;
+    }
+    write_subtree(&mut writer, &report.root, 0)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Writes the line of a taxon in the Kraken2 report layout
+///
+/// # Arguments
+/// * `writer` - Destination of the line
+/// * `entry` - Taxon of the line (its children are not written)
+/// * `depth` - Number of ancestors, written as two spaces each before the name
+/// * `extra_columns` - Counts written between the direct reads and the rank code
+///   (e.g. per-input reads of combined reports), empty for a standard line
+pub fn write_report_line<W: Write>(writer: &mut W, entry: &TaxonEntry, depth: usize, extra_columns: &[u64]) -> std::io::Result<()> {
+    write!(writer, "{:6.2}\t{}\t{}", entry.percentage, entry.clade_reads, entry.direct_reads)?;
+    for value in extra_columns {
+        write!(writer, "\t{}", value)?;
+    }
+    writeln!(writer, "\t{}\t{}\t{}{}", entry.rank_code, entry.taxid, "  ".repeat(depth), entry.name)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -549,10 +637,79 @@ mod tests {
         assert_eq!(entry.clade_fragments, 1000);
         assert_eq!(entry.direct_fragments, 500);
         assert_eq!(entry.rank_code, "P");
+        assert_eq!(entry.rank, Rank::Phylum(0));
         assert_eq!(entry.taxid, 123);
         assert_eq!(entry.name, "Bacteria");
     }
+
+    #[test]
+    fn test_parse_line_gtdb_prefix() {
+        let mut cache = StringCache::new();
+        let entry = parse_line(b"90.00\t900\t0\tR1\t2\t  d__Bacteria", &mut cache, None).unwrap();
+        assert_eq!(entry.rank, Rank::Domain(0));
+        assert_eq!(entry.rank_code, "R1");
+        assert_eq!(entry.name, "d__Bacteria");
+        assert_eq!(entry.scientific_name(), "Bacteria");
+
+        // A plain rank code reported by Kraken2 is kept as is
+        let entry = parse_line(b"10.00\t100\t100\tS\t7\t      s__Escherichia coli", &mut cache, None).unwrap();
+        assert_eq!(entry.rank, Rank::Species(0));
+        assert_eq!(entry.scientific_name(), "Escherichia coli");
+    }
     
+    #[test]
+    fn test_write_kraken_report_round_trip() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("krakenclip_roundtrip_in_{}.kreport", std::process::id()));
+        let output = dir.join(format!("krakenclip_roundtrip_out_{}.kreport", std::process::id()));
+        let text = concat!(
+            " 10.00\t10\t10\tU\t0\tunclassified\n",
+            " 90.00\t90\t5\tR\t1\troot\n",
+            " 85.00\t85\t15\tD\t2\t  Bacteria\n",
+            " 40.00\t40\t10\tG\t561\t    Escherichia\n",
+            " 30.00\t30\t25\tS\t562\t      Escherichia coli\n",
+            "  5.00\t5\t5\tS1\t83333\t        Escherichia coli K-12\n",
+            " 30.00\t30\t30\tS\t623\t    Shigella flexneri\n",
+            "  0.00\t0\t0\tG\t590\t    Salmonella\n",
+        );
+        std::fs::write(&input, text).unwrap();
+
+        let (report, _) = parse_kraken2_report(input.to_str().unwrap()).unwrap();
+        write_kraken_report(&report, output.to_str().unwrap()).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), text);
+        let (reparsed, _) = parse_kraken2_report(output.to_str().unwrap()).unwrap();
+        assert_eq!(reparsed.root, report.root);
+        assert_eq!(reparsed.unclassified, report.unclassified);
+        assert_eq!(reparsed.taxon_map, report.taxon_map);
+
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn test_write_kraken_report_gtdb_round_trip() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("krakenclip_gtdb_roundtrip_in_{}.kreport", std::process::id()));
+        let output = dir.join(format!("krakenclip_gtdb_roundtrip_out_{}.kreport", std::process::id()));
+        // GTDB databases report the domains with an intermediate root code
+        let text = concat!(
+            "  5.00\t5\t5\tU\t0\tunclassified\n",
+            " 95.00\t95\t0\tR\t1\troot\n",
+            " 95.00\t95\t5\tR1\t2\t  d__Bacteria\n",
+            " 90.00\t90\t10\tP\t3\t    p__Pseudomonadota\n",
+            " 80.00\t80\t80\tS\t4\t      s__Escherichia coli\n",
+        );
+        std::fs::write(&input, text).unwrap();
+
+        let (report, _) = parse_kraken2_report(input.to_str().unwrap()).unwrap();
+        assert_eq!(report.root.children[0].rank, Rank::Domain(0));
+        write_kraken_report(&report, output.to_str().unwrap()).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), text);
+
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+    }
+
     #[test]
     fn test_build_hierarchy() {
         let entries = vec![
@@ -570,7 +727,7 @@ mod tests {
                 taxon_id: 1,
                 clade_reads: 1000,
                 direct_reads: 0,
-                rank: "D".to_string(),
+                rank: Rank::from_code("D"),
             },
             TaxonEntry {
                 level: 1,
@@ -586,7 +743,7 @@ mod tests {
                 taxon_id: 2,
                 clade_reads: 800,
                 direct_reads: 200,
-                rank: "P".to_string(),
+                rank: Rank::from_code("P"),
             },
             TaxonEntry {
                 level: 2,
@@ -602,7 +759,7 @@ mod tests {
                 taxon_id: 3,
                 clade_reads: 600,
                 direct_reads: 100,
-                rank: "C".to_string(),
+                rank: Rank::from_code("C"),
             },
         ];
         
@@ -3,4 +3,15 @@ pub mod taxon_query;
 pub mod logkrk_parser;
 pub mod sequence_processor;
 pub mod generate_test_data;
-pub mod abundance_matrix;
\ No newline at end of file
+pub mod abundance_matrix;
+pub mod gtdb;
+pub mod rank;
+pub mod sample_metadata;
+pub mod normalization;
+pub mod diversity;
+pub mod ordination;
+pub mod stats;
+pub mod differential;
+pub mod report_diff;
+pub mod combine_reports;
+pub mod report_transform;
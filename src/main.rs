@@ -6,6 +6,17 @@ mod generate_test_data;
 mod abundance_matrix;
 mod cli;
 mod biom;
+mod gtdb;
+mod rank;
+mod sample_metadata;
+mod normalization;
+mod diversity;
+mod ordination;
+mod stats;
+mod differential;
+mod report_diff;
+mod combine_reports;
+mod report_transform;
 
 fn main() {
     println!("KrakenClip - High-performance Kraken2 processing toolkit");
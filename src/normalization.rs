@@ -0,0 +1,264 @@
+use std::fmt;
+use std::str::FromStr;
+use rand::rngs::StdRng;
+use rand::seq::index;
+use crate::abundance_matrix::{AbundanceMatrixError, AbundanceResult};
+
+/// Default pseudocount added before taking logarithms in the CLR transform
+pub const DEFAULT_PSEUDOCOUNT: f64 = 1.0;
+
+/// Default quantile of the non-zero counts used by cumulative-sum scaling
+pub const DEFAULT_CSS_QUANTILE: f64 = 0.5;
+
+/// Scale applied to cumulative-sum scaled values (counts per 1,000 reads up to the quantile)
+const CSS_SCALE: f64 = 1000.0;
+
+/// Normalization strategy applied to every sample of an abundance matrix
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    /// Total-sum scaling: each value divided by the sum of its sample (samples sum to 1)
+    TotalSum,
+    /// Centered log-ratio: ln(x + pseudocount) minus its mean over all taxa of the sample
+    Clr { pseudocount: f64 },
+    /// Random subsampling of the reads of each sample, without replacement, to the same depth
+    ///
+    /// Without a depth, the smallest sample total is used. Samples with fewer
+    /// reads than the depth are dropped. The matrix leaves the "Unclassified"
+    /// and unassigned rows out of the sums and the subsampling.
+    Rarefy { depth: Option<u64>, seed: u64 },
+    /// Cumulative-sum scaling (metagenomeSeq): values divided by the sum of the
+    /// counts up to a quantile of the non-zero counts of the sample, times 1,000
+    Css { quantile: f64 },
+    /// log10(x + 1) of each value
+    Log10,
+}
+
+impl FromStr for Normalization {
+    type Err = AbundanceMatrixError;
+
+    /// Parses a normalization name (tss, clr, rarefy, css or log10) with default parameters
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tss" => Ok(Self::TotalSum),
+            "clr" => Ok(Self::Clr { pseudocount: DEFAULT_PSEUDOCOUNT }),
+            "rarefy" | "rarefaction" => Ok(Self::Rarefy { depth: None, seed: 0 }),
+            "css" => Ok(Self::Css { quantile: DEFAULT_CSS_QUANTILE }),
+            "log10" | "log" => Ok(Self::Log10),
+            _ => Err(AbundanceMatrixError::InvalidValue(format!(
+                "unknown normalization '{}', use tss, clr, rarefy, css or log10", s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Normalization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TotalSum => write!(f, "total-sum scaling"),
+            Self::Clr { pseudocount } => write!(f, "centered log-ratio (pseudocount {})", pseudocount),
+            Self::Rarefy { depth: Some(depth), seed } => write!(f, "rarefaction to {} reads (seed {})", depth, seed),
+            Self::Rarefy { depth: None, seed } => write!(f, "rarefaction to the smallest sample (seed {})", seed),
+            Self::Css { quantile } => write!(f, "cumulative-sum scaling (quantile {})", quantile),
+            Self::Log10 => write!(f, "log10(x + 1)"),
+        }
+    }
+}
+
+impl Normalization {
+    /// Whether the normalized values are logarithms, which cannot be summed into an "Other" row
+    pub fn is_logarithmic(&self) -> bool {
+        matches!(self, Self::Clr { .. } | Self::Log10)
+    }
+
+    /// Normalizes the values of one sample
+    ///
+    /// # Arguments
+    /// * `entries` - Non-zero (row, value) pairs of the sample, sorted by row
+    /// * `rows` - Number of rows in the matrix, needed by CLR since zeros get a value
+    /// * `rng` - Random generator used by rarefaction
+    ///
+    /// # Returns
+    /// * `AbundanceResult<Option<Vec<(u32, f64)>>>` - New (row, value) pairs sorted by row,
+    ///   or None when the sample is dropped because it has fewer reads than the rarefaction depth
+    pub fn apply(&self, entries: &[(u32, f64)], rows: usize, rng: &mut StdRng) -> AbundanceResult<Option<Vec<(u32, f64)>>> {
+        let normalized = match *self {
+            Self::TotalSum => {
+                let sum: f64 = entries.iter().map(|&(_, value)| value).sum();
+                if sum > 0.0 {
+                    entries.iter().map(|&(row, value)| (row, value / sum)).collect()
+                } else {
+                    Vec::new()
+                }
+            }
+            Self::Clr { pseudocount } => {
+                if pseudocount <= 0.0 {
+                    return Err(AbundanceMatrixError::InvalidValue("the CLR pseudocount must be positive".to_string()));
+                }
+                // Zeros take part in the geometric mean and become ln(pseudocount) - mean
+                let zeros = rows - entries.len();
+                let log_sum: f64 = entries.iter().map(|&(_, value)| (value + pseudocount).ln()).sum::<f64>()
+                    + zeros as f64 * pseudocount.ln();
+                let mean = log_sum / rows as f64;
+                let mut dense = vec![pseudocount.ln() - mean; rows];
+                for &(row, value) in entries {
+                    dense[row as usize] = (value + pseudocount).ln() - mean;
+                }
+                dense.into_iter().enumerate().map(|(row, value)| (row as u32, value)).collect()
+            }
+            Self::Rarefy { depth, seed: _ } => {
+                let depth = depth.ok_or_else(|| {
+                    AbundanceMatrixError::InvalidValue("the rarefaction depth must be resolved before use".to_string())
+                })?;
+                return rarefy(entries, depth, rng);
+            }
+            Self::Css { quantile } => {
+                if !(0.0..=1.0).contains(&quantile) {
+                    return Err(AbundanceMatrixError::InvalidValue("the CSS quantile must be between 0 and 1".to_string()));
+                }
+                let mut values: Vec<f64> = entries.iter().map(|&(_, value)| value).filter(|&v| v > 0.0).collect();
+                values.sort_by(|a, b| a.total_cmp(b));
+                let threshold = quantile_of_sorted(&values, quantile);
+                let scaling: f64 = values.iter().filter(|&&v| v <= threshold).sum();
+                if scaling > 0.0 {
+                    entries.iter().map(|&(row, value)| (row, value / scaling * CSS_SCALE)).collect()
+                } else {
+                    Vec::new()
+                }
+            }
+            Self::Log10 => entries.iter().map(|&(row, value)| (row, (value + 1.0).log10())).collect(),
+        };
+        Ok(Some(normalized))
+    }
+}
+
+/// Quantile of sorted values, interpolating between the closest ranks (R's default type 7)
+///
+/// # Arguments
+/// * `sorted` - Values in increasing order
+/// * `p` - Quantile between 0 and 1
+pub fn quantile_of_sorted(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let h = (sorted.len() - 1) as f64 * p;
+    let lower = h.floor() as usize;
+    let upper = h.ceil() as usize;
+    sorted[lower] + (h - lower as f64) * (sorted[upper] - sorted[lower])
+}
+
+/// Subsamples the reads of a sample to a fixed depth without replacement
+///
+/// Every read is equally likely to be kept, so the new counts follow a
+/// multivariate hypergeometric distribution. Fails when the depth is 0 or the
+/// values are not whole read counts, and returns None when the sample has
+/// fewer reads than the depth.
+fn rarefy(entries: &[(u32, f64)], depth: u64, rng: &mut StdRng) -> AbundanceResult<Option<Vec<(u32, f64)>>> {
+    if depth == 0 {
+        return Err(AbundanceMatrixError::InvalidValue("the rarefaction depth must be positive".to_string()));
+    }
+    if entries.iter().any(|&(_, value)| value.fract() != 0.0 || value < 0.0) {
+        return Err(AbundanceMatrixError::InvalidValue(
+            "rarefaction needs absolute read counts".to_string(),
+        ));
+    }
+    let total: u64 = entries.iter().map(|&(_, value)| value as u64).sum();
+    if total < depth {
+        return Ok(None);
+    }
+
+    // Pick `depth` distinct reads and find the taxon of each one through the cumulative counts
+    let mut picked = index::sample(rng, total as usize, depth as usize).into_vec();
+    picked.sort_unstable();
+    let mut counts = Vec::new();
+    let mut reads = picked.into_iter().peekable();
+    let mut end = 0usize;
+    for &(row, value) in entries {
+        end += value as usize;
+        let mut count = 0u64;
+        while reads.next_if(|&read| read < end).is_some() {
+            count += 1;
+        }
+        if count > 0 {
+            counts.push((row, count as f64));
+        }
+    }
+    Ok(Some(counts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    const EPSILON: f64 = 1e-9;
+
+    fn apply(normalization: Normalization, entries: &[(u32, f64)], rows: usize) -> Vec<(u32, f64)> {
+        normalization.apply(entries, rows, &mut StdRng::seed_from_u64(1)).unwrap().unwrap()
+    }
+
+    fn assert_values(actual: &[(u32, f64)], expected: &[(u32, f64)]) {
+        assert_eq!(actual.len(), expected.len());
+        for (&(row, value), &(expected_row, expected_value)) in actual.iter().zip(expected) {
+            assert_eq!(row, expected_row);
+            assert!((value - expected_value).abs() < EPSILON, "{} != {}", value, expected_value);
+        }
+    }
+
+    #[test]
+    fn test_tss_and_log10() {
+        assert_values(&apply(Normalization::TotalSum, &[(0, 1.0), (2, 3.0)], 3), &[(0, 0.25), (2, 0.75)]);
+        assert_values(&apply(Normalization::Log10, &[(0, 9.0), (1, 99.0)], 2), &[(0, 1.0), (1, 2.0)]);
+    }
+
+    #[test]
+    fn test_clr() {
+        // ln(2), ln(4) and ln(1) for the zero have a mean of ln(2)
+        let clr = apply(Normalization::Clr { pseudocount: 1.0 }, &[(0, 1.0), (1, 3.0)], 3);
+        let ln2 = 2f64.ln();
+        assert_values(&clr, &[(0, 0.0), (1, ln2), (2, -ln2)]);
+        assert!(clr.iter().map(|&(_, v)| v).sum::<f64>().abs() < EPSILON);
+        assert!(Normalization::Clr { pseudocount: 0.0 }.apply(&[(0, 1.0)], 1, &mut StdRng::seed_from_u64(1)).is_err());
+    }
+
+    #[test]
+    fn test_css() {
+        // The median of the non-zero counts is 3, so the scaling factor is 1 + 2 + 3
+        let entries = [(0, 1.0), (1, 2.0), (2, 3.0), (3, 4.0), (4, 10.0)];
+        let css = apply(Normalization::Css { quantile: 0.5 }, &entries, 5);
+        let expected: Vec<(u32, f64)> = entries.iter().map(|&(row, v)| (row, v / 6.0 * 1000.0)).collect();
+        assert_values(&css, &expected);
+        assert_eq!(quantile_of_sorted(&[1.0, 2.0, 3.0, 4.0], 0.5), 2.5);
+    }
+
+    #[test]
+    fn test_rarefy() {
+        let entries = [(0, 50.0), (3, 30.0), (7, 20.0)];
+        let rarefy = Normalization::Rarefy { depth: Some(40), seed: 7 };
+        let first = rarefy.apply(&entries, 8, &mut StdRng::seed_from_u64(7)).unwrap().unwrap();
+        let second = rarefy.apply(&entries, 8, &mut StdRng::seed_from_u64(7)).unwrap().unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.iter().map(|&(_, v)| v).sum::<f64>(), 40.0);
+        for (row, value) in first {
+            let original = entries.iter().find(|&&(r, _)| r == row).unwrap().1;
+            assert!(value <= original);
+        }
+
+        // Keeping every read leaves the counts unchanged
+        let all = Normalization::Rarefy { depth: Some(100), seed: 0 };
+        assert_eq!(all.apply(&entries, 8, &mut StdRng::seed_from_u64(0)).unwrap().unwrap(), entries.to_vec());
+        let too_deep = Normalization::Rarefy { depth: Some(101), seed: 0 };
+        assert_eq!(too_deep.apply(&entries, 8, &mut StdRng::seed_from_u64(0)).unwrap(), None);
+        assert!(all.apply(&[(0, 1.5)], 1, &mut StdRng::seed_from_u64(0)).is_err());
+        let empty = Normalization::Rarefy { depth: Some(0), seed: 0 };
+        assert!(empty.apply(&entries, 8, &mut StdRng::seed_from_u64(0)).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("CLR".parse::<Normalization>().unwrap(), Normalization::Clr { pseudocount: 1.0 });
+        assert_eq!("rarefy".parse::<Normalization>().unwrap(), Normalization::Rarefy { depth: None, seed: 0 });
+        assert!("zscore".parse::<Normalization>().is_err());
+        assert!("log10".parse::<Normalization>().unwrap().is_logarithmic());
+        assert!(!"css".parse::<Normalization>().unwrap().is_logarithmic());
+    }
+}
@@ -0,0 +1,454 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use crate::diversity::{DistanceMatrix, DiversityError, DiversityResult};
+
+/// Optimized buffer size for writing ordination results
+const BUFFER_SIZE: usize = 256 * 1024; // 256KB
+
+/// Eigenvalues below this fraction of the largest one are treated as zero
+const EIGENVALUE_TOLERANCE: f64 = 1e-10;
+
+/// Principal coordinates of the samples of a distance matrix
+#[derive(Debug, Clone)]
+pub struct Pcoa {
+    /// Sample names in row order
+    pub samples: Vec<String>,
+    /// Positive eigenvalues of the axes, in decreasing order
+    pub eigenvalues: Vec<f64>,
+    /// Fraction of the variance explained by each axis
+    pub proportion_explained: Vec<f64>,
+    /// Coordinates of each sample on each axis
+    pub coordinates: Vec<Vec<f64>>,
+}
+
+impl Pcoa {
+    /// Writes the coordinates of the samples, one line per sample and one column per axis
+    ///
+    /// # Arguments
+    /// * `output_file` - Path to the output file
+    /// * `axes` - Maximum number of axes written (all axes when None)
+    pub fn write_coordinates(&self, output_file: &str, axes: Option<usize>) -> DiversityResult<()> {
+        let file = File::create(output_file)?;
+        let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+        let axes = axes.unwrap_or(self.eigenvalues.len()).min(self.eigenvalues.len());
+
+        write!(writer, "sample")?;
+        for axis in 1..=axes {
+            write!(writer, "\tPC{}", axis)?;
+        }
+        writeln!(writer)?;
+        for (sample, coordinates) in self.samples.iter().zip(&self.coordinates) {
+            write!(writer, "{}", sample)?;
+            for value in &coordinates[..axes] {
+                write!(writer, "\t{:.6}", value)?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the eigenvalue and the variance explained by each axis
+    pub fn write_variance(&self, output_file: &str) -> DiversityResult<()> {
+        let file = File::create(output_file)?;
+        let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+        writeln!(writer, "axis\teigenvalue\tproportion_explained\tcumulative_proportion")?;
+        let mut cumulative = 0.0;
+        for (axis, (eigenvalue, proportion)) in self.eigenvalues.iter().zip(&self.proportion_explained).enumerate() {
+            cumulative += proportion;
+            writeln!(writer, "PC{}\t{:.6}\t{:.6}\t{:.6}", axis + 1, eigenvalue, proportion, cumulative)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Principal coordinates analysis (classical multidimensional scaling)
+///
+/// # Arguments
+/// * `distances` - Distances between at least two samples
+///
+/// # Returns
+/// * `DiversityResult<Pcoa>` - Coordinates on the axes with a positive eigenvalue
+///
+/// # Implementation Details
+/// The squared distances are double-centered into `B = -1/2 J D² J` and the
+/// sample coordinates on each axis are the eigenvectors of B scaled by the
+/// square root of their eigenvalue. Non-Euclidean distances such as
+/// Bray-Curtis give negative eigenvalues, which are left out, and the variance
+/// explained is relative to the sum of the positive eigenvalues. The sign of
+/// each axis is chosen so that its largest coordinate is positive, which
+/// makes the output reproducible.
+pub fn pcoa(distances: &DistanceMatrix) -> DiversityResult<Pcoa> {
+    let n = distances.len();
+    if n < 2 {
+        return Err(DiversityError::InvalidData("PCoA needs at least two samples".to_string()));
+    }
+
+    // Double centering of -d²/2
+    let mut centered: Vec<Vec<f64>> = (0..n)
+        .map(|i| (0..n).map(|j| -0.5 * distances.get(i, j).powi(2)).collect())
+        .collect();
+    let row_means: Vec<f64> = centered.iter().map(|row| row.iter().sum::<f64>() / n as f64).collect();
+    let mean = row_means.iter().sum::<f64>() / n as f64;
+    for (i, row) in centered.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value += mean - row_means[i] - row_means[j];
+        }
+    }
+
+    let (values, vectors) = symmetric_eigen(centered);
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by(|&a, &b| values[b].total_cmp(&values[a]));
+    let largest = values[order[0]].max(0.0);
+    let axes: Vec<usize> = order.into_iter()
+        .filter(|&axis| values[axis] > largest * EIGENVALUE_TOLERANCE && values[axis] > 0.0)
+        .collect();
+
+    let eigenvalues: Vec<f64> = axes.iter().map(|&axis| values[axis]).collect();
+    let total: f64 = eigenvalues.iter().sum();
+    let mut coordinates = vec![Vec::with_capacity(axes.len()); n];
+    for &axis in &axes {
+        let scale = values[axis].sqrt();
+        let largest = (0..n).max_by(|&a, &b| vectors[a][axis].abs().total_cmp(&vectors[b][axis].abs())).unwrap_or(0);
+        let sign = if vectors[largest][axis] < 0.0 { -1.0 } else { 1.0 };
+        for (sample, row) in coordinates.iter_mut().enumerate() {
+            row.push(sign * vectors[sample][axis] * scale);
+        }
+    }
+
+    Ok(Pcoa {
+        samples: distances.samples.clone(),
+        proportion_explained: eigenvalues.iter().map(|value| value / total).collect(),
+        eigenvalues,
+        coordinates,
+    })
+}
+
+/// Eigenvalues and eigenvectors of a symmetric matrix
+///
+/// Householder reduction to a tridiagonal matrix followed by the implicit QL
+/// algorithm (the `tred2` and `tql2` routines of EISPACK). Eigenvectors are
+/// the columns of the returned matrix: `vectors[k][i]` is the k-th component
+/// of the eigenvector of `values[i]`.
+fn symmetric_eigen(matrix: Vec<Vec<f64>>) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let n = matrix.len();
+    let mut v = matrix;
+    let mut d = vec![0.0; n];
+    let mut e = vec![0.0; n];
+    if n == 0 {
+        return (d, v);
+    }
+
+    // Householder reduction to tridiagonal form
+    d.copy_from_slice(&v[n - 1]);
+    for i in (1..n).rev() {
+        let scale: f64 = d[..i].iter().map(|x| x.abs()).sum();
+        let mut h = 0.0;
+        if scale == 0.0 {
+            e[i] = d[i - 1];
+            for j in 0..i {
+                d[j] = v[i - 1][j];
+                v[i][j] = 0.0;
+                v[j][i] = 0.0;
+            }
+        } else {
+            for x in d[..i].iter_mut() {
+                *x /= scale;
+                h += *x * *x;
+            }
+            let f = d[i - 1];
+            let g = if f > 0.0 { -h.sqrt() } else { h.sqrt() };
+            e[i] = scale * g;
+            h -= f * g;
+            d[i - 1] = f - g;
+            e[..i].iter_mut().for_each(|x| *x = 0.0);
+
+            for j in 0..i {
+                let f = d[j];
+                v[j][i] = f;
+                let mut g = e[j] + v[j][j] * f;
+                for k in j + 1..i {
+                    g += v[k][j] * d[k];
+                    e[k] += v[k][j] * f;
+                }
+                e[j] = g;
+            }
+            let mut f = 0.0;
+            for j in 0..i {
+                e[j] /= h;
+                f += e[j] * d[j];
+            }
+            let hh = f / (h + h);
+            for j in 0..i {
+                e[j] -= hh * d[j];
+            }
+            for j in 0..i {
+                let (f, g) = (d[j], e[j]);
+                for k in j..i {
+                    v[k][j] -= f * e[k] + g * d[k];
+                }
+                d[j] = v[i - 1][j];
+                v[i][j] = 0.0;
+            }
+        }
+        d[i] = h;
+    }
+
+    // Accumulation of the transformations
+    for i in 0..n - 1 {
+        v[n - 1][i] = v[i][i];
+        v[i][i] = 1.0;
+        let h = d[i + 1];
+        if h != 0.0 {
+            for k in 0..=i {
+                d[k] = v[k][i + 1] / h;
+            }
+            for j in 0..=i {
+                let g: f64 = (0..=i).map(|k| v[k][i + 1] * v[k][j]).sum();
+                for k in 0..=i {
+                    v[k][j] -= g * d[k];
+                }
+            }
+        }
+        for row in v.iter_mut().take(i + 1) {
+            row[i + 1] = 0.0;
+        }
+    }
+    for j in 0..n {
+        d[j] = v[n - 1][j];
+        v[n - 1][j] = 0.0;
+    }
+    v[n - 1][n - 1] = 1.0;
+    e[0] = 0.0;
+
+    // Implicit QL iterations on the tridiagonal matrix
+    for i in 1..n {
+        e[i - 1] = e[i];
+    }
+    e[n - 1] = 0.0;
+    let mut f = 0.0;
+    let mut tst1: f64 = 0.0;
+    let eps = f64::EPSILON;
+    for l in 0..n {
+        tst1 = tst1.max(d[l].abs() + e[l].abs());
+        let mut m = l;
+        while m < n - 1 && e[m].abs() > eps * tst1 {
+            m += 1;
+        }
+        if m > l {
+            loop {
+                let g = d[l];
+                let mut p = (d[l + 1] - g) / (2.0 * e[l]);
+                let mut r = p.hypot(1.0);
+                if p < 0.0 {
+                    r = -r;
+                }
+                d[l] = e[l] / (p + r);
+                d[l + 1] = e[l] * (p + r);
+                let dl1 = d[l + 1];
+                let mut h = g - d[l];
+                for x in d[l + 2..].iter_mut() {
+                    *x -= h;
+                }
+                f += h;
+
+                p = d[m];
+                let (mut c, mut c2, mut c3) = (1.0, 1.0, 1.0);
+                let el1 = e[l + 1];
+                let (mut s, mut s2) = (0.0, 0.0);
+                for i in (l..m).rev() {
+                    c3 = c2;
+                    c2 = c;
+                    s2 = s;
+                    let g = c * e[i];
+                    h = c * p;
+                    r = p.hypot(e[i]);
+                    e[i + 1] = s * r;
+                    s = e[i] / r;
+                    c = p / r;
+                    p = c * d[i] - s * g;
+                    d[i + 1] = h + s * (c * g + s * d[i]);
+                    for row in v.iter_mut() {
+                        let h = row[i + 1];
+                        row[i + 1] = s * row[i] + c * h;
+                        row[i] = c * row[i] - s * h;
+                    }
+                }
+                p = -s * s2 * c3 * el1 * e[l] / dl1;
+                e[l] = s * p;
+                d[l] = c * p;
+                if e[l].abs() <= eps * tst1 {
+                    break;
+                }
+            }
+        }
+        d[l] += f;
+        e[l] = 0.0;
+    }
+    (d, v)
+}
+
+/// Average-linkage (UPGMA) clustering of the samples as a Newick tree
+///
+/// # Arguments
+/// * `distances` - Distances between at least one sample
+///
+/// # Returns
+/// * `String` - Rooted, ultrametric tree in Newick format, ending with ";"
+///
+/// # Implementation Details
+/// The two closest clusters are merged at half their distance, and the
+/// distance from the new cluster to the others is the size-weighted mean of
+/// the distances of its two parts. Ties are broken by the order of the
+/// samples, and the cluster formed earlier in that order is written first.
+pub fn upgma(distances: &DistanceMatrix) -> String {
+    if distances.is_empty() {
+        return ";".to_string();
+    }
+    let n = distances.len();
+    // Active clusters: Newick subtree, height and number of samples
+    let mut clusters: Vec<(String, f64, usize)> = distances.samples.iter()
+        .map(|sample| (newick_label(sample), 0.0, 1))
+        .collect();
+    let mut matrix: Vec<Vec<f64>> = (0..n).map(|i| (0..n).map(|j| distances.get(i, j)).collect()).collect();
+
+    while clusters.len() > 1 {
+        let mut closest = (0, 1);
+        for i in 0..clusters.len() {
+            for j in i + 1..clusters.len() {
+                if matrix[i][j] < matrix[closest.0][closest.1] {
+                    closest = (i, j);
+                }
+            }
+        }
+        let (i, j) = closest;
+        let height = matrix[i][j] / 2.0;
+        let (right, right_height, right_size) = clusters.remove(j);
+        let (left, left_height, left_size) = &clusters[i];
+        let merged = format!(
+            "({}:{},{}:{})",
+            left, format_length(height - left_height), right, format_length(height - right_height)
+        );
+        let size = left_size + right_size;
+
+        // Size-weighted average distance to the merged cluster
+        let right_row = matrix.remove(j);
+        for row in matrix.iter_mut() {
+            row.remove(j);
+        }
+        for k in 0..clusters.len() {
+            if k != i {
+                let value = (matrix[i][k] * *left_size as f64 + right_row[if k < j { k } else { k + 1 }] * right_size as f64)
+                    / size as f64;
+                matrix[i][k] = value;
+                matrix[k][i] = value;
+            }
+        }
+        clusters[i] = (merged, height, size);
+    }
+    format!("{};", clusters[0].0)
+}
+
+/// Branch length with at most 6 decimals and no trailing zeros
+fn format_length(length: f64) -> String {
+    let formatted = format!("{:.6}", length.max(0.0));
+    formatted.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Sample name as a Newick label, quoted when it holds characters with a meaning in Newick
+fn newick_label(name: &str) -> String {
+    if name.chars().any(|c| "()[]':;, \t".contains(c)) {
+        format!("'{}'", name.replace('\'', "''"))
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f64 = 1e-9;
+
+    fn distances(samples: &[&str], values: &[f64]) -> DistanceMatrix {
+        DistanceMatrix::new(samples.iter().map(|s| s.to_string()).collect(), values.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_pcoa_line() {
+        // Points at 0, 1 and 3 on a line, centered at 4/3
+        let matrix = distances(&["a", "b", "c"], &[0.0, 1.0, 3.0, 1.0, 0.0, 2.0, 3.0, 2.0, 0.0]);
+        let pcoa = pcoa(&matrix).unwrap();
+        assert_eq!(pcoa.eigenvalues.len(), 1);
+        assert!((pcoa.eigenvalues[0] - 42.0 / 9.0).abs() < EPSILON);
+        assert!((pcoa.proportion_explained[0] - 1.0).abs() < EPSILON);
+        let expected = [-4.0 / 3.0, -1.0 / 3.0, 5.0 / 3.0];
+        for (coordinates, expected) in pcoa.coordinates.iter().zip(expected) {
+            assert!((coordinates[0] - expected).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn test_pcoa_square() {
+        // Corners of a unit square: two axes explaining half of the variance each
+        let diagonal = 2f64.sqrt();
+        let matrix = distances(&["a", "b", "c", "d"], &[
+            0.0, 1.0, diagonal, 1.0,
+            1.0, 0.0, 1.0, diagonal,
+            diagonal, 1.0, 0.0, 1.0,
+            1.0, diagonal, 1.0, 0.0,
+        ]);
+        let pcoa = pcoa(&matrix).unwrap();
+        assert_eq!(pcoa.eigenvalues.len(), 2);
+        for (eigenvalue, proportion) in pcoa.eigenvalues.iter().zip(&pcoa.proportion_explained) {
+            assert!((eigenvalue - 1.0).abs() < EPSILON);
+            assert!((proportion - 0.5).abs() < EPSILON);
+        }
+        // The coordinates keep the Euclidean distances
+        for i in 0..4 {
+            for j in 0..4 {
+                let (a, b) = (&pcoa.coordinates[i], &pcoa.coordinates[j]);
+                let distance = a.iter().zip(b).map(|(x, y)| (x - y).powi(2)).sum::<f64>().sqrt();
+                assert!((distance - matrix.get(i, j)).abs() < EPSILON);
+            }
+        }
+        assert!(super::pcoa(&distances(&["a"], &[0.0])).is_err());
+    }
+
+    #[test]
+    fn test_pcoa_reconstructs_euclidean_distances() {
+        let points = [[0.0, 0.0, 0.0], [1.0, 2.0, 0.5], [3.0, -1.0, 2.0], [-2.0, 0.5, 1.0], [0.3, 4.0, -1.0], [2.5, 2.5, 2.5]];
+        let names: Vec<String> = (0..points.len()).map(|i| format!("s{}", i)).collect();
+        let values: Vec<f64> = points.iter()
+            .flat_map(|a| points.iter().map(move |b| a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum::<f64>().sqrt()))
+            .collect();
+        let matrix = DistanceMatrix::new(names, values).unwrap();
+        let pcoa = pcoa(&matrix).unwrap();
+        assert_eq!(pcoa.eigenvalues.len(), 3);
+        assert!(pcoa.eigenvalues.windows(2).all(|pair| pair[0] >= pair[1]));
+        for i in 0..points.len() {
+            for j in 0..points.len() {
+                let (a, b) = (&pcoa.coordinates[i], &pcoa.coordinates[j]);
+                let distance = a.iter().zip(b).map(|(x, y)| (x - y).powi(2)).sum::<f64>().sqrt();
+                assert!((distance - matrix.get(i, j)).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn test_upgma() {
+        let matrix = distances(&["a", "b", "c", "d e"], &[
+            0.0, 2.0, 6.0, 6.0,
+            2.0, 0.0, 6.0, 6.0,
+            6.0, 6.0, 0.0, 4.0,
+            6.0, 6.0, 4.0, 0.0,
+        ]);
+        assert_eq!(upgma(&matrix), "((a:1,b:1):2,(c:2,'d e':2):1);");
+        assert_eq!(upgma(&distances(&["a"], &[0.0])), "a;");
+
+        // The distance to a merged cluster is the mean over its samples
+        let matrix = distances(&["a", "b", "c"], &[0.0, 1.0, 4.0, 1.0, 0.0, 5.0, 4.0, 5.0, 0.0]);
+        assert_eq!(upgma(&matrix), "((a:0.5,b:0.5):1.75,c:2.25);");
+    }
+}
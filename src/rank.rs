@@ -0,0 +1,317 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Standard rank letters used by Kraken2, from the root down
+const RANK_LETTERS: &[(char, &str)] = &[
+    ('R', "root"),
+    ('D', "domain"),
+    ('K', "kingdom"),
+    ('P', "phylum"),
+    ('C', "class"),
+    ('O', "order"),
+    ('F', "family"),
+    ('G', "genus"),
+    ('S', "species"),
+];
+
+/// Taxonomic rank of a Kraken2 report line
+///
+/// Kraken2 uses one letter per standard rank and appends a number for intermediate
+/// ranks below it, e.g. "S1" for strains or "R1" for nodes between the root and the
+/// domains. Each variant stores that sub-level depth (0 for the standard rank itself).
+///
+/// Ranks are ordered from the root down, and by depth within the same standard rank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Rank {
+    Unclassified,
+    Root(u8),
+    Domain(u8),
+    Kingdom(u8),
+    Phylum(u8),
+    Class(u8),
+    Order(u8),
+    Family(u8),
+    Genus(u8),
+    Species(u8),
+    /// Rank code not used by Kraken2 (e.g. "-" in other classifiers' reports)
+    Unknown,
+}
+
+/// Error returned when a rank code cannot be parsed
+#[derive(Debug)]
+pub struct ParseRankError(String);
+
+impl fmt::Display for ParseRankError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid taxonomic rank '{}'. Use a Kraken rank code such as R, D, K, P, C, O, F, G, S or S1", self.0)
+    }
+}
+
+impl Error for ParseRankError {}
+
+impl Rank {
+    /// Builds a rank from its standard letter and sub-level depth
+    fn from_letter(letter: char, depth: u8) -> Option<Self> {
+        match letter {
+            'R' => Some(Self::Root(depth)),
+            'D' => Some(Self::Domain(depth)),
+            'K' => Some(Self::Kingdom(depth)),
+            'P' => Some(Self::Phylum(depth)),
+            'C' => Some(Self::Class(depth)),
+            'O' => Some(Self::Order(depth)),
+            'F' => Some(Self::Family(depth)),
+            'G' => Some(Self::Genus(depth)),
+            'S' => Some(Self::Species(depth)),
+            _ => None,
+        }
+    }
+
+    /// Parses a rank code from a report, mapping unrecognized codes to `Rank::Unknown`
+    ///
+    /// # Arguments
+    /// * `code` - Rank code as written in the report (e.g. "G", "S1", "U")
+    pub fn from_code(code: &str) -> Self {
+        code.parse().unwrap_or(Self::Unknown)
+    }
+
+    /// Standard rank letter, or None for unclassified and unknown ranks
+    pub fn letter(&self) -> Option<char> {
+        match self {
+            Self::Root(_) => Some('R'),
+            Self::Domain(_) => Some('D'),
+            Self::Kingdom(_) => Some('K'),
+            Self::Phylum(_) => Some('P'),
+            Self::Class(_) => Some('C'),
+            Self::Order(_) => Some('O'),
+            Self::Family(_) => Some('F'),
+            Self::Genus(_) => Some('G'),
+            Self::Species(_) => Some('S'),
+            Self::Unclassified | Self::Unknown => None,
+        }
+    }
+
+    /// Sub-level depth below the standard rank (1 for "S1", 0 for "S")
+    pub fn depth(&self) -> u8 {
+        match *self {
+            Self::Root(d) | Self::Domain(d) | Self::Kingdom(d) | Self::Phylum(d) | Self::Class(d)
+            | Self::Order(d) | Self::Family(d) | Self::Genus(d) | Self::Species(d) => d,
+            Self::Unclassified | Self::Unknown => 0,
+        }
+    }
+
+    /// Standard rank this rank belongs to ("S" for "S1")
+    #[allow(dead_code)]
+    pub fn base(&self) -> Self {
+        self.letter()
+            .and_then(|letter| Self::from_letter(letter, 0))
+            .unwrap_or(*self)
+    }
+
+    /// Full name of the standard rank (e.g. "species")
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Unclassified => "unclassified",
+            Self::Unknown => "unknown",
+            _ => RANK_LETTERS.iter()
+                .find(|(letter, _)| Some(*letter) == self.letter())
+                .map(|(_, name)| *name)
+                .unwrap_or("unknown"),
+        }
+    }
+
+    /// Standard ranks from domain to species, as used for "all ranks" selections
+    pub fn standard_ranks() -> impl Iterator<Item = Rank> {
+        RANK_LETTERS.iter()
+            .filter(|(letter, _)| *letter != 'R')
+            .filter_map(|(letter, _)| Self::from_letter(*letter, 0))
+    }
+
+    /// Checks whether this rank is the given rank or more specific than it
+    ///
+    /// Unclassified and unknown ranks are never at or below any rank.
+    pub fn is_at_or_below(&self, other: Rank) -> bool {
+        self.letter().is_some() && other.letter().is_some() && *self >= other
+    }
+}
+
+impl FromStr for Rank {
+    type Err = ParseRankError;
+
+    /// Parses a Kraken rank code ("U", "R", "D", "D1", "S", "S2", ...)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "U" {
+            return Ok(Self::Unclassified);
+        }
+
+        let mut chars = s.chars();
+        let letter = chars.next().ok_or_else(|| ParseRankError(s.to_string()))?;
+        let digits = chars.as_str();
+        let depth = if digits.is_empty() {
+            0
+        } else {
+            digits.parse::<u8>().map_err(|_| ParseRankError(s.to_string()))?
+        };
+        Self::from_letter(letter, depth).ok_or_else(|| ParseRankError(s.to_string()))
+    }
+}
+
+impl fmt::Display for Rank {
+    /// Formats the rank as a Kraken rank code
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self, self.letter()) {
+            (Self::Unclassified, _) => write!(f, "U"),
+            (_, None) => write!(f, "-"),
+            (_, Some(letter)) if self.depth() == 0 => write!(f, "{}", letter),
+            (_, Some(letter)) => write!(f, "{}{}", letter, self.depth()),
+        }
+    }
+}
+
+/// Selection of ranks used to build abundance tables
+///
+/// * `Exact` - Only taxa with exactly this rank ("S", "S1")
+/// * `AtOrBelow` - The first taxon at this rank or below it on each lineage ("S+", "G+"),
+///   so that e.g. a strain with no species above it is still counted at species level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RankSelector {
+    Exact(Rank),
+    AtOrBelow(Rank),
+}
+
+impl RankSelector {
+    /// Checks whether a taxon with the given rank is selected
+    pub fn matches(&self, rank: Rank) -> bool {
+        match self {
+            Self::Exact(target) => rank == *target,
+            Self::AtOrBelow(target) => rank.is_at_or_below(*target),
+        }
+    }
+
+    /// Rank the selection is based on
+    pub fn rank(&self) -> Rank {
+        match self {
+            Self::Exact(rank) | Self::AtOrBelow(rank) => *rank,
+        }
+    }
+}
+
+impl FromStr for RankSelector {
+    type Err = ParseRankError;
+
+    /// Parses a rank code, with a trailing "+" to include all more specific ranks
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let selector = match s.strip_suffix('+') {
+            Some(code) => Self::AtOrBelow(code.parse()?),
+            None => Self::Exact(s.parse()?),
+        };
+        match selector.rank() {
+            Rank::Unclassified => Err(ParseRankError(s.to_string())),
+            _ => Ok(selector),
+        }
+    }
+}
+
+/// Parses a comma-separated list of rank selections, where "all" expands to every standard rank
+///
+/// # Arguments
+/// * `levels` - List such as "D,P,G,S", "S1,G+" or "all"
+pub fn parse_rank_list(levels: &str) -> Result<Vec<RankSelector>, ParseRankError> {
+    let mut selectors = Vec::new();
+    for level in levels.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        if level.eq_ignore_ascii_case("all") {
+            selectors.extend(Rank::standard_ranks().map(RankSelector::Exact));
+        } else {
+            selectors.push(level.parse()?);
+        }
+    }
+    if selectors.is_empty() {
+        return Err(ParseRankError(levels.to_string()));
+    }
+    // Keep the first occurrence of each selection
+    let mut seen = Vec::with_capacity(selectors.len());
+    selectors.retain(|s| {
+        let new = !seen.contains(s);
+        seen.push(*s);
+        new
+    });
+    Ok(selectors)
+}
+
+impl fmt::Display for RankSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exact(rank) => write!(f, "{}", rank),
+            Self::AtOrBelow(rank) => write!(f, "{}+", rank),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rank_codes() {
+        assert_eq!("S".parse::<Rank>().unwrap(), Rank::Species(0));
+        assert_eq!("S1".parse::<Rank>().unwrap(), Rank::Species(1));
+        assert_eq!("R1".parse::<Rank>().unwrap(), Rank::Root(1));
+        assert_eq!("D".parse::<Rank>().unwrap(), Rank::Domain(0));
+        assert_eq!("U".parse::<Rank>().unwrap(), Rank::Unclassified);
+        assert!("Z".parse::<Rank>().is_err());
+        assert!("Sx".parse::<Rank>().is_err());
+        assert_eq!(Rank::from_code("-"), Rank::Unknown);
+    }
+
+    #[test]
+    fn test_rank_display_round_trip() {
+        for code in ["U", "R", "R1", "D", "K", "P", "C", "O", "F", "G2", "S", "S1"] {
+            assert_eq!(code.parse::<Rank>().unwrap().to_string(), code);
+        }
+        assert_eq!(Rank::Unknown.to_string(), "-");
+    }
+
+    #[test]
+    fn test_rank_ordering() {
+        assert!(Rank::Root(0) < Rank::Root(1));
+        assert!(Rank::Root(1) < Rank::Domain(0));
+        assert!(Rank::Genus(2) < Rank::Species(0));
+        assert!(Rank::Species(0) < Rank::Species(1));
+        assert_eq!(Rank::Species(3).base(), Rank::Species(0));
+        assert_eq!(Rank::Genus(1).name(), "genus");
+
+        let standard: Vec<String> = Rank::standard_ranks().map(|r| r.to_string()).collect();
+        assert_eq!(standard, vec!["D", "K", "P", "C", "O", "F", "G", "S"]);
+    }
+
+    #[test]
+    fn test_rank_selector() {
+        let species = "S".parse::<RankSelector>().unwrap();
+        assert!(species.matches(Rank::Species(0)));
+        assert!(!species.matches(Rank::Species(1)));
+
+        let genus_or_below = "G+".parse::<RankSelector>().unwrap();
+        assert!(genus_or_below.matches(Rank::Genus(0)));
+        assert!(genus_or_below.matches(Rank::Species(1)));
+        assert!(!genus_or_below.matches(Rank::Family(1)));
+        assert!(!genus_or_below.matches(Rank::Unknown));
+        assert_eq!(genus_or_below.to_string(), "G+");
+
+        assert!("U".parse::<RankSelector>().is_err());
+    }
+
+    #[test]
+    fn test_parse_rank_list() {
+        let levels = parse_rank_list("D, G+,S1,D").unwrap();
+        assert_eq!(levels, vec![
+            RankSelector::Exact(Rank::Domain(0)),
+            RankSelector::AtOrBelow(Rank::Genus(0)),
+            RankSelector::Exact(Rank::Species(1)),
+        ]);
+        assert_eq!(parse_rank_list("all").unwrap().len(), 8);
+        assert!(parse_rank_list("D,Z").is_err());
+        assert!(parse_rank_list(",").is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use colored::*;
+use crate::krk_parser::{KrakenReport, TaxonEntry};
+use crate::rank::{Rank, RankSelector};
+use crate::taxon_query;
+
+/// Optimized buffer size for writing diff tables
+const BUFFER_SIZE: usize = 256 * 1024; // 256KB
+
+/// Absolute log2 fold change above which the tree view colors a taxon
+const TREE_FOLD_CHANGE: f64 = 1.0;
+
+/// Reports in which a taxon has reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Both,
+    OnlyA,
+    OnlyB,
+}
+
+impl Presence {
+    /// Label of the presence in diff tables
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Both => "both",
+            Self::OnlyA => "only_a",
+            Self::OnlyB => "only_b",
+        }
+    }
+}
+
+/// Clade reads of a taxon in both reports
+#[derive(Debug, Clone)]
+pub struct TaxonDiff {
+    pub taxid: u32,
+    pub name: String,
+    /// Rank code as written in the report
+    pub rank_code: String,
+    pub rank: Rank,
+    /// Clade reads in report A
+    pub reads_a: u64,
+    /// Clade reads in report B
+    pub reads_b: u64,
+    /// Percentage of the reads of report A in the clade
+    pub percent_a: f64,
+    /// Percentage of the reads of report B in the clade
+    pub percent_b: f64,
+    /// Log2 ratio of the relative abundances in B and A, with a pseudocount added to the reads
+    pub log2_fold_change: f64,
+}
+
+impl TaxonDiff {
+    /// Reports in which the taxon has reads
+    pub fn presence(&self) -> Presence {
+        match (self.reads_a > 0, self.reads_b > 0) {
+            (true, false) => Presence::OnlyA,
+            (false, true) => Presence::OnlyB,
+            _ => Presence::Both,
+        }
+    }
+
+    /// Change in clade reads from A to B
+    pub fn read_change(&self) -> i64 {
+        self.reads_b as i64 - self.reads_a as i64
+    }
+
+    /// Change in percentage of reads from A to B (percentage points)
+    pub fn percent_change(&self) -> f64 {
+        self.percent_b - self.percent_a
+    }
+}
+
+/// Taxa of two Kraken2 reports aligned by taxid
+#[derive(Debug, Clone)]
+pub struct ReportDiff {
+    /// Total reads (classified and unclassified) of report A
+    pub total_a: u64,
+    /// Total reads (classified and unclassified) of report B
+    pub total_b: u64,
+    /// Taxa with reads in either report, in tree order
+    pub taxa: Vec<TaxonDiff>,
+    /// Position of each taxid in `taxa`
+    index: HashMap<u32, usize>,
+    /// Union of the two trees, with the unclassified node first
+    tree: Vec<TaxonEntry>,
+}
+
+/// Filter of the taxa listed in a diff
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiffFilter {
+    /// Ranks of the listed taxa (all ranks when None)
+    pub rank: Option<RankSelector>,
+    /// Minimum clade reads in at least one of the reports
+    pub min_reads: u64,
+}
+
+impl DiffFilter {
+    /// Checks whether a taxon passes the read threshold and the rank selection
+    pub fn matches(&self, taxon: &TaxonDiff) -> bool {
+        self.passes_reads(taxon) && self.rank.is_none_or(|rank| rank.matches(taxon.rank))
+    }
+
+    fn passes_reads(&self, taxon: &TaxonDiff) -> bool {
+        taxon.reads_a.max(taxon.reads_b) >= self.min_reads.max(1)
+    }
+}
+
+/// Aligns the taxa of two reports by taxid
+///
+/// # Arguments
+/// * `a` - First report (e.g. before)
+/// * `b` - Second report (e.g. after)
+/// * `pseudocount` - Reads added to both clade counts before computing the fold change
+///
+/// # Returns
+/// * `ReportDiff` - Taxa with reads in either report
+///
+/// # Implementation Details
+/// The two trees are merged by taxid under each parent, so taxa found only
+/// in B are placed where B has them. The fold change compares the relative
+/// abundances, `log2(((b + pseudocount) / total_b) / ((a + pseudocount) / total_a))`,
+/// so that reports of different depths can be compared. Lines with zero
+/// reads in both reports (`--report-zero-counts`) are left out.
+pub fn diff_reports(a: &KrakenReport, b: &KrakenReport, pseudocount: f64) -> ReportDiff {
+    let tops = |report: &KrakenReport| -> Vec<TaxonEntry> {
+        report.unclassified.iter().chain(std::iter::once(&report.root)).cloned().collect()
+    };
+    let (tops_a, tops_b) = (tops(a), tops(b));
+    let tree = merge_trees(&tops_a, &tops_b);
+
+    let mut entries_a = HashMap::new();
+    let mut entries_b = HashMap::new();
+    index_entries(&tops_a, &mut entries_a);
+    index_entries(&tops_b, &mut entries_b);
+    let total = |tops: &[TaxonEntry]| tops.iter().map(|entry| entry.clade_reads).sum::<u64>();
+    let (total_a, total_b) = (total(&tops_a), total(&tops_b));
+    let percent = |reads: u64, total: u64| if total > 0 { reads as f64 * 100.0 / total as f64 } else { 0.0 };
+
+    let mut order = Vec::new();
+    let mut seen = HashSet::new();
+    collect_taxids(&tree, &mut seen, &mut order);
+    let mut taxa = Vec::with_capacity(order.len());
+    for taxid in order {
+        let entry_a = entries_a.get(&taxid).copied();
+        let entry_b = entries_b.get(&taxid).copied();
+        let entry: &TaxonEntry = match entry_a.or(entry_b) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let reads_a = entry_a.map_or(0, |e| e.clade_reads);
+        let reads_b = entry_b.map_or(0, |e| e.clade_reads);
+        if reads_a == 0 && reads_b == 0 {
+            continue;
+        }
+        let log2_fold_change = (((reads_b as f64 + pseudocount) / total_b.max(1) as f64)
+            / ((reads_a as f64 + pseudocount) / total_a.max(1) as f64)).log2();
+        taxa.push(TaxonDiff {
+            taxid,
+            name: entry.name.clone(),
+            rank_code: entry.rank_code.clone(),
+            rank: entry.rank,
+            reads_a,
+            reads_b,
+            percent_a: percent(reads_a, total_a),
+            percent_b: percent(reads_b, total_b),
+            log2_fold_change,
+        });
+    }
+    let index = taxa.iter().enumerate().map(|(i, taxon)| (taxon.taxid, i)).collect();
+
+    ReportDiff { total_a, total_b, taxa, index, tree }
+}
+
+/// Merges sibling lists by taxid, keeping the order of A and appending the taxa only in B
+fn merge_trees(a: &[TaxonEntry], b: &[TaxonEntry]) -> Vec<TaxonEntry> {
+    let by_taxid: HashMap<u32, &TaxonEntry> = b.iter().map(|entry| (entry.taxid, entry)).collect();
+    let mut merged = Vec::with_capacity(a.len());
+    for entry in a {
+        let other = by_taxid.get(&entry.taxid).map(|other| &other.children[..]).unwrap_or(&[]);
+        let mut node = leaf(entry);
+        node.children = merge_trees(&entry.children, other);
+        merged.push(node);
+    }
+    let in_a: HashSet<u32> = a.iter().map(|entry| entry.taxid).collect();
+    for entry in b.iter().filter(|entry| !in_a.contains(&entry.taxid)) {
+        let mut node = leaf(entry);
+        node.children = merge_trees(&entry.children, &[]);
+        merged.push(node);
+    }
+    merged
+}
+
+/// Copy of a taxon without its children
+fn leaf(entry: &TaxonEntry) -> TaxonEntry {
+    let mut leaf = TaxonEntry::new(
+        entry.percentage, entry.clade_reads, entry.direct_reads,
+        entry.rank_code.clone(), entry.taxid, entry.name.clone(), entry.depth,
+    );
+    // The rank of GTDB names comes from their prefix rather than from the code
+    leaf.rank = entry.rank;
+    leaf
+}
+
+/// Maps each taxid to its first entry in the tree
+fn index_entries<'a>(entries: &'a [TaxonEntry], map: &mut HashMap<u32, &'a TaxonEntry>) {
+    for entry in entries {
+        map.entry(entry.taxid).or_insert(entry);
+        index_entries(&entry.children, map);
+    }
+}
+
+/// Lists the taxids of the tree in preorder, once each
+fn collect_taxids(entries: &[TaxonEntry], seen: &mut HashSet<u32>, order: &mut Vec<u32>) {
+    for entry in entries {
+        if seen.insert(entry.taxid) {
+            order.push(entry.taxid);
+        }
+        collect_taxids(&entry.children, seen, order);
+    }
+}
+
+impl ReportDiff {
+    /// Diff of a taxon, if it has reads in either report
+    pub fn taxon(&self, taxid: u32) -> Option<&TaxonDiff> {
+        self.index.get(&taxid).map(|&i| &self.taxa[i])
+    }
+
+    /// Taxa passing a filter, in tree order
+    pub fn filtered(&self, filter: &DiffFilter) -> Vec<&TaxonDiff> {
+        self.taxa.iter().filter(|taxon| filter.matches(taxon)).collect()
+    }
+
+    /// Writes the taxa passing a filter as a TSV table
+    ///
+    /// # Arguments
+    /// * `output_path` - Path to the output file
+    /// * `filter` - Rank and read filter of the listed taxa
+    pub fn write_tsv(&self, output_path: &str, filter: &DiffFilter) -> std::io::Result<()> {
+        let file = File::create(output_path)?;
+        let mut writer = BufWriter::with_capacity(BUFFER_SIZE, file);
+        writeln!(
+            writer,
+            "taxid\tname\trank\tpresence\treads_a\treads_b\tpercent_a\tpercent_b\tread_change\tpercent_change\tlog2_fold_change"
+        )?;
+        for taxon in self.filtered(filter) {
+            writeln!(
+                writer, "{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{:.6}\t{}\t{:.6}\t{:.6}",
+                taxon.taxid, taxon.name, taxon.rank_code, taxon.presence().label(),
+                taxon.reads_a, taxon.reads_b, taxon.percent_a, taxon.percent_b,
+                taxon.read_change(), taxon.percent_change(), taxon.log2_fold_change
+            )?;
+        }
+        writer.flush()
+    }
+
+    /// Prints the merged tree with the reads of both reports and the fold change of each taxon
+    ///
+    /// # Implementation Details
+    /// Taxa below the read threshold are hidden with their descendants. With an
+    /// exact rank selection the tree stops at that rank, and the ancestors are
+    /// shown to place the selected taxa. Taxa found in one report only are
+    /// marked with "+" (B) or "-" (A), and fold changes of at least 2x are
+    /// colored green (up in B) or red (down in B).
+    pub fn print_tree(&self, filter: &DiffFilter) {
+        let tree = self.display_tree(&self.tree, filter);
+        println!(
+            "{}",
+            format!("Taxonomic Tree - A {} reads - B {} reads - log2 fold change of B over A", self.total_a, self.total_b).bold()
+        );
+        taxon_query::print_tree(&tree, "", &|entry| {
+            let taxon = match self.taxon(entry.taxid) {
+                Some(taxon) => taxon,
+                None => return format!("{}: {}", entry.taxid, entry.name),
+            };
+            let text = format!(
+                "{}: {} (A{}) (B{}) {:+.2}",
+                taxon.taxid, taxon.name, taxon.reads_a, taxon.reads_b, taxon.log2_fold_change
+            );
+            match taxon.presence() {
+                Presence::OnlyA => format!("{} {}", "-".red().bold(), text.red()),
+                Presence::OnlyB => format!("{} {}", "+".green().bold(), text.green()),
+                Presence::Both if taxon.log2_fold_change >= TREE_FOLD_CHANGE => text.green().to_string(),
+                Presence::Both if taxon.log2_fold_change <= -TREE_FOLD_CHANGE => text.red().to_string(),
+                Presence::Both => text,
+            }
+        });
+    }
+
+    /// Copy of the merged tree without the taxa hidden by a filter
+    fn display_tree(&self, entries: &[TaxonEntry], filter: &DiffFilter) -> Vec<TaxonEntry> {
+        let deepest = match filter.rank {
+            Some(RankSelector::Exact(rank)) => Some(rank),
+            _ => None,
+        };
+        entries.iter()
+            .filter(|entry| self.taxon(entry.taxid).is_some_and(|taxon| filter.passes_reads(taxon)))
+            .filter(|entry| deepest.is_none_or(|rank| entry.rank <= rank || entry.rank == Rank::Unknown))
+            .map(|entry| {
+                let mut node = leaf(entry);
+                node.children = self.display_tree(&entry.children, filter);
+                node
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(clade_reads: u64, rank: &str, taxid: u32, name: &str, depth: usize, children: Vec<TaxonEntry>) -> TaxonEntry {
+        let direct = clade_reads - children.iter().map(|child| child.clade_reads).sum::<u64>();
+        let mut entry = TaxonEntry::new(0.0, clade_reads, direct, rank.to_string(), taxid, name.to_string(), depth);
+        entry.children = children;
+        entry
+    }
+
+    fn report(unclassified: u64, root: TaxonEntry) -> KrakenReport {
+        KrakenReport::new(root, Some(entry(unclassified, "U", 0, "unclassified", 0, Vec::new())))
+    }
+
+    fn reports() -> (KrakenReport, KrakenReport) {
+        let a = report(20, entry(80, "R", 1, "root", 0, vec![
+            entry(80, "G", 561, "Escherichia", 1, vec![
+                entry(60, "S", 562, "Escherichia coli", 2, Vec::new()),
+                entry(10, "S", 208962, "Escherichia albertii", 2, Vec::new()),
+            ]),
+        ]));
+        let b = report(100, entry(300, "R", 1, "root", 0, vec![
+            entry(240, "G", 561, "Escherichia", 1, vec![
+                entry(240, "S", 562, "Escherichia coli", 2, Vec::new()),
+            ]),
+            entry(60, "G", 590, "Salmonella", 1, vec![
+                entry(60, "S", 28901, "Salmonella enterica", 2, Vec::new()),
+            ]),
+        ]));
+        (a, b)
+    }
+
+    #[test]
+    fn test_diff_reports() {
+        let (a, b) = reports();
+        let diff = diff_reports(&a, &b, 1.0);
+        assert_eq!((diff.total_a, diff.total_b), (100, 400));
+        let order: Vec<u32> = diff.taxa.iter().map(|taxon| taxon.taxid).collect();
+        assert_eq!(order, vec![0, 1, 561, 562, 208962, 590, 28901]);
+
+        let coli = diff.taxon(562).unwrap();
+        assert_eq!(coli.presence(), Presence::Both);
+        assert_eq!(coli.read_change(), 180);
+        assert!((coli.percent_change() - 0.0).abs() < 1e-12);
+        assert!((coli.log2_fold_change - ((241.0 / 400.0) / (61.0 / 100.0f64)).log2()).abs() < 1e-12);
+
+        assert_eq!(diff.taxon(208962).unwrap().presence(), Presence::OnlyA);
+        let salmonella = diff.taxon(28901).unwrap();
+        assert_eq!(salmonella.presence(), Presence::OnlyB);
+        assert!((salmonella.percent_b - 15.0).abs() < 1e-12);
+        assert_eq!(salmonella.percent_a, 0.0);
+    }
+
+    #[test]
+    fn test_diff_filter() {
+        let (a, b) = reports();
+        let diff = diff_reports(&a, &b, 1.0);
+        let species = DiffFilter { rank: Some("S".parse().unwrap()), min_reads: 50 };
+        let taxids: Vec<u32> = diff.filtered(&species).iter().map(|taxon| taxon.taxid).collect();
+        assert_eq!(taxids, vec![562, 28901]);
+
+        // The display tree keeps the ancestors and stops at the selected rank
+        let genus = DiffFilter { rank: Some("G".parse().unwrap()), min_reads: 0 };
+        let tree = diff.display_tree(&diff.tree, &genus);
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree[1].children.len(), 2);
+        assert!(tree[1].children.iter().all(|genus| genus.children.is_empty()));
+    }
+}
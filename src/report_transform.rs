@@ -0,0 +1,201 @@
+use crate::krk_parser::{KrakenReport, TaxonEntry};
+use crate::rank::Rank;
+
+/// Taxa and reads moved by a report transform
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TransformSummary {
+    /// Taxa removed from the tree
+    pub taxa_removed: usize,
+    /// Reads moved to the direct reads of a surviving ancestor
+    pub reads_reassigned: u64,
+}
+
+/// Thresholds a taxon must reach to be kept by `prune_report`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PruneThresholds {
+    /// Minimum clade reads
+    pub min_reads: u64,
+    /// Minimum percentage of the reads of the report (classified and unclassified) in the clade
+    pub min_percent: f64,
+}
+
+/// Removes the taxa below read thresholds from a report
+///
+/// # Arguments
+/// * `report` - Report to prune
+/// * `thresholds` - Minimum clade reads and percentage of a kept taxon
+///
+/// # Returns
+/// * `(KrakenReport, TransformSummary)` - Pruned report and the number of removed taxa and reads
+///
+/// # Implementation Details
+/// A taxon below either threshold is removed with all its descendants, and
+/// its clade reads are added to the direct reads of its parent, the nearest
+/// surviving ancestor. The clade reads and percentages of the surviving taxa
+/// are therefore unchanged. The root and unclassified lines are always kept.
+pub fn prune_report(report: &KrakenReport, thresholds: &PruneThresholds) -> (KrakenReport, TransformSummary) {
+    let total = report.root.clade_reads + report.unclassified.as_ref().map_or(0, |entry| entry.clade_reads);
+    let percent = |entry: &TaxonEntry| if total > 0 { entry.clade_reads as f64 * 100.0 / total as f64 } else { 0.0 };
+    let below = |_: &TaxonEntry, entry: &TaxonEntry| {
+        entry.clade_reads < thresholds.min_reads || percent(entry) < thresholds.min_percent
+    };
+
+    let mut root = report.root.clone();
+    let mut summary = TransformSummary::default();
+    fold_children(&mut root, &below, &mut summary);
+    (KrakenReport::new(root, report.unclassified.clone()), summary)
+}
+
+/// Collapses a report to a rank, folding the taxa below it into their ancestor at that rank
+///
+/// # Arguments
+/// * `report` - Report to collapse
+/// * `rank` - Deepest rank of the collapsed report (e.g. genus)
+///
+/// # Returns
+/// * `(KrakenReport, TransformSummary)` - Collapsed report and the number of removed taxa and reads
+///
+/// # Implementation Details
+/// The direct reads of a taxon at the rank become its clade reads, so that
+/// e.g. the direct reads of a genus include all its species and strains.
+/// Taxa below the rank with no ancestor at it (a species directly under a
+/// family) are folded into their nearest ancestor above the rank. Taxa with
+/// an unknown rank are kept unless they descend from a taxon at the rank.
+pub fn collapse_report(report: &KrakenReport, rank: Rank) -> (KrakenReport, TransformSummary) {
+    let below = |parent: &TaxonEntry, entry: &TaxonEntry| {
+        parent.rank == rank || (entry.rank.is_at_or_below(rank) && entry.rank != rank)
+    };
+
+    let mut root = report.root.clone();
+    let mut summary = TransformSummary::default();
+    fold_children(&mut root, &below, &mut summary);
+    (KrakenReport::new(root, report.unclassified.clone()), summary)
+}
+
+/// Removes the descendants for which `fold(parent, child)` holds, adding their
+/// clade reads to the direct reads of their parent
+fn fold_children(entry: &mut TaxonEntry, fold: &dyn Fn(&TaxonEntry, &TaxonEntry) -> bool, summary: &mut TransformSummary) {
+    for mut child in std::mem::take(&mut entry.children) {
+        if fold(entry, &child) {
+            entry.direct_reads += child.clade_reads;
+            summary.taxa_removed += count_taxa(&child);
+            summary.reads_reassigned += child.clade_reads;
+        } else {
+            fold_children(&mut child, fold, summary);
+            entry.children.push(child);
+        }
+    }
+    entry.direct_fragments = entry.direct_reads;
+}
+
+/// Number of taxa of a subtree, including its root
+fn count_taxa(entry: &TaxonEntry) -> usize {
+    1 + entry.children.iter().map(count_taxa).sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(clade_reads: u64, rank: &str, taxid: u32, name: &str, depth: usize, children: Vec<TaxonEntry>) -> TaxonEntry {
+        let direct = clade_reads - children.iter().map(|child| child.clade_reads).sum::<u64>();
+        let mut entry = TaxonEntry::new(0.0, clade_reads, direct, rank.to_string(), taxid, name.to_string(), depth);
+        entry.children = children;
+        entry
+    }
+
+    /// Checks that the clade reads of every taxon are its direct reads plus the clade reads of its children
+    fn assert_consistent(entry: &TaxonEntry) {
+        let children: u64 = entry.children.iter().map(|child| child.clade_reads).sum();
+        assert_eq!(entry.clade_reads, entry.direct_reads + children, "taxid {}", entry.taxid);
+        assert_eq!(entry.direct_fragments, entry.direct_reads);
+        entry.children.iter().for_each(assert_consistent);
+    }
+
+    fn report() -> KrakenReport {
+        KrakenReport::new(
+            entry(190, "R", 1, "root", 0, vec![
+                entry(188, "D", 2, "Bacteria", 1, vec![
+                    entry(150, "G", 561, "Escherichia", 2, vec![
+                        entry(148, "S", 562, "Escherichia coli", 3, vec![
+                            entry(2, "S1", 83333, "Escherichia coli K-12", 4, Vec::new()),
+                        ]),
+                        entry(1, "S", 208962, "Escherichia albertii", 3, Vec::new()),
+                    ]),
+                    entry(20, "G", 590, "Salmonella", 2, vec![
+                        entry(20, "S", 28901, "Salmonella enterica", 3, Vec::new()),
+                    ]),
+                ]),
+            ]),
+            Some(entry(10, "U", 0, "unclassified", 0, Vec::new())),
+        )
+    }
+
+    #[test]
+    fn test_prune_by_reads() {
+        let (pruned, summary) = prune_report(&report(), &PruneThresholds { min_reads: 3, min_percent: 0.0 });
+        assert_eq!(summary, TransformSummary { taxa_removed: 2, reads_reassigned: 3 });
+        assert_consistent(&pruned.root);
+        assert!(!pruned.taxon_map.contains_key(&83333));
+        assert!(!pruned.taxon_map.contains_key(&208962));
+
+        let genus = &pruned.root.children[0].children[0];
+        assert_eq!((genus.clade_reads, genus.direct_reads), (150, 2));
+        assert_eq!(genus.children[0].direct_reads, 148);
+        assert_eq!(pruned.unclassified.as_ref().unwrap().clade_reads, 10);
+    }
+
+    #[test]
+    fn test_prune_by_percent() {
+        // 20 reads are 10% of the 200 reads of the report
+        let (pruned, summary) = prune_report(&report(), &PruneThresholds { min_reads: 0, min_percent: 15.0 });
+        assert_eq!(summary, TransformSummary { taxa_removed: 4, reads_reassigned: 23 });
+        assert_consistent(&pruned.root);
+        let bacteria = &pruned.root.children[0];
+        assert_eq!(bacteria.direct_reads, 38);
+        assert_eq!(bacteria.children.len(), 1);
+
+        let (unchanged, summary) = prune_report(&report(), &PruneThresholds::default());
+        assert_eq!(summary, TransformSummary::default());
+        assert_eq!(unchanged.root, report().root);
+    }
+
+    #[test]
+    fn test_collapse_to_genus() {
+        let (collapsed, summary) = collapse_report(&report(), Rank::Genus(0));
+        assert_eq!(summary, TransformSummary { taxa_removed: 4, reads_reassigned: 169 });
+        assert_consistent(&collapsed.root);
+        let genera = &collapsed.root.children[0].children;
+        assert!(genera.iter().all(|genus| genus.children.is_empty() && genus.direct_reads == genus.clade_reads));
+        assert_eq!(collapsed.root.children[0].direct_reads, 18);
+        assert_eq!(collapsed.taxon_map.len(), 4);
+    }
+
+    #[test]
+    fn test_collapse_without_rank_ancestor() {
+        // A species directly under a family is folded into the family, and
+        // strains are folded into their species
+        let report = KrakenReport::new(
+            entry(30, "R", 1, "root", 0, vec![
+                entry(30, "F", 543, "Enterobacteriaceae", 1, vec![
+                    entry(10, "S", 2, "Species without genus", 2, Vec::new()),
+                    entry(20, "G", 561, "Escherichia", 2, vec![
+                        entry(20, "S", 562, "Escherichia coli", 3, vec![
+                            entry(5, "S1", 83333, "Escherichia coli K-12", 4, Vec::new()),
+                        ]),
+                    ]),
+                ]),
+            ]),
+            None,
+        );
+        let (genus, _) = collapse_report(&report, Rank::Genus(0));
+        let family = &genus.root.children[0];
+        assert_eq!((family.direct_reads, family.children.len()), (10, 1));
+        assert_consistent(&genus.root);
+
+        let (species, summary) = collapse_report(&report, Rank::Species(0));
+        assert_eq!(summary, TransformSummary { taxa_removed: 1, reads_reassigned: 5 });
+        assert_eq!(species.root.children[0].children[1].children[0].direct_reads, 20);
+        assert_consistent(&species.root);
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::error::Error;
+use std::path::Path;
+
+/// Optimized buffer size for reading metadata files
+const BUFFER_SIZE: usize = 256 * 1024; // 256KB
+
+/// Accepted names for the sample identifier column (ours, QIIME 1 and QIIME 2 styles)
+const SAMPLE_ID_COLUMNS: &[&str] = &["sample_id", "#SampleID", "sample-id", "#sample-id", "id"];
+
+/// Accepted names for the optional column mapping report files to samples (metadata and manifest styles)
+const FILE_COLUMNS: &[&str] = &["file", "report_path"];
+
+/// Specific errors for the sample metadata module
+#[derive(Debug)]
+pub enum MetadataError {
+    IoError(std::io::Error),
+    InvalidData(String),
+}
+
+impl std::fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(e) => write!(f, "I/O error: {}", e),
+            Self::InvalidData(s) => write!(f, "Invalid sample metadata: {}", s),
+        }
+    }
+}
+
+impl Error for MetadataError {}
+
+impl From<std::io::Error> for MetadataError {
+    fn from(e: std::io::Error) -> Self {
+        Self::IoError(e)
+    }
+}
+
+/// Specialized result type for sample metadata functions
+pub type MetadataResult<T> = Result<T, MetadataError>;
+
+/// Metadata of a single sample
+#[derive(Debug, Clone)]
+pub struct SampleRecord {
+    /// Sample identifier used as the matrix column name
+    pub sample_id: String,
+    /// Report file mapped to this sample, if the table has a `file` column
+    pub file: Option<String>,
+    /// Values of the remaining columns by column name
+    pub values: HashMap<String, String>,
+}
+
+/// Sample metadata table
+///
+/// The table is a tab-separated file with a header line. It needs a sample
+/// identifier column (`sample_id`, or `#SampleID`/`sample-id` as used by QIIME),
+/// and may have a `file` (or `report_path`) column mapping report files to
+/// samples. Every other column is kept as metadata. A manifest is the same
+/// table with a report path for every sample.
+#[derive(Debug, Default)]
+pub struct SampleMetadata {
+    /// Metadata column names in file order
+    pub fields: Vec<String>,
+    records: Vec<SampleRecord>,
+    by_sample: HashMap<String, usize>,
+}
+
+impl SampleMetadata {
+    /// Loads sample metadata from a TSV file
+    pub fn from_tsv(path: &str) -> MetadataResult<Self> {
+        let file = File::open(path)?;
+        Self::from_reader(BufReader::with_capacity(BUFFER_SIZE, file))
+    }
+
+    /// Loads sample metadata from any buffered reader
+    pub fn from_reader<R: BufRead>(reader: R) -> MetadataResult<Self> {
+        let mut lines = reader.lines();
+        let header = match lines.next() {
+            Some(line) => line?,
+            None => return Err(MetadataError::InvalidData("empty file".to_string())),
+        };
+        let columns: Vec<String> = header.split('\t').map(|c| c.trim().to_string()).collect();
+        let id_idx = columns.iter()
+            .position(|c| SAMPLE_ID_COLUMNS.contains(&c.as_str()))
+            .ok_or_else(|| MetadataError::InvalidData("missing 'sample_id' column".to_string()))?;
+        let file_idx = columns.iter().position(|c| FILE_COLUMNS.contains(&c.as_str()));
+
+        let mut metadata = Self {
+            fields: columns.iter().enumerate()
+                .filter(|(i, _)| *i != id_idx && Some(*i) != file_idx)
+                .map(|(_, c)| c.clone())
+                .collect(),
+            ..Default::default()
+        };
+
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            // Skip empty lines and QIIME directives/comments
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').map(|f| f.trim()).collect();
+            let sample_id = fields.get(id_idx).copied().unwrap_or("");
+            if sample_id.is_empty() {
+                return Err(MetadataError::InvalidData(format!("missing sample id at line {}", i + 2)));
+            }
+            if metadata.by_sample.contains_key(sample_id) {
+                return Err(MetadataError::InvalidData(format!("duplicate sample id '{}' at line {}", sample_id, i + 2)));
+            }
+
+            let values = columns.iter().enumerate()
+                .filter(|(i, _)| *i != id_idx && Some(*i) != file_idx)
+                .map(|(i, c)| (c.clone(), fields.get(i).copied().unwrap_or("").to_string()))
+                .collect();
+            metadata.by_sample.insert(sample_id.to_string(), metadata.records.len());
+            metadata.records.push(SampleRecord {
+                sample_id: sample_id.to_string(),
+                file: file_idx.and_then(|idx| fields.get(idx)).filter(|f| !f.is_empty()).map(|f| f.to_string()),
+                values,
+            });
+        }
+
+        Ok(metadata)
+    }
+
+    /// Number of samples in the table
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Whether the table has no samples
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Metadata record of a sample
+    pub fn get(&self, sample_id: &str) -> Option<&SampleRecord> {
+        self.by_sample.get(sample_id).map(|&idx| &self.records[idx])
+    }
+
+    /// Samples and their report files as listed in a manifest
+    /// 
+    /// # Returns
+    /// * `MetadataResult<Vec<(String, String)>>` - (sample id, report path) pairs in file order
+    /// 
+    /// Fails when a sample has no report path or a report is listed twice.
+    pub fn manifest_inputs(&self) -> MetadataResult<Vec<(String, String)>> {
+        let mut inputs = Vec::with_capacity(self.records.len());
+        let mut seen: HashMap<&str, &str> = HashMap::with_capacity(self.records.len());
+        for record in &self.records {
+            let file = record.file.as_deref().ok_or_else(|| {
+                MetadataError::InvalidData(format!("sample '{}' has no report path", record.sample_id))
+            })?;
+            if let Some(previous) = seen.insert(file, &record.sample_id) {
+                return Err(MetadataError::InvalidData(format!(
+                    "report '{}' is listed for both '{}' and '{}'", file, previous, record.sample_id
+                )));
+            }
+            inputs.push((record.sample_id.clone(), file.to_string()));
+        }
+        Ok(inputs)
+    }
+
+    /// Value of a metadata field for a sample
+    pub fn value(&self, sample_id: &str, field: &str) -> Option<&str> {
+        self.get(sample_id)
+            .and_then(|record| record.values.get(field))
+            .map(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+    }
+
+    /// Sample id mapped to a report file
+    ///
+    /// The path is matched against the `file` column as written, then by file
+    /// name and finally by file stem, so that "runs/A/report.txt" can be listed
+    /// as the full path while "sample1.kreport" can be listed as "sample1".
+    pub fn sample_for_file(&self, path: &str) -> Option<&str> {
+        let file_name = Path::new(path).file_name().and_then(|s| s.to_str());
+        let file_stem = Path::new(path).file_stem().and_then(|s| s.to_str());
+        let with_file = || self.records.iter().filter_map(|r| r.file.as_deref().map(|f| (f, r)));
+
+        with_file().find(|(f, _)| *f == path)
+            .or_else(|| with_file().find(|(f, _)| Path::new(f).file_name().and_then(|s| s.to_str()) == file_name))
+            .or_else(|| with_file().find(|(f, _)| Some(*f) == file_stem))
+            .map(|(_, r)| r.sample_id.as_str())
+    }
+
+    /// Sorts sample names by the value of a metadata field
+    ///
+    /// Values that all parse as numbers are compared numerically. Samples with no
+    /// value for the field go last, and ties keep the sample name order.
+    pub fn sort_samples(&self, samples: &mut [String], field: &str) -> MetadataResult<()> {
+        if !self.fields.iter().any(|f| f == field) {
+            return Err(MetadataError::InvalidData(format!("unknown metadata field '{}'", field)));
+        }
+        let numeric = samples.iter()
+            .filter_map(|s| self.value(s, field))
+            .all(|v| v.parse::<f64>().is_ok());
+
+        samples.sort_by(|a, b| {
+            let order = match (self.value(a, field), self.value(b, field)) {
+                (Some(x), Some(y)) if numeric => {
+                    let (x, y) = (x.parse::<f64>().unwrap_or(0.0), y.parse::<f64>().unwrap_or(0.0));
+                    x.partial_cmp(&y).unwrap_or(Ordering::Equal)
+                }
+                (Some(x), Some(y)) => x.cmp(y),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            };
+            order.then_with(|| a.cmp(b))
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METADATA: &str = "file\tsample_id\tday\tgroup\n\
+        runs/A/report.txt\tA\t10\tcontrol\n\
+        runs/B/report.txt\tB\t2\ttreated\n\
+        sample_c.kreport\tC\t\ttreated\n";
+
+    #[test]
+    fn test_sample_for_file() {
+        let metadata = SampleMetadata::from_reader(METADATA.as_bytes()).unwrap();
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata.fields, vec!["day", "group"]);
+        assert_eq!(metadata.sample_for_file("runs/B/report.txt"), Some("B"));
+        assert_eq!(metadata.sample_for_file("/data/sample_c.kreport"), Some("C"));
+        assert_eq!(metadata.sample_for_file("other.kreport"), None);
+        assert_eq!(metadata.value("A", "group"), Some("control"));
+        assert_eq!(metadata.value("C", "day"), None);
+    }
+
+    #[test]
+    fn test_sort_samples() {
+        let metadata = SampleMetadata::from_reader(METADATA.as_bytes()).unwrap();
+        let mut samples = vec!["A".to_string(), "C".to_string(), "B".to_string()];
+        metadata.sort_samples(&mut samples, "day").unwrap();
+        assert_eq!(samples, vec!["B", "A", "C"]);
+        metadata.sort_samples(&mut samples, "group").unwrap();
+        assert_eq!(samples, vec!["A", "B", "C"]);
+        assert!(metadata.sort_samples(&mut samples, "site").is_err());
+    }
+
+    #[test]
+    fn test_manifest_inputs() {
+        let manifest = SampleMetadata::from_reader("sample_id\treport_path\tgroup\nA\truns/a.kreport\tcontrol\nB\truns/b.kreport\t\n".as_bytes()).unwrap();
+        assert_eq!(manifest.fields, vec!["group"]);
+        assert_eq!(manifest.manifest_inputs().unwrap(), vec![
+            ("A".to_string(), "runs/a.kreport".to_string()),
+            ("B".to_string(), "runs/b.kreport".to_string()),
+        ]);
+
+        let missing = SampleMetadata::from_reader("sample_id\treport_path\nA\ta.kreport\nB\t\n".as_bytes()).unwrap();
+        assert!(missing.manifest_inputs().is_err());
+        let repeated = SampleMetadata::from_reader("sample_id\treport_path\nA\ta.kreport\nB\ta.kreport\n".as_bytes()).unwrap();
+        assert!(repeated.manifest_inputs().is_err());
+    }
+
+    #[test]
+    fn test_invalid_metadata() {
+        assert!(SampleMetadata::from_reader("file\tday\n".as_bytes()).is_err());
+        assert!(SampleMetadata::from_reader("sample_id\tday\nA\t1\nA\t2\n".as_bytes()).is_err());
+        let qiime = SampleMetadata::from_reader("#SampleID\tday\n#q2:types\tnumeric\nA\t1\n".as_bytes()).unwrap();
+        assert_eq!(qiime.value("A", "day"), Some("1"));
+    }
+}
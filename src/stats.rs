@@ -0,0 +1,275 @@
+/// Maximum number of iterations of the incomplete gamma series and continued fraction
+const MAX_ITERATIONS: usize = 1000;
+
+/// Relative precision targeted by the incomplete gamma function
+const GAMMA_EPSILON: f64 = 1e-15;
+
+/// Result of a rank-based test
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TestResult {
+    /// Test statistic (U of the first group, or the Kruskal-Wallis H)
+    pub statistic: f64,
+    /// Two-sided p-value, NaN when the test is undefined (e.g. all values tied)
+    pub p_value: f64,
+}
+
+/// Ranks of the values, with tied values sharing the mean of their ranks
+///
+/// # Returns
+/// * `(Vec<f64>, f64)` - Rank of each value (starting at 1) and the tie term sum(t³ - t)
+///   over the groups of t tied values
+pub fn ranks(values: &[f64]) -> (Vec<f64>, f64) {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|&a, &b| values[a].total_cmp(&values[b]));
+    let mut ranks = vec![0.0; values.len()];
+    let mut ties = 0.0;
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        let rank = (start + end + 1) as f64 / 2.0;
+        for &index in &order[start..end] {
+            ranks[index] = rank;
+        }
+        let tied = (end - start) as f64;
+        ties += tied * tied * tied - tied;
+        start = end;
+    }
+    (ranks, ties)
+}
+
+/// Mann-Whitney U test between two samples
+///
+/// The p-value uses the normal approximation with tie and continuity
+/// corrections, as R's `wilcox.test(x, y, exact = FALSE)`.
+pub fn mann_whitney_u(x: &[f64], y: &[f64]) -> TestResult {
+    let (n1, n2) = (x.len() as f64, y.len() as f64);
+    if x.is_empty() || y.is_empty() {
+        return TestResult { statistic: f64::NAN, p_value: f64::NAN };
+    }
+    let values: Vec<f64> = x.iter().chain(y).copied().collect();
+    let (ranks, ties) = ranks(&values);
+    let rank_sum: f64 = ranks[..x.len()].iter().sum();
+    let u = rank_sum - n1 * (n1 + 1.0) / 2.0;
+
+    let n = n1 + n2;
+    let sigma = (n1 * n2 / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)))).sqrt();
+    let difference = u - n1 * n2 / 2.0;
+    // R's sign(0) is 0, so a U at its expected value is not corrected
+    let correction = if difference == 0.0 { 0.0 } else { 0.5 * difference.signum() };
+    let z = (difference - correction) / sigma;
+    let p_value = if sigma > 0.0 { normal_two_sided(z) } else { f64::NAN };
+    TestResult { statistic: u, p_value }
+}
+
+/// Kruskal-Wallis H test between two or more samples
+///
+/// The p-value uses the chi-squared approximation with k - 1 degrees of
+/// freedom and the tie correction, as R's `kruskal.test`.
+pub fn kruskal_wallis(groups: &[Vec<f64>]) -> TestResult {
+    let groups: Vec<&Vec<f64>> = groups.iter().filter(|group| !group.is_empty()).collect();
+    if groups.len() < 2 {
+        return TestResult { statistic: f64::NAN, p_value: f64::NAN };
+    }
+    let values: Vec<f64> = groups.iter().flat_map(|group| group.iter().copied()).collect();
+    let n = values.len() as f64;
+    let (ranks, ties) = ranks(&values);
+
+    let mut sum = 0.0;
+    let mut offset = 0;
+    for group in &groups {
+        let rank_sum: f64 = ranks[offset..offset + group.len()].iter().sum();
+        sum += rank_sum * rank_sum / group.len() as f64;
+        offset += group.len();
+    }
+    let correction = 1.0 - ties / (n * n * n - n);
+    let h = (12.0 / (n * (n + 1.0)) * sum - 3.0 * (n + 1.0)) / correction;
+    let p_value = if correction > 0.0 { chi_squared_survival(h, (groups.len() - 1) as f64) } else { f64::NAN };
+    TestResult { statistic: h, p_value }
+}
+
+/// Benjamini-Hochberg adjusted p-values (q-values)
+///
+/// NaN p-values are left out of the number of tests and stay NaN.
+pub fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..p_values.len()).filter(|&i| !p_values[i].is_nan()).collect();
+    order.sort_by(|&a, &b| p_values[a].total_cmp(&p_values[b]));
+    let tests = order.len() as f64;
+    let mut q_values = vec![f64::NAN; p_values.len()];
+    let mut minimum: f64 = 1.0;
+    for (rank, &index) in order.iter().enumerate().rev() {
+        minimum = minimum.min(p_values[index] * tests / (rank + 1) as f64);
+        q_values[index] = minimum;
+    }
+    q_values
+}
+
+/// Two-sided p-value of a standard normal statistic
+pub fn normal_two_sided(z: f64) -> f64 {
+    // erfc(|z| / sqrt(2)) is the regularized upper incomplete gamma Q(1/2, z²/2)
+    gamma_q(0.5, z * z / 2.0)
+}
+
+/// Probability that a chi-squared variable with `df` degrees of freedom exceeds `x`
+pub fn chi_squared_survival(x: f64, df: f64) -> f64 {
+    if x <= 0.0 {
+        return 1.0;
+    }
+    gamma_q(df / 2.0, x / 2.0)
+}
+
+/// Regularized upper incomplete gamma function Q(a, x)
+///
+/// Computed with its series below a + 1 and its continued fraction above, as
+/// in Numerical Recipes.
+fn gamma_q(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 1.0;
+    }
+    let log_prefix = a * x.ln() - x - ln_gamma(a);
+    if x < a + 1.0 {
+        // Series of the lower function P(a, x)
+        let mut term = 1.0 / a;
+        let mut sum = term;
+        let mut denominator = a;
+        for _ in 0..MAX_ITERATIONS {
+            denominator += 1.0;
+            term *= x / denominator;
+            sum += term;
+            if term.abs() < sum.abs() * GAMMA_EPSILON {
+                break;
+            }
+        }
+        1.0 - sum * log_prefix.exp()
+    } else {
+        // Modified Lentz evaluation of the continued fraction of Q(a, x)
+        let tiny = f64::MIN_POSITIVE / GAMMA_EPSILON;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..MAX_ITERATIONS {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < GAMMA_EPSILON {
+                break;
+            }
+        }
+        log_prefix.exp() * h
+    }
+}
+
+/// Natural logarithm of the gamma function (Lanczos approximation, g = 7)
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // Reflection formula
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let t = x + 7.5;
+    let series = COEFFICIENTS[1..].iter().enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, &c)| sum + c / (x + i as f64 + 1.0));
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + series.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() < tolerance, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn test_special_functions() {
+        assert_close(ln_gamma(5.0), 24f64.ln(), 1e-12);
+        assert_close(ln_gamma(0.5), std::f64::consts::PI.sqrt().ln(), 1e-12);
+        // Two-sided normal p-values of 1.96 and 3
+        assert_close(normal_two_sided(1.959963984540054), 0.05, 1e-12);
+        assert_close(normal_two_sided(3.0), 0.0026997960632601913, 1e-14);
+        // With 2 degrees of freedom the chi-squared survival is exp(-x/2)
+        assert_close(chi_squared_survival(5.0, 2.0), (-2.5f64).exp(), 1e-14);
+        assert_close(chi_squared_survival(3.84145882069412, 1.0), 0.05, 1e-12);
+    }
+
+    #[test]
+    fn test_ranks() {
+        let (ranks, ties) = ranks(&[3.0, 1.0, 3.0, 2.0, 3.0]);
+        assert_eq!(ranks, vec![4.0, 1.0, 4.0, 2.0, 4.0]);
+        assert_eq!(ties, 24.0);
+    }
+
+    #[test]
+    fn test_mann_whitney_u() {
+        // Example of R's wilcox.test: W = 35, and p = 0.2544 with the exact test
+        let x = [0.80, 0.83, 1.89, 1.04, 1.45, 1.38, 1.91, 1.64, 0.73, 1.46];
+        let y = [1.15, 0.88, 0.90, 0.74, 1.21];
+        let result = mann_whitney_u(&x, &y);
+        assert_eq!(result.statistic, 35.0);
+        // Normal approximation: z = (35 - 25 - 0.5) / sqrt(50 * 16 / 12)
+        assert_close(result.p_value, 0.24462360512698333, 1e-12);
+
+        // Ties reduce the variance
+        let tied = mann_whitney_u(&[1.0, 2.0, 2.0, 3.0], &[2.0, 3.0, 3.0, 4.0, 5.0]);
+        assert_eq!(tied.statistic, 3.0);
+        assert_close(tied.p_value, 0.09934224785346527, 1e-12);
+        assert!(mann_whitney_u(&[1.0, 1.0], &[1.0]).p_value.is_nan());
+
+        // A U at its expected value gives p = 1, without a continuity correction
+        let symmetric = mann_whitney_u(&[1.0, 2.0], &[2.0, 1.0]);
+        assert_eq!(symmetric.statistic, 2.0);
+        assert_eq!(symmetric.p_value, 1.0);
+    }
+
+    #[test]
+    fn test_kruskal_wallis() {
+        // Example of R's kruskal.test (Hollander & Wolfe): chi-squared = 0.77143, df = 2, p-value = 0.68
+        let groups = vec![
+            vec![2.9, 3.0, 2.5, 2.6, 3.2],
+            vec![3.8, 2.7, 4.0, 2.4],
+            vec![2.8, 3.4, 3.7, 2.2, 2.0],
+        ];
+        let result = kruskal_wallis(&groups);
+        assert_close(result.statistic, 0.77143, 1e-5);
+        assert_close(result.p_value, 0.68, 1e-3);
+        assert!(kruskal_wallis(&[vec![1.0, 2.0]]).p_value.is_nan());
+    }
+
+    #[test]
+    fn test_benjamini_hochberg() {
+        // p.adjust(c(0.01, 0.04, 0.03, 0.20), "BH") = 0.04, 0.0533, 0.0533, 0.20
+        let q = benjamini_hochberg(&[0.01, 0.04, f64::NAN, 0.03, 0.20]);
+        for (actual, expected) in q.iter().zip([0.04, 0.16 / 3.0, f64::NAN, 0.16 / 3.0, 0.20]) {
+            if expected.is_nan() {
+                assert!(actual.is_nan());
+            } else {
+                assert_close(*actual, expected, 1e-12);
+            }
+        }
+    }
+}
@@ -58,30 +58,42 @@ pub fn print_taxon_info(info: &TaxonInfo) {
 
 fn print_taxonomic_tree(parents: &[TaxonEntry], current: &TaxonEntry, children: &[TaxonEntry]) {
     for (i, parent) in parents.iter().enumerate() {
-        print!("{}├── {}: {} (C{}) (D{})\n", "│   ".repeat(i), parent.taxon_id, parent.name, parent.clade_fragments, parent.direct_fragments);
+        println!("{}├── {}: {} (C{}) (D{})", "│   ".repeat(i), parent.taxon_id, parent.name, parent.clade_fragments, parent.direct_fragments);
     }
     
     let parent_depth = parents.len();
-    print!("{}└── {}: {} (C{}) (D{})\n", "│   ".repeat(parent_depth), current.taxon_id, current.name.green(), current.clade_fragments, current.direct_fragments);
+    println!("{}└── {}: {} (C{}) (D{})", "│   ".repeat(parent_depth), current.taxon_id, current.name.green(), current.clade_fragments, current.direct_fragments);
     
     print_children_tree(children, &"│   ".repeat(parent_depth + 1));
 }
 
 fn print_children_tree(children: &[TaxonEntry], prefix: &str) {
-    for (i, child) in children.iter().enumerate() {
-        let is_last = i == children.len() - 1;
+    print_tree(children, prefix, &|child| {
+        format!("{}: {} (C{}) (D{})", child.taxon_id, child.name, child.clade_fragments, child.direct_fragments)
+    });
+}
+
+/// Prints taxa and their descendants as a tree, one line per taxon
+///
+/// # Arguments
+/// * `entries` - Taxa at the top of the tree
+/// * `prefix` - Indentation written before the branches of every line
+/// * `label` - Text of the line of a taxon
+pub fn print_tree<F: Fn(&TaxonEntry) -> String>(entries: &[TaxonEntry], prefix: &str, label: &F) {
+    for (i, entry) in entries.iter().enumerate() {
+        let is_last = i == entries.len() - 1;
         let current_prefix = if is_last {
             format!("{}└── ", prefix)
         } else {
             format!("{}├── ", prefix)
         };
-        println!("{}{}: {} (C{}) (D{})", current_prefix, child.taxon_id, child.name, child.clade_fragments, child.direct_fragments);
+        println!("{}{}", current_prefix, label(entry));
 
         let new_prefix = if is_last {
             format!("{}    ", prefix)
         } else {
             format!("{}│   ", prefix)
         };
-        print_children_tree(&child.children, &new_prefix);
+        print_tree(&entry.children, &new_prefix, label);
     }
-}
\ No newline at end of file
+}